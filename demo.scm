@@ -0,0 +1,118 @@
+;; The page the Compute binary serves at /. `demo-page` takes the output
+;; of the examples and returns the page as SXML; the binary renders it
+;; with `sxml->html`.
+
+(define repository "https://github.com/aspires/scheme-on-compute")
+
+(define demo-styles "
+    body {
+        font-family: 'Courier New', monospace;
+        max-width: 800px;
+        margin: 0 auto;
+        padding: 20px;
+        background: linear-gradient(135deg, #667eea 0%, #764ba2 100%);
+        color: white;
+        min-height: 100vh;
+    }
+    .container {
+        background: rgba(255, 255, 255, 0.1);
+        padding: 30px;
+        border-radius: 15px;
+        backdrop-filter: blur(10px);
+        box-shadow: 0 8px 32px rgba(0, 0, 0, 0.1);
+    }
+    h1 {
+        text-align: center;
+        margin-bottom: 30px;
+        font-size: 2.5em;
+        text-shadow: 2px 2px 4px rgba(0, 0, 0, 0.3);
+    }
+    .scheme-output {
+        background: rgba(0, 0, 0, 0.3);
+        padding: 20px;
+        border-radius: 10px;
+        border-left: 4px solid #4CAF50;
+        white-space: pre-wrap;
+        font-size: 1.1em;
+        line-height: 1.6;
+    }
+    .info {
+        margin-top: 30px;
+        padding: 20px;
+        background: rgba(255, 255, 255, 0.1);
+        border-radius: 10px;
+        border-left: 4px solid #2196F3;
+    }
+    .highlight {
+        color: #4CAF50;
+        font-weight: bold;
+    }
+    .github-link {
+        margin-top: 20px;
+        text-align: center;
+    }
+    .github-link a {
+        color: #4CAF50;
+        text-decoration: none;
+        font-weight: bold;
+        padding: 10px 20px;
+        border: 2px solid #4CAF50;
+        border-radius: 25px;
+        transition: all 0.3s ease;
+    }
+    .github-link a:hover {
+        background: #4CAF50;
+        color: white;
+    }
+    .github-header {
+        text-align: center;
+        margin-bottom: 30px;
+        padding: 30px;
+        background: linear-gradient(135deg, #4CAF50 0%, #45a049 100%);
+        border-radius: 20px;
+        border: 3px solid #2E7D32;
+        box-shadow: 0 8px 25px rgba(76, 175, 80, 0.3);
+    }
+    .github-header a {
+        color: white;
+        text-decoration: none;
+        font-weight: bold;
+        font-size: 1.5em;
+        padding: 20px 40px;
+        border: 3px solid white;
+        border-radius: 40px;
+        transition: all 0.3s ease;
+        display: inline-block;
+        background: rgba(255, 255, 255, 0.1);
+        text-shadow: 1px 1px 2px rgba(0, 0, 0, 0.3);
+    }
+    .github-header a:hover {
+        background: rgba(255, 255, 255, 0.2);
+        transform: scale(1.05);
+        box-shadow: 0 10px 30px rgba(255, 255, 255, 0.2);
+    }
+")
+
+(define (demo-page output)
+  `(*TOP*
+    (*DECL* DOCTYPE html)
+    (html (@ (lang "en"))
+      (head
+        (meta (@ (charset "UTF-8")))
+        (meta (@ (name "viewport") (content "width=device-width, initial-scale=1.0")))
+        (title "Scheme Lisp on Fastly Compute")
+        (style ,demo-styles))
+      (body
+        (div (@ (class "container"))
+          (h1 "🚀 Scheme Lisp on Fastly Compute")
+          (div (@ (class "github-header"))
+            (a (@ (href ,repository) (target "_blank")) "🐙 View on GitHub"))
+          (div (@ (class "scheme-output")) ,output)
+          (div (@ (class "info"))
+            (h3 "About this demo:")
+            (p "This page demonstrates a simple Scheme Lisp interpreter running on "
+               (span (@ (class "highlight")) "Fastly Compute@Edge") ".")
+            (p "The Scheme code is executed server-side and the results are displayed above.")
+            (p "This shows how you can run custom programming languages at the edge for dynamic content generation.")
+            (div (@ (class "github-link"))
+              (a (@ (href ,repository) (target "_blank")) "🐙 View Source on GitHub"))))))))
@@ -0,0 +1,19 @@
+;; The Compute service's request handler, for every path its built-in
+;; routes do not serve. `handle` gets the request and returns a response
+;; from `make-response`, a string for a 200 plain-text answer, or #f for
+;; the 404 page.
+
+(define (handle request)
+  (let ((path (request-path request)))
+    (cond ((equal? path "/hello")
+           (make-response 200 '(("content-type" "text/plain; charset=utf-8"))
+                          (string-append "Hello from Scheme! This was a "
+                                         (request-method request)
+                                         " request.\n")))
+          ((equal? path "/echo")
+           (make-response 200
+                          (list (list "content-type"
+                                      (or (request-header request "content-type")
+                                          "application/octet-stream")))
+                          (request-body request)))
+          (else #f))))
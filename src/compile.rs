@@ -0,0 +1,324 @@
+// Compiler from expanded core forms to bytecode for the VM in `vm.rs`.
+//
+// Only the core forms left by the expander reach here: `quote`, `if`,
+// `define`, `set!`, `lambda`, `begin` and applications. A variable bound by
+// an enclosing lambda, as a parameter or an internal definition, compiles
+// to its lexical address: how many frames out it lives and its slot there.
+// Any other variable is global and is looked up by name when it runs.
+//
+// A procedure that creates no closures and has no internal definitions
+// keeps its arguments on the VM stack. All others get a heap frame that
+// closures can capture. A malformed form compiles to a `Fail` instruction,
+// so it reports the same error as the tree-walker, at the same time. So does
+// a form nested deeper than the expander allows, which only the optimizer's
+// inlining can produce.
+
+use std::rc::Rc;
+
+use crate::expand::MAX_NESTING;
+use crate::{parse_params, SchemeValue};
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Op {
+    /// Push `constants[i]`.
+    Const(usize),
+    /// Push an argument kept on the VM stack.
+    Arg(usize),
+    /// Store the value on top of the stack into an argument, leaving it there.
+    SetArg(usize),
+    /// Push a slot of the heap frame `depth` frames out. `name` indexes
+    /// `names`, for the error when the slot has not been defined yet.
+    Local { depth: usize, slot: usize, name: usize },
+    SetLocal { depth: usize, slot: usize, name: usize },
+    /// Pop a value into a slot of the current heap frame and push the name.
+    DefineLocal { slot: usize, name: usize },
+    /// Push the global `names[i]`.
+    Global(usize),
+    SetGlobal(usize),
+    DefineGlobal(usize),
+    /// Push a closure over `functions[i]` and the current frame.
+    Closure(usize),
+    Jump(usize),
+    /// Pop a value and jump if it is false.
+    JumpIfFalse(usize),
+    Pop,
+    /// Call the procedure below `n` arguments on the stack.
+    Call(usize),
+    /// The same in tail position: a compiled procedure replaces the current
+    /// call instead of nesting inside it.
+    TailCall(usize),
+    Return,
+    /// Fail with the message in `constants[i]`.
+    Fail(usize),
+}
+
+/// A compiled lambda body or top-level form.
+#[derive(Debug, Default)]
+pub(crate) struct Code {
+    pub(crate) ops: Vec<Op>,
+    pub(crate) constants: Vec<SchemeValue>,
+    pub(crate) names: Vec<String>,
+    pub(crate) functions: Vec<Rc<Code>>,
+    pub(crate) params: usize,
+    pub(crate) rest: bool,
+    /// Number of slots in the heap frame, or `None` if the arguments stay
+    /// on the stack.
+    pub(crate) frame_size: Option<usize>,
+    /// Whether closures can capture the frame, making cycles possible.
+    pub(crate) captures: bool,
+}
+
+/// Compiles a top-level form. Its definitions are global.
+pub(crate) fn compile(expr: &SchemeValue) -> Code {
+    let mut function =
+        Function { code: Code::default(), slots: Vec::new(), heap: false, toplevel: true, parent: None, depth: 0 };
+    function.expr(expr, true);
+    function.emit(Op::Return);
+    function.code
+}
+
+struct Function<'a> {
+    code: Code,
+    /// Names of the stack arguments or heap frame slots, in slot order.
+    slots: Vec<String>,
+    heap: bool,
+    toplevel: bool,
+    parent: Option<&'a Function<'a>>,
+    /// Calls to `expr` in progress, counting those of enclosing functions.
+    depth: usize,
+}
+
+impl Function<'_> {
+    fn emit(&mut self, op: Op) -> usize {
+        self.code.ops.push(op);
+        self.code.ops.len() - 1
+    }
+
+    fn constant(&mut self, value: SchemeValue) -> usize {
+        self.code.constants.push(value);
+        self.code.constants.len() - 1
+    }
+
+    fn name(&mut self, name: &str) -> usize {
+        match self.code.names.iter().position(|n| n == name) {
+            Some(i) => i,
+            None => {
+                self.code.names.push(name.to_string());
+                self.code.names.len() - 1
+            }
+        }
+    }
+
+    fn fail(&mut self, message: &str) {
+        let i = self.constant(SchemeValue::String(message.to_string()));
+        self.emit(Op::Fail(i));
+    }
+
+    /// The lexical address of `name`: lambdas out, and slot.
+    fn resolve(&self, name: &str) -> Option<(usize, usize)> {
+        let mut function = Some(self);
+        let mut depth = 0;
+        while let Some(f) = function {
+            if let Some(slot) = f.slots.iter().rposition(|s| s == name) {
+                return Some((depth, slot));
+            }
+            function = f.parent;
+            depth += 1;
+        }
+        None
+    }
+
+    /// Every enclosing procedure has a heap frame, since it creates this
+    /// one's closure. Only the current one may keep its arguments on the
+    /// stack, in which case heap depths start one lambda out.
+    fn heap_depth(&self, depth: usize) -> usize {
+        if self.heap {
+            depth
+        } else {
+            depth - 1
+        }
+    }
+
+    fn variable(&mut self, name: &str) {
+        let op = match self.resolve(name) {
+            Some((0, slot)) if !self.heap => Op::Arg(slot),
+            Some((depth, slot)) => Op::Local { depth: self.heap_depth(depth), slot, name: self.name(name) },
+            None => Op::Global(self.name(name)),
+        };
+        self.emit(op);
+    }
+
+    fn expr(&mut self, expr: &SchemeValue, tail: bool) {
+        if self.depth == MAX_NESTING {
+            return self.fail(&format!("expression nested deeper than {} levels", MAX_NESTING));
+        }
+        self.depth += 1;
+        self.compile_expr(expr, tail);
+        self.depth -= 1;
+    }
+
+    fn compile_expr(&mut self, expr: &SchemeValue, tail: bool) {
+        let items = match expr {
+            SchemeValue::Symbol(name) => return self.variable(name),
+            SchemeValue::List(items) if items.is_empty() => return self.fail("Empty function call"),
+            SchemeValue::List(items) => items,
+            other => {
+                let i = self.constant(other.clone());
+                self.emit(Op::Const(i));
+                return;
+            }
+        };
+        if let SchemeValue::Symbol(head) = &items[0] {
+            match head.as_str() {
+                "quote" => return self.quote(items),
+                "if" => return self.if_(items, tail),
+                "define" => return self.define(items),
+                "set!" => return self.set(items),
+                "lambda" => return self.lambda(items),
+                "begin" => return self.begin(&items[1..], tail),
+                _ => {}
+            }
+        }
+        for item in items {
+            self.expr(item, false);
+        }
+        let argc = items.len() - 1;
+        self.emit(if tail { Op::TailCall(argc) } else { Op::Call(argc) });
+    }
+
+    fn quote(&mut self, items: &[SchemeValue]) {
+        if items.len() != 2 {
+            return self.fail("quote requires exactly one argument");
+        }
+        let i = self.constant(items[1].clone());
+        self.emit(Op::Const(i));
+    }
+
+    fn if_(&mut self, items: &[SchemeValue], tail: bool) {
+        if items.len() != 3 && items.len() != 4 {
+            return self.fail("if requires two or three arguments");
+        }
+        self.expr(&items[1], false);
+        let to_else = self.emit(Op::JumpIfFalse(0));
+        self.expr(&items[2], tail);
+        let to_end = self.emit(Op::Jump(0));
+        self.code.ops[to_else] = Op::JumpIfFalse(self.code.ops.len());
+        match items.get(3) {
+            Some(otherwise) => self.expr(otherwise, tail),
+            None => {
+                let i = self.constant(SchemeValue::Nil);
+                self.emit(Op::Const(i));
+            }
+        }
+        self.code.ops[to_end] = Op::Jump(self.code.ops.len());
+    }
+
+    fn begin(&mut self, forms: &[SchemeValue], tail: bool) {
+        let Some((last, init)) = forms.split_last() else {
+            let i = self.constant(SchemeValue::Nil);
+            self.emit(Op::Const(i));
+            return;
+        };
+        for form in init {
+            self.expr(form, false);
+            self.emit(Op::Pop);
+        }
+        self.expr(last, tail);
+    }
+
+    fn define(&mut self, items: &[SchemeValue]) {
+        let name = match items.get(1) {
+            Some(SchemeValue::Symbol(name)) if items.len() == 3 => name,
+            _ => return self.fail("define requires a name and a value"),
+        };
+        self.expr(&items[2], false);
+        let op = match self.resolve(name) {
+            // Internal definitions were given slots when the body was scanned.
+            Some((0, slot)) if !self.toplevel => Op::DefineLocal { slot, name: self.name(name) },
+            _ => Op::DefineGlobal(self.name(name)),
+        };
+        self.emit(op);
+    }
+
+    fn set(&mut self, items: &[SchemeValue]) {
+        let name = match items.get(1) {
+            Some(SchemeValue::Symbol(name)) if items.len() == 3 => name,
+            _ => return self.fail("set! requires a name and a value"),
+        };
+        self.expr(&items[2], false);
+        let op = match self.resolve(name) {
+            Some((0, slot)) if !self.heap => Op::SetArg(slot),
+            Some((depth, slot)) => Op::SetLocal { depth: self.heap_depth(depth), slot, name: self.name(name) },
+            None => Op::SetGlobal(self.name(name)),
+        };
+        self.emit(op);
+    }
+
+    fn lambda(&mut self, items: &[SchemeValue]) {
+        if items.len() < 3 {
+            return self.fail("lambda requires parameters and a body");
+        }
+        let (params, rest) = match parse_params(&items[1]) {
+            Ok(params) => params,
+            Err(message) => return self.fail(&message),
+        };
+        let body = &items[2..];
+        let mut defines = Vec::new();
+        let mut creates_closures = false;
+        for form in body {
+            scan(form, &mut defines, &mut creates_closures);
+        }
+
+        let mut slots = params;
+        let params = slots.len();
+        slots.extend(rest.iter().cloned());
+        let heap = creates_closures || !defines.is_empty();
+        if heap {
+            for name in defines {
+                if !slots.contains(&name) {
+                    slots.push(name);
+                }
+            }
+        }
+        let code = Code {
+            params,
+            rest: rest.is_some(),
+            frame_size: if heap { Some(slots.len()) } else { None },
+            captures: creates_closures,
+            ..Code::default()
+        };
+        let mut function = Function { code, slots, heap, toplevel: false, parent: Some(self), depth: self.depth };
+        function.begin(body, true);
+        function.emit(Op::Return);
+        let code = function.code;
+
+        self.code.functions.push(Rc::new(code));
+        let i = self.code.functions.len() - 1;
+        self.emit(Op::Closure(i));
+    }
+}
+
+/// Finds the internal definitions of a lambda body, and whether it creates
+/// closures, without looking inside nested lambdas or quoted data.
+pub(crate) fn scan(form: &SchemeValue, defines: &mut Vec<String>, creates_closures: &mut bool) {
+    let items = match form {
+        SchemeValue::List(items) => items,
+        _ => return,
+    };
+    match items.first() {
+        Some(SchemeValue::Symbol(head)) if head == "quote" => return,
+        Some(SchemeValue::Symbol(head)) if head == "lambda" => {
+            *creates_closures = true;
+            return;
+        }
+        Some(SchemeValue::Symbol(head)) if head == "define" => {
+            if let Some(SchemeValue::Symbol(name)) = items.get(1) {
+                defines.push(name.clone());
+            }
+        }
+        _ => {}
+    }
+    for item in items {
+        scan(item, defines, creates_closures);
+    }
+}
@@ -0,0 +1,320 @@
+// Conversions between Rust and Scheme values, and `register_fn`, which turns
+// a plain Rust closure with typed parameters into a native procedure.
+//
+// Conversions are strict: a number is only an integer if it has no
+// fractional part and fits the target type, and only `#f` converts to `false`
+// or `None`. A failed conversion is an ordinary `SchemeError::Error`, so a
+// script can catch it with `guard`.
+
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+use crate::{SchemeError, SchemeInterpreter, SchemeValue};
+
+/// Reads a Rust value out of a Scheme value.
+pub trait FromScheme: Sized {
+    fn from_scheme(value: &SchemeValue) -> Result<Self, SchemeError>;
+}
+
+/// Turns a Rust value into a Scheme value. Conversion can fail only for
+/// `Result`, whose `Err` becomes the error of the procedure call.
+pub trait IntoScheme {
+    fn into_scheme(self) -> Result<SchemeValue, SchemeError>;
+}
+
+/// The kind of value, for error messages.
+pub(crate) fn type_name(value: &SchemeValue) -> &'static str {
+    match value {
+        SchemeValue::String(_) => "a string",
+        SchemeValue::Number(_) => "a number",
+        SchemeValue::Boolean(_) => "a boolean",
+        SchemeValue::List(_) | SchemeValue::Nil => "a list",
+        SchemeValue::Vector(_) => "a vector",
+        SchemeValue::HashTable(_) => "a hash table",
+        SchemeValue::Function(_)
+        | SchemeValue::Native(_)
+        | SchemeValue::Primitive(_)
+        | SchemeValue::Lambda(_)
+        | SchemeValue::Closure(_)
+        | SchemeValue::Continuation(_) => "a procedure",
+        SchemeValue::Macro(_) => "a macro",
+        SchemeValue::ErrorObject(_) => "an error object",
+        SchemeValue::Values(_) => "multiple values",
+        SchemeValue::Promise(_) => "a promise",
+        SchemeValue::Environment(_) => "an environment",
+        SchemeValue::WeakBox(_) => "a weak box",
+        SchemeValue::WeakTable(_) => "a weak hash table",
+        SchemeValue::Ephemeron(_) => "an ephemeron",
+        SchemeValue::Request(_) => "a request",
+        SchemeValue::Response(_) => "a response",
+        SchemeValue::Symbol(_) => "a symbol",
+    }
+}
+
+fn expected(what: &str, value: &SchemeValue) -> SchemeError {
+    SchemeError::Error(format!("expected {}, got {}", what, type_name(value)))
+}
+
+impl FromScheme for SchemeValue {
+    fn from_scheme(value: &SchemeValue) -> Result<Self, SchemeError> {
+        Ok(value.clone())
+    }
+}
+
+impl IntoScheme for SchemeValue {
+    fn into_scheme(self) -> Result<SchemeValue, SchemeError> {
+        Ok(self)
+    }
+}
+
+macro_rules! integer_conversions {
+    ($($t:ty),*) => {$(
+        impl FromScheme for $t {
+            fn from_scheme(value: &SchemeValue) -> Result<Self, SchemeError> {
+                match *value {
+                    // `MAX as f64` rounds up to a power of two for the wide
+                    // types, so compare against that power exclusively.
+                    SchemeValue::Number(n)
+                        if n.fract() == 0.0
+                            && n >= <$t>::MIN as f64
+                            && n < 2f64.powi(<$t>::BITS as i32 - <$t>::MIN.count_ones() as i32) =>
+                    {
+                        Ok(n as $t)
+                    }
+                    SchemeValue::Number(n) if n.fract() == 0.0 => {
+                        Err(SchemeError::Error(format!("{} is out of range for {}", n, stringify!($t))))
+                    }
+                    _ => Err(expected("an integer", value)),
+                }
+            }
+        }
+
+        impl IntoScheme for $t {
+            fn into_scheme(self) -> Result<SchemeValue, SchemeError> {
+                Ok(SchemeValue::Number(self as f64))
+            }
+        }
+    )*};
+}
+
+integer_conversions!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+macro_rules! float_conversions {
+    ($($t:ty),*) => {$(
+        impl FromScheme for $t {
+            fn from_scheme(value: &SchemeValue) -> Result<Self, SchemeError> {
+                match *value {
+                    SchemeValue::Number(n) => Ok(n as $t),
+                    _ => Err(expected("a number", value)),
+                }
+            }
+        }
+
+        impl IntoScheme for $t {
+            fn into_scheme(self) -> Result<SchemeValue, SchemeError> {
+                Ok(SchemeValue::Number(self as f64))
+            }
+        }
+    )*};
+}
+
+float_conversions!(f32, f64);
+
+impl FromScheme for bool {
+    fn from_scheme(value: &SchemeValue) -> Result<Self, SchemeError> {
+        match value {
+            SchemeValue::Boolean(b) => Ok(*b),
+            _ => Err(expected("a boolean", value)),
+        }
+    }
+}
+
+impl IntoScheme for bool {
+    fn into_scheme(self) -> Result<SchemeValue, SchemeError> {
+        Ok(SchemeValue::Boolean(self))
+    }
+}
+
+impl FromScheme for String {
+    fn from_scheme(value: &SchemeValue) -> Result<Self, SchemeError> {
+        match value {
+            SchemeValue::String(s) => Ok(s.clone()),
+            _ => Err(expected("a string", value)),
+        }
+    }
+}
+
+impl IntoScheme for String {
+    fn into_scheme(self) -> Result<SchemeValue, SchemeError> {
+        Ok(SchemeValue::String(self))
+    }
+}
+
+impl IntoScheme for &str {
+    fn into_scheme(self) -> Result<SchemeValue, SchemeError> {
+        Ok(SchemeValue::String(self.to_string()))
+    }
+}
+
+/// `()` is the unspecified value.
+impl IntoScheme for () {
+    fn into_scheme(self) -> Result<SchemeValue, SchemeError> {
+        Ok(SchemeValue::Nil)
+    }
+}
+
+/// Accepts a list or a vector.
+impl<T: FromScheme> FromScheme for Vec<T> {
+    fn from_scheme(value: &SchemeValue) -> Result<Self, SchemeError> {
+        match value {
+            SchemeValue::Nil => Ok(Vec::new()),
+            SchemeValue::List(items) | SchemeValue::Vector(items) => items.iter().map(T::from_scheme).collect(),
+            _ => Err(expected("a list", value)),
+        }
+    }
+}
+
+/// Produces a list.
+impl<T: IntoScheme> IntoScheme for Vec<T> {
+    fn into_scheme(self) -> Result<SchemeValue, SchemeError> {
+        if self.is_empty() {
+            return Ok(SchemeValue::Nil);
+        }
+        let items = self.into_iter().map(T::into_scheme).collect::<Result<Vec<_>, _>>()?;
+        Ok(SchemeValue::List(items))
+    }
+}
+
+/// `#f` is `None`; anything else must convert to `T`.
+impl<T: FromScheme> FromScheme for Option<T> {
+    fn from_scheme(value: &SchemeValue) -> Result<Self, SchemeError> {
+        match value {
+            SchemeValue::Boolean(false) => Ok(None),
+            other => T::from_scheme(other).map(Some),
+        }
+    }
+}
+
+impl<T: IntoScheme> IntoScheme for Option<T> {
+    fn into_scheme(self) -> Result<SchemeValue, SchemeError> {
+        match self {
+            Some(value) => value.into_scheme(),
+            None => Ok(SchemeValue::Boolean(false)),
+        }
+    }
+}
+
+impl<T: FromScheme, S: BuildHasher + Default> FromScheme for HashMap<String, T, S> {
+    fn from_scheme(value: &SchemeValue) -> Result<Self, SchemeError> {
+        match value {
+            SchemeValue::HashTable(table) => {
+                table.iter().map(|(k, v)| Ok((k.clone(), T::from_scheme(v)?))).collect()
+            }
+            _ => Err(expected("a hash table", value)),
+        }
+    }
+}
+
+impl<T: IntoScheme, S: BuildHasher> IntoScheme for HashMap<String, T, S> {
+    fn into_scheme(self) -> Result<SchemeValue, SchemeError> {
+        let table = self
+            .into_iter()
+            .map(|(k, v)| Ok((k, v.into_scheme()?)))
+            .collect::<Result<HashMap<_, _>, SchemeError>>()?;
+        Ok(SchemeValue::HashTable(table))
+    }
+}
+
+/// A returned `Err` becomes the error of the call, so a native can fail
+/// with a plain message or pass a `SchemeError` through.
+impl<T: IntoScheme, E: Into<SchemeError>> IntoScheme for Result<T, E> {
+    fn into_scheme(self) -> Result<SchemeValue, SchemeError> {
+        self.map_err(Into::into)?.into_scheme()
+    }
+}
+
+/// Tuples convert to and from lists of the same length.
+macro_rules! tuple_conversions {
+    ($len:literal: $($t:ident),+) => {
+        impl<$($t: FromScheme),+> FromScheme for ($($t,)+) {
+            fn from_scheme(value: &SchemeValue) -> Result<Self, SchemeError> {
+                match value {
+                    SchemeValue::List(items) if items.len() == $len => {
+                        let mut items = items.iter();
+                        Ok(($($t::from_scheme(items.next().unwrap())?,)+))
+                    }
+                    _ => Err(expected(concat!("a list of ", $len, " elements"), value)),
+                }
+            }
+        }
+
+        impl<$($t: IntoScheme),+> IntoScheme for ($($t,)+) {
+            #[allow(non_snake_case)]
+            fn into_scheme(self) -> Result<SchemeValue, SchemeError> {
+                let ($($t,)+) = self;
+                Ok(SchemeValue::List(vec![$($t.into_scheme()?),+]))
+            }
+        }
+    };
+}
+
+tuple_conversions!(1: A);
+tuple_conversions!(2: A, B);
+tuple_conversions!(3: A, B, C);
+tuple_conversions!(4: A, B, C, D);
+tuple_conversions!(5: A, B, C, D, E);
+
+/// A Rust closure that `register_fn` can call with converted arguments.
+/// Implemented for `Fn` closures of up to six `FromScheme` parameters
+/// returning an `IntoScheme` value; `Args` is the tuple of parameter types.
+pub trait TypedFn<Args> {
+    const ARITY: usize;
+
+    fn call(&self, name: &str, args: &[SchemeValue]) -> Result<SchemeValue, SchemeError>;
+}
+
+fn argument<T: FromScheme>(name: &str, args: &[SchemeValue], index: usize) -> Result<T, SchemeError> {
+    T::from_scheme(&args[index]).map_err(|e| match e {
+        SchemeError::Error(message) => SchemeError::Error(format!("{}: argument {}: {}", name, index + 1, message)),
+        other => other,
+    })
+}
+
+macro_rules! typed_fn {
+    ($len:literal: $($t:ident $i:tt),*) => {
+        impl<F, R, $($t),*> TypedFn<($($t,)*)> for F
+        where
+            F: Fn($($t),*) -> R,
+            R: IntoScheme,
+            $($t: FromScheme,)*
+        {
+            const ARITY: usize = $len;
+
+            #[allow(unused_variables)]
+            fn call(&self, name: &str, args: &[SchemeValue]) -> Result<SchemeValue, SchemeError> {
+                self($(argument::<$t>(name, args, $i)?),*).into_scheme()
+            }
+        }
+    };
+}
+
+typed_fn!(0:);
+typed_fn!(1: A 0);
+typed_fn!(2: A 0, B 1);
+typed_fn!(3: A 0, B 1, C 2);
+typed_fn!(4: A 0, B 1, C 2, D 3);
+typed_fn!(5: A 0, B 1, C 2, D 3, E 4);
+typed_fn!(6: A 0, B 1, C 2, D 3, E 4, G 5);
+
+impl SchemeInterpreter {
+    /// Registers a Rust closure with typed parameters as a procedure. The
+    /// arity comes from the closure's signature, and arguments that do not
+    /// convert are reported as `name: argument N: expected ...`.
+    pub fn register_fn<Args, F>(&self, name: &str, func: F)
+    where
+        F: TypedFn<Args> + 'static,
+    {
+        let owned_name = name.to_string();
+        self.define_native(name, F::ARITY, move |_ctx, args| func.call(&owned_name, args));
+    }
+}
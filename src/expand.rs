@@ -0,0 +1,775 @@
+// Macro expander: rewrites macro uses and derived syntax (`let`, `cond`,
+// `and`, quasiquote, ...) into the core forms understood by `eval_in` and
+// the bytecode compiler.
+//
+// Explicit-renaming macros get hygiene from aliases. `rename` returns a symbol
+// spelled `#:name@N`, which the reader can never produce. An alias bound by
+// the expansion (a `let` variable, a lambda parameter) stays distinct from
+// every user identifier. A free alias refers to the top-level binding of
+// `name`.
+//
+// Rewrites like `and` and `let*` turn a long flat form into a deeply nested
+// one, so expansion stops at `MAX_NESTING` levels, or the interpreter's
+// `max_depth` if that is lower, instead of exhausting the stack. The
+// optimizer and compiler never see a deeper form.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::{parse_params, Env, Macro, MacroKind, Primitive, SchemeError, SchemeInterpreter, SchemeValue};
+
+/// How deeply `expand_in` may recurse, whatever the limits.
+pub(crate) const MAX_NESTING: usize = 512;
+
+/// Names bound by the enclosing lambdas and bodies of the form being expanded.
+struct Scope {
+    names: Vec<String>,
+    depth: usize,
+    /// Calls to `expand_in` in progress, and how many may be.
+    nesting: usize,
+    max_nesting: usize,
+}
+
+impl Scope {
+    fn binds(&self, name: &str) -> bool {
+        self.names.iter().any(|n| n == name)
+    }
+
+    fn resolve(&self, name: &str) -> String {
+        if self.binds(name) {
+            name.to_string()
+        } else {
+            strip_alias(name).to_string()
+        }
+    }
+}
+
+/// Maps an alias produced by `rename` back to the symbol it was made from.
+pub(crate) fn strip_alias(name: &str) -> &str {
+    match name.strip_prefix("#:").and_then(|n| n.rsplit_once('@')) {
+        Some((original, _)) => strip_alias(original),
+        None => name,
+    }
+}
+
+/// Removes aliases from quoted data, so `(quote x)` always yields the symbol
+/// the macro writer spelled.
+fn strip_datum(datum: &SchemeValue) -> SchemeValue {
+    match datum {
+        SchemeValue::Symbol(s) => SchemeValue::Symbol(strip_alias(s).to_string()),
+        SchemeValue::List(items) => SchemeValue::List(items.iter().map(strip_datum).collect()),
+        SchemeValue::Vector(items) => SchemeValue::Vector(items.iter().map(strip_datum).collect()),
+        other => other.clone(),
+    }
+}
+
+fn sym(name: &str) -> SchemeValue {
+    SchemeValue::Symbol(name.to_string())
+}
+
+fn quoted(datum: SchemeValue) -> SchemeValue {
+    SchemeValue::List(vec![sym("quote"), datum])
+}
+
+fn is_keyword(value: &SchemeValue, keyword: &str, scope: &Scope) -> bool {
+    matches!(value, SchemeValue::Symbol(s) if !scope.binds(s) && strip_alias(s) == keyword)
+}
+
+/// The elements of a list, treating `()` as empty.
+fn list_items<'a>(value: &'a SchemeValue, what: &str) -> Result<&'a [SchemeValue], SchemeError> {
+    match value {
+        SchemeValue::List(items) => Ok(items),
+        SchemeValue::Nil => Ok(&[]),
+        _ => Err(format!("{} must be a list", what).into()),
+    }
+}
+
+/// Splits `((var init) ...)` into variables and initialisers.
+fn parse_bindings(bindings: &SchemeValue, form: &str) -> Result<(Vec<SchemeValue>, Vec<SchemeValue>), SchemeError> {
+    let mut vars = Vec::new();
+    let mut inits = Vec::new();
+    for binding in list_items(bindings, &format!("{} bindings", form))? {
+        match binding {
+            SchemeValue::List(pair) if pair.len() == 2 && matches!(pair[0], SchemeValue::Symbol(_)) => {
+                vars.push(pair[0].clone());
+                inits.push(pair[1].clone());
+            }
+            _ => return Err(format!("{} requires bindings of the form (name value)", form).into()),
+        }
+    }
+    Ok((vars, inits))
+}
+
+impl SchemeInterpreter {
+    /// Fully expands a top-level form. Macros are looked up in `env`, the
+    /// top-level environment, and `define-macro`/`define-syntax` bind there.
+    pub(crate) fn expand(&self, form: &SchemeValue, env: &Env) -> Result<SchemeValue, SchemeError> {
+        let max_nesting = self.limits.max_depth.map_or(MAX_NESTING, |max| max.min(MAX_NESTING));
+        self.expand_in(form, env, &mut Scope { names: Vec::new(), depth: 0, nesting: 0, max_nesting })
+    }
+
+    /// Expands the macro use at the head of `form` once. Returns `None` if
+    /// `form` is not a macro use.
+    pub(crate) fn expand_once(&self, form: &SchemeValue, env: &Env) -> Result<Option<SchemeValue>, SchemeError> {
+        if let SchemeValue::List(items) = form {
+            if let Some(SchemeValue::Symbol(head)) = items.first() {
+                if let Some(mac) = self.lookup_macro(strip_alias(head), env) {
+                    return self.apply_macro(&mac, form).map(Some);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the alias for `name` in the current transformer call. Renaming
+    /// the same symbol twice in one expansion yields the same alias.
+    pub(crate) fn rename(&self, name: &str) -> String {
+        if let Some(alias) = self.rename_scopes.borrow().last().and_then(|scope| scope.get(name)) {
+            return alias.clone();
+        }
+        let n = self.gensym_counter.get() + 1;
+        self.gensym_counter.set(n);
+        let alias = format!("#:{}@{}", name, n);
+        if let Some(scope) = self.rename_scopes.borrow_mut().last_mut() {
+            scope.insert(name.to_string(), alias.clone());
+        }
+        alias
+    }
+
+    fn lookup_macro(&self, name: &str, env: &Env) -> Option<Rc<Macro>> {
+        match env.borrow().get(name) {
+            Some(SchemeValue::Macro(mac)) => Some(mac),
+            _ => None,
+        }
+    }
+
+    fn apply_macro(&self, mac: &Macro, form: &SchemeValue) -> Result<SchemeValue, SchemeError> {
+        match mac.kind {
+            MacroKind::DefineMacro => {
+                let operands = match form {
+                    SchemeValue::List(items) => items[1..].to_vec(),
+                    _ => Vec::new(),
+                };
+                self.apply(&mac.transformer, operands)
+            }
+            MacroKind::ExplicitRenaming => {
+                self.rename_scopes.borrow_mut().push(HashMap::new());
+                let result = self.apply(
+                    &mac.transformer,
+                    vec![
+                        form.clone(),
+                        SchemeValue::Primitive(Primitive::Rename),
+                        SchemeValue::Primitive(Primitive::Compare),
+                    ],
+                );
+                self.rename_scopes.borrow_mut().pop();
+                result
+            }
+        }
+    }
+
+    fn expand_in(&self, form: &SchemeValue, env: &Env, scope: &mut Scope) -> Result<SchemeValue, SchemeError> {
+        match form {
+            SchemeValue::Symbol(s) => Ok(SchemeValue::Symbol(scope.resolve(s))),
+            SchemeValue::List(_) if scope.nesting == scope.max_nesting => {
+                Err(SchemeError::MemoryLimit(format!("expansion nested deeper than {} levels", scope.max_nesting)))
+            }
+            SchemeValue::List(items) => {
+                scope.nesting += 1;
+                let expanded = self.expand_list(items, env, scope);
+                scope.nesting -= 1;
+                expanded
+            }
+            other => Ok(other.clone()),
+        }
+    }
+
+    fn expand_all(&self, forms: &[SchemeValue], env: &Env, scope: &mut Scope) -> Result<Vec<SchemeValue>, SchemeError> {
+        let mut out = Vec::with_capacity(forms.len());
+        for form in forms {
+            out.push(self.expand_in(form, env, scope)?);
+        }
+        Ok(out)
+    }
+
+    fn expand_list(&self, items: &[SchemeValue], env: &Env, scope: &mut Scope) -> Result<SchemeValue, SchemeError> {
+        let head = match items.first() {
+            None => return Ok(SchemeValue::List(Vec::new())),
+            Some(SchemeValue::Symbol(s)) if !scope.binds(s) => strip_alias(s).to_string(),
+            _ => return Ok(SchemeValue::List(self.expand_all(items, env, scope)?)),
+        };
+
+        if let Some(mac) = self.lookup_macro(&head, env) {
+            let expanded = self.apply_macro(&mac, &SchemeValue::List(items.to_vec()))?;
+            return self.expand_in(&expanded, env, scope);
+        }
+
+        // Every arm that expands subforms is kept small, since this frame is
+        // on the stack once per level of nesting.
+        match head.as_str() {
+            "quote" if items.len() != 2 => Err("quote requires exactly one argument".into()),
+            "quote" => Ok(quoted(strip_datum(&items[1]))),
+            "if" if items.len() != 3 && items.len() != 4 => Err("if requires two or three arguments".into()),
+            // The keyword is unbound here, so it expands to its plain name.
+            "if" | "begin" => Ok(SchemeValue::List(self.expand_all(items, env, scope)?)),
+            "set!" => self.expand_set(items, env, scope),
+            "lambda" if items.len() < 3 => Err("lambda requires parameters and a body".into()),
+            "lambda" => self.expand_lambda(&items[1], &items[2..], env, scope),
+            "define" => self.expand_define(items, env, scope),
+            "define-macro" => self.define_macro(items, env),
+            "define-syntax" => self.define_syntax(items, env),
+            _ => match self.rewrite(&head, items, scope)? {
+                Some(rewritten) => self.expand_in(&rewritten, env, scope),
+                None => Ok(SchemeValue::List(self.expand_all(items, env, scope)?)),
+            },
+        }
+    }
+
+    fn expand_set(&self, items: &[SchemeValue], env: &Env, scope: &mut Scope) -> Result<SchemeValue, SchemeError> {
+        let name = match items.get(1) {
+            Some(SchemeValue::Symbol(name)) if items.len() == 3 => scope.resolve(name),
+            _ => return Err("set! requires a name and a value".into()),
+        };
+        let value = self.expand_in(&items[2], env, scope)?;
+        Ok(SchemeValue::List(vec![sym("set!"), SchemeValue::Symbol(name), value]))
+    }
+
+    /// A derived form rewritten into simpler ones, or `None` if `head` does
+    /// not name one. This is apart from `expand_list` so that its large frame
+    /// is not on the stack while the result is expanded.
+    fn rewrite(&self, head: &str, items: &[SchemeValue], scope: &Scope) -> Result<Option<SchemeValue>, SchemeError> {
+        let rewritten = match head {
+            "quasiquote" => {
+                if items.len() != 2 {
+                    return Err("quasiquote requires exactly one argument".into());
+                }
+                self.quasi(&items[1], 1, scope)?
+            }
+            "let" => self.rewrite_let(items)?,
+            "let*" => rewrite_let_star(items)?,
+            "letrec" | "letrec*" => {
+                if items.len() < 3 {
+                    return Err(format!("{} requires bindings and a body", head).into());
+                }
+                let (vars, inits) = parse_bindings(&items[1], head)?;
+                let mut body = vec![sym("lambda"), SchemeValue::Nil];
+                for (var, init) in vars.into_iter().zip(inits) {
+                    body.push(SchemeValue::List(vec![sym("define"), var, init]));
+                }
+                body.extend_from_slice(&items[2..]);
+                SchemeValue::List(vec![SchemeValue::List(body)])
+            }
+            "cond" => self.rewrite_cond(&items[1..], scope)?,
+            "case" => self.rewrite_case(items, scope)?,
+            "and" => match items.len() {
+                1 => SchemeValue::Boolean(true),
+                2 => items[1].clone(),
+                _ => {
+                    let mut rest = vec![sym("and")];
+                    rest.extend_from_slice(&items[2..]);
+                    SchemeValue::List(vec![sym("if"), items[1].clone(), SchemeValue::List(rest), SchemeValue::Boolean(false)])
+                }
+            },
+            "or" => match items.len() {
+                1 => SchemeValue::Boolean(false),
+                2 => items[1].clone(),
+                _ => {
+                    let temp = sym(&self.gensym("t"));
+                    let mut rest = vec![sym("or")];
+                    rest.extend_from_slice(&items[2..]);
+                    SchemeValue::List(vec![
+                        sym("let"),
+                        SchemeValue::List(vec![SchemeValue::List(vec![temp.clone(), items[1].clone()])]),
+                        SchemeValue::List(vec![sym("if"), temp.clone(), temp, SchemeValue::List(rest)]),
+                    ])
+                }
+            },
+            "when" | "unless" => {
+                if items.len() < 3 {
+                    return Err(format!("{} requires a test and a body", head).into());
+                }
+                let mut body = vec![sym("begin")];
+                body.extend_from_slice(&items[2..]);
+                let body = SchemeValue::List(body);
+                let skip = SchemeValue::List(vec![sym("begin")]);
+                let (then, otherwise) = if head == "when" { (body, skip) } else { (skip, body) };
+                SchemeValue::List(vec![sym("if"), items[1].clone(), then, otherwise])
+            }
+            "do" => self.rewrite_do(items)?,
+            "guard" => rewrite_guard(items)?,
+            "receive" => {
+                if items.len() < 4 {
+                    return Err("receive requires formals, an expression and a body".into());
+                }
+                let mut consumer = vec![sym("lambda"), items[1].clone()];
+                consumer.extend_from_slice(&items[3..]);
+                call_with_values(items[2].clone(), SchemeValue::List(consumer))
+            }
+            "let-values" => self.rewrite_let_values(items)?,
+            "delay" | "delay-force" => {
+                if items.len() != 2 {
+                    return Err(format!("{} requires exactly one expression", head).into());
+                }
+                let primitive = if head == "delay" { Primitive::Delay } else { Primitive::DelayForce };
+                SchemeValue::List(vec![
+                    SchemeValue::Primitive(primitive),
+                    SchemeValue::List(vec![sym("lambda"), SchemeValue::Nil, items[1].clone()]),
+                ])
+            }
+            // A stream is a promise of either () or a list of two promises:
+            // the element and the rest of the stream.
+            "stream-cons" => {
+                if items.len() != 3 {
+                    return Err("stream-cons requires an element and a stream".into());
+                }
+                SchemeValue::List(vec![
+                    sym("make-promise"),
+                    SchemeValue::List(vec![
+                        sym("list"),
+                        SchemeValue::List(vec![sym("delay"), items[1].clone()]),
+                        SchemeValue::List(vec![sym("delay-force"), items[2].clone()]),
+                    ]),
+                ])
+            }
+            "let*-values" => rewrite_let_star_values(items)?,
+            "define-values" => self.rewrite_define_values(items)?,
+            _ => return Ok(None),
+        };
+        Ok(Some(rewritten))
+    }
+
+    fn expand_lambda(&self, params: &SchemeValue, body: &[SchemeValue], env: &Env, scope: &mut Scope) -> Result<SchemeValue, SchemeError> {
+        let (names, rest) = parse_params(params)?;
+        let mark = scope.names.len();
+        scope.names.extend(names);
+        scope.names.extend(rest);
+        scope.depth += 1;
+        let expanded = self.expand_body(body, env, scope);
+        scope.depth -= 1;
+        scope.names.truncate(mark);
+        let mut out = vec![sym("lambda"), params.clone()];
+        out.extend(expanded?);
+        Ok(SchemeValue::List(out))
+    }
+
+    /// Internal definitions are visible to the whole body, so their names are
+    /// bound before any form in it is expanded.
+    fn expand_body(&self, body: &[SchemeValue], env: &Env, scope: &mut Scope) -> Result<Vec<SchemeValue>, SchemeError> {
+        for form in body {
+            if let SchemeValue::List(items) = form {
+                if items.len() > 1 && is_keyword(&items[0], "define-values", scope) {
+                    for var in formals_vars(&items[1]) {
+                        if let SchemeValue::Symbol(name) = var {
+                            scope.names.push(name);
+                        }
+                    }
+                }
+                if items.len() > 1 && is_keyword(&items[0], "define", scope) {
+                    match &items[1] {
+                        SchemeValue::Symbol(name) => scope.names.push(name.clone()),
+                        SchemeValue::List(sig) => {
+                            if let Some(SchemeValue::Symbol(name)) = sig.first() {
+                                scope.names.push(name.clone());
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        self.expand_all(body, env, scope)
+    }
+
+    fn expand_define(&self, items: &[SchemeValue], env: &Env, scope: &mut Scope) -> Result<SchemeValue, SchemeError> {
+        let (name, value) = match items.get(1) {
+            Some(SchemeValue::Symbol(name)) if items.len() == 3 => (name.clone(), self.expand_in(&items[2], env, scope)?),
+            Some(SchemeValue::List(sig)) if items.len() >= 3 => {
+                let name = match &sig[0] {
+                    SchemeValue::Symbol(name) => name.clone(),
+                    _ => return Err("define requires a procedure name".into()),
+                };
+                let params = if sig.len() == 1 { SchemeValue::Nil } else { SchemeValue::List(sig[1..].to_vec()) };
+                (name, self.expand_lambda(&params, &items[2..], env, scope)?)
+            }
+            _ => return Err("define requires a name and a value".into()),
+        };
+        // A top-level definition introduced by a macro defines the name the
+        // macro writer spelled, not a hidden alias.
+        let name = if scope.depth == 0 { strip_alias(&name).to_string() } else { name };
+        Ok(SchemeValue::List(vec![sym("define"), SchemeValue::Symbol(name), value]))
+    }
+
+    /// `(define-macro (name . params) body ...)` or `(define-macro name proc)`.
+    /// The transformer is evaluated immediately so later forms can use it.
+    fn define_macro(&self, items: &[SchemeValue], env: &Env) -> Result<SchemeValue, SchemeError> {
+        let (name, transformer) = match items.get(1) {
+            Some(SchemeValue::List(sig)) if items.len() >= 3 => {
+                let params = if sig.len() == 1 { SchemeValue::Nil } else { SchemeValue::List(sig[1..].to_vec()) };
+                let mut lambda = vec![sym("lambda"), params];
+                lambda.extend_from_slice(&items[2..]);
+                (sig[0].clone(), SchemeValue::List(lambda))
+            }
+            Some(name) if items.len() == 3 => (name.clone(), items[2].clone()),
+            _ => return Err("define-macro requires a name and a transformer".into()),
+        };
+        let name = match name {
+            SchemeValue::Symbol(name) => strip_alias(&name).to_string(),
+            _ => return Err("define-macro requires a symbol name".into()),
+        };
+        let transformer = self.eval_expanded(&self.expand(&transformer, env)?, env)?;
+        if !matches!(
+            transformer,
+            SchemeValue::Lambda(_) | SchemeValue::Closure(_) | SchemeValue::Function(_) | SchemeValue::Primitive(_)
+        ) {
+            return Err(format!("define-macro: transformer for {} is not a procedure", name).into());
+        }
+        env.borrow_mut().define(&name, SchemeValue::Macro(Rc::new(Macro { kind: MacroKind::DefineMacro, transformer })));
+        Ok(quoted(SchemeValue::Symbol(name)))
+    }
+
+    /// `(define-syntax name (er-macro-transformer proc))`.
+    fn define_syntax(&self, items: &[SchemeValue], env: &Env) -> Result<SchemeValue, SchemeError> {
+        let name = match items.get(1) {
+            Some(SchemeValue::Symbol(name)) if items.len() == 3 => strip_alias(name).to_string(),
+            _ => return Err("define-syntax requires a name and a transformer".into()),
+        };
+        let transformer = self.eval_expanded(&self.expand(&items[2], env)?, env)?;
+        if !matches!(transformer, SchemeValue::Macro(_)) {
+            return Err(format!("define-syntax: {} is not bound to a macro transformer", name).into());
+        }
+        env.borrow_mut().define(&name, transformer);
+        Ok(quoted(SchemeValue::Symbol(name)))
+    }
+
+    fn rewrite_let(&self, items: &[SchemeValue]) -> Result<SchemeValue, SchemeError> {
+        // Named let: (let loop ((var init) ...) body ...)
+        if let Some(SchemeValue::Symbol(name)) = items.get(1) {
+            if items.len() < 4 {
+                return Err("named let requires bindings and a body".into());
+            }
+            let (vars, inits) = parse_bindings(&items[2], "let")?;
+            let mut lambda = vec![sym("lambda"), SchemeValue::List(vars)];
+            lambda.extend_from_slice(&items[3..]);
+            let name = SchemeValue::Symbol(name.clone());
+            let letrec = SchemeValue::List(vec![
+                sym("letrec"),
+                SchemeValue::List(vec![SchemeValue::List(vec![name.clone(), SchemeValue::List(lambda)])]),
+                name,
+            ]);
+            let mut call = vec![letrec];
+            call.extend(inits);
+            return Ok(SchemeValue::List(call));
+        }
+        if items.len() < 3 {
+            return Err("let requires bindings and a body".into());
+        }
+        let (vars, inits) = parse_bindings(&items[1], "let")?;
+        let vars = if vars.is_empty() { SchemeValue::Nil } else { SchemeValue::List(vars) };
+        let mut lambda = vec![sym("lambda"), vars];
+        lambda.extend_from_slice(&items[2..]);
+        let mut call = vec![SchemeValue::List(lambda)];
+        call.extend(inits);
+        Ok(SchemeValue::List(call))
+    }
+
+    fn rewrite_cond(&self, clauses: &[SchemeValue], scope: &Scope) -> Result<SchemeValue, SchemeError> {
+        let Some((clause, rest)) = clauses.split_first() else {
+            return Ok(SchemeValue::List(vec![sym("begin")]));
+        };
+        let clause = match clause {
+            SchemeValue::List(clause) => clause,
+            _ => return Err("cond clauses must be non-empty lists".into()),
+        };
+        let mut otherwise = vec![sym("cond")];
+        otherwise.extend_from_slice(rest);
+        let otherwise = SchemeValue::List(otherwise);
+
+        if is_keyword(&clause[0], "else", scope) {
+            let mut body = vec![sym("begin")];
+            body.extend_from_slice(&clause[1..]);
+            return Ok(SchemeValue::List(body));
+        }
+        if clause.len() == 1 {
+            return Ok(SchemeValue::List(vec![sym("or"), clause[0].clone(), otherwise]));
+        }
+        if is_keyword(&clause[1], "=>", scope) {
+            if clause.len() != 3 {
+                return Err("cond => clause requires exactly one receiver".into());
+            }
+            let temp = sym(&self.gensym("t"));
+            return Ok(SchemeValue::List(vec![
+                sym("let"),
+                SchemeValue::List(vec![SchemeValue::List(vec![temp.clone(), clause[0].clone()])]),
+                SchemeValue::List(vec![
+                    sym("if"),
+                    temp.clone(),
+                    SchemeValue::List(vec![clause[2].clone(), temp]),
+                    otherwise,
+                ]),
+            ]));
+        }
+        let mut body = vec![sym("begin")];
+        body.extend_from_slice(&clause[1..]);
+        Ok(SchemeValue::List(vec![sym("if"), clause[0].clone(), SchemeValue::List(body), otherwise]))
+    }
+
+    fn rewrite_case(&self, items: &[SchemeValue], scope: &Scope) -> Result<SchemeValue, SchemeError> {
+        if items.len() < 2 {
+            return Err("case requires a key".into());
+        }
+        let key = sym(&self.gensym("key"));
+        let mut clauses = vec![sym("cond")];
+        for clause in &items[2..] {
+            let clause = match clause {
+                SchemeValue::List(clause) if clause.len() >= 2 => clause,
+                _ => return Err("case clauses must have data and a body".into()),
+            };
+            let test = if is_keyword(&clause[0], "else", scope) {
+                clause[0].clone()
+            } else {
+                let mut test = vec![sym("or")];
+                for datum in list_items(&clause[0], "case clause data")? {
+                    test.push(SchemeValue::List(vec![sym("eqv?"), key.clone(), quoted(datum.clone())]));
+                }
+                SchemeValue::List(test)
+            };
+            let mut out = vec![test];
+            out.extend_from_slice(&clause[1..]);
+            clauses.push(SchemeValue::List(out));
+        }
+        Ok(SchemeValue::List(vec![
+            sym("let"),
+            SchemeValue::List(vec![SchemeValue::List(vec![key, items[1].clone()])]),
+            SchemeValue::List(clauses),
+        ]))
+    }
+
+    /// Gives every variable in a formals list a fresh temporary, keeping the
+    /// shape (including any rest variable). Returns the temporary formals and
+    /// the `(var temp)` pairs.
+    fn temp_formals(&self, formals: &SchemeValue) -> (SchemeValue, Vec<SchemeValue>) {
+        let mut bindings = Vec::new();
+        let mut rename = |var: &SchemeValue| match var {
+            SchemeValue::Symbol(s) if s == "." => var.clone(),
+            _ => {
+                let temp = sym(&self.gensym("v"));
+                bindings.push(SchemeValue::List(vec![var.clone(), temp.clone()]));
+                temp
+            }
+        };
+        let temps = match formals {
+            SchemeValue::List(items) => SchemeValue::List(items.iter().map(&mut rename).collect()),
+            SchemeValue::Symbol(_) => rename(formals),
+            other => other.clone(),
+        };
+        (temps, bindings)
+    }
+
+    /// `let-values` evaluates every expression before binding anything, so
+    /// each result is received into temporaries first and bound at the end.
+    fn rewrite_let_values(&self, items: &[SchemeValue]) -> Result<SchemeValue, SchemeError> {
+        if items.len() < 3 {
+            return Err("let-values requires bindings and a body".into());
+        }
+        let mut receivers = Vec::new();
+        let mut bindings = Vec::new();
+        for binding in list_items(&items[1], "let-values bindings")? {
+            match binding {
+                SchemeValue::List(pair) if pair.len() == 2 => {
+                    let (temps, pairs) = self.temp_formals(&pair[0]);
+                    receivers.push((temps, pair[1].clone()));
+                    bindings.extend(pairs);
+                }
+                _ => return Err("let-values requires bindings of the form (formals expression)".into()),
+            }
+        }
+        let mut body = vec![sym("let"), if bindings.is_empty() { SchemeValue::Nil } else { SchemeValue::List(bindings) }];
+        body.extend_from_slice(&items[2..]);
+        let mut result = SchemeValue::List(body);
+        for (temps, expr) in receivers.into_iter().rev() {
+            result = call_with_values(expr, SchemeValue::List(vec![sym("lambda"), temps, result]));
+        }
+        Ok(result)
+    }
+
+    /// `(define-values formals expr)` defines each variable and then assigns
+    /// it from the received values.
+    fn rewrite_define_values(&self, items: &[SchemeValue]) -> Result<SchemeValue, SchemeError> {
+        if items.len() != 3 {
+            return Err("define-values requires formals and an expression".into());
+        }
+        let (temps, pairs) = self.temp_formals(&items[1]);
+        let mut out = vec![sym("begin")];
+        let mut assignments = vec![sym("lambda"), temps];
+        for pair in pairs {
+            if let SchemeValue::List(pair) = pair {
+                out.push(SchemeValue::List(vec![sym("define"), pair[0].clone(), SchemeValue::Boolean(false)]));
+                assignments.push(SchemeValue::List(vec![sym("set!"), pair[0].clone(), pair[1].clone()]));
+            }
+        }
+        if assignments.len() == 2 {
+            assignments.push(SchemeValue::List(vec![sym("begin")]));
+        }
+        out.push(call_with_values(items[2].clone(), SchemeValue::List(assignments)));
+        Ok(SchemeValue::List(out))
+    }
+
+    /// `(do ((var init step) ...) (test result ...) body ...)` becomes a
+    /// named let.
+    fn rewrite_do(&self, items: &[SchemeValue]) -> Result<SchemeValue, SchemeError> {
+        if items.len() < 3 {
+            return Err("do requires bindings and a test clause".into());
+        }
+        let mut bindings = Vec::new();
+        let mut steps = Vec::new();
+        for spec in list_items(&items[1], "do bindings")? {
+            match spec {
+                SchemeValue::List(spec) if (2..=3).contains(&spec.len()) => {
+                    bindings.push(SchemeValue::List(vec![spec[0].clone(), spec[1].clone()]));
+                    steps.push(spec.get(2).unwrap_or(&spec[0]).clone());
+                }
+                _ => return Err("do requires bindings of the form (var init [step])".into()),
+            }
+        }
+        let exit = list_items(&items[2], "do test clause")?;
+        if exit.is_empty() {
+            return Err("do requires a test".into());
+        }
+        let loop_name = sym(&self.gensym("loop"));
+        let mut result = vec![sym("begin")];
+        result.extend_from_slice(&exit[1..]);
+        let mut next = vec![loop_name.clone()];
+        next.extend(steps);
+        let mut body = vec![sym("begin")];
+        body.extend_from_slice(&items[3..]);
+        body.push(SchemeValue::List(next));
+        Ok(SchemeValue::List(vec![
+            sym("let"),
+            loop_name,
+            if bindings.is_empty() { SchemeValue::Nil } else { SchemeValue::List(bindings) },
+            SchemeValue::List(vec![sym("if"), exit[0].clone(), SchemeValue::List(result), SchemeValue::List(body)]),
+        ]))
+    }
+
+    /// Rewrites a quasiquoted template into calls to `list` and `append`.
+    fn quasi(&self, template: &SchemeValue, depth: usize, scope: &Scope) -> Result<SchemeValue, SchemeError> {
+        let items = match template {
+            SchemeValue::List(items) => items,
+            other => return Ok(quoted(other.clone())),
+        };
+        if items.len() == 2 {
+            if is_keyword(&items[0], "unquote", scope) {
+                if depth == 1 {
+                    return Ok(items[1].clone());
+                }
+                return Ok(SchemeValue::List(vec![sym("list"), quoted(sym("unquote")), self.quasi(&items[1], depth - 1, scope)?]));
+            }
+            if is_keyword(&items[0], "quasiquote", scope) {
+                return Ok(SchemeValue::List(vec![sym("list"), quoted(sym("quasiquote")), self.quasi(&items[1], depth + 1, scope)?]));
+            }
+        }
+        let mut parts = vec![sym("append")];
+        for item in items {
+            match item {
+                SchemeValue::List(inner) if inner.len() == 2 && is_keyword(&inner[0], "unquote-splicing", scope) => {
+                    if depth == 1 {
+                        parts.push(inner[1].clone());
+                    } else {
+                        let spliced = SchemeValue::List(vec![
+                            sym("list"),
+                            quoted(sym("unquote-splicing")),
+                            self.quasi(&inner[1], depth - 1, scope)?,
+                        ]);
+                        parts.push(SchemeValue::List(vec![sym("list"), spliced]));
+                    }
+                }
+                _ => parts.push(SchemeValue::List(vec![sym("list"), self.quasi(item, depth, scope)?])),
+            }
+        }
+        Ok(SchemeValue::List(parts))
+    }
+}
+
+fn rewrite_let_star(items: &[SchemeValue]) -> Result<SchemeValue, SchemeError> {
+    if items.len() < 3 {
+        return Err("let* requires bindings and a body".into());
+    }
+    let bindings = list_items(&items[1], "let* bindings")?;
+    if bindings.len() <= 1 {
+        let mut out = vec![sym("let")];
+        out.extend_from_slice(&items[1..]);
+        return Ok(SchemeValue::List(out));
+    }
+    let mut inner = vec![sym("let*"), SchemeValue::List(bindings[1..].to_vec())];
+    inner.extend_from_slice(&items[2..]);
+    Ok(SchemeValue::List(vec![
+        sym("let"),
+        SchemeValue::List(vec![bindings[0].clone()]),
+        SchemeValue::List(inner),
+    ]))
+}
+
+/// `(guard (var clause ...) body ...)` becomes a call to the guard primitive
+/// with the body as a thunk and the clauses as a one-argument handler. The
+/// handler re-raises the condition when no clause matches.
+fn rewrite_guard(items: &[SchemeValue]) -> Result<SchemeValue, SchemeError> {
+    let spec = match items.get(1) {
+        Some(SchemeValue::List(spec)) if items.len() >= 3 && matches!(spec[0], SchemeValue::Symbol(_)) => spec,
+        _ => return Err("guard requires (variable clause ...) and a body".into()),
+    };
+    let var = spec[0].clone();
+    let mut body = vec![sym("lambda"), SchemeValue::Nil];
+    body.extend_from_slice(&items[2..]);
+    let mut clauses = vec![sym("cond")];
+    clauses.extend_from_slice(&spec[1..]);
+    clauses.push(SchemeValue::List(vec![
+        sym("else"),
+        SchemeValue::List(vec![SchemeValue::Primitive(Primitive::Raise), var.clone()]),
+    ]));
+    Ok(SchemeValue::List(vec![
+        SchemeValue::Primitive(Primitive::Guard),
+        SchemeValue::List(body),
+        SchemeValue::List(vec![sym("lambda"), SchemeValue::List(vec![var]), SchemeValue::List(clauses)]),
+    ]))
+}
+
+fn call_with_values(producer: SchemeValue, consumer: SchemeValue) -> SchemeValue {
+    SchemeValue::List(vec![
+        SchemeValue::Primitive(Primitive::CallWithValues),
+        SchemeValue::List(vec![sym("lambda"), SchemeValue::Nil, producer]),
+        consumer,
+    ])
+}
+
+/// The variables named by a lambda-style formals list.
+fn formals_vars(formals: &SchemeValue) -> Vec<SchemeValue> {
+    match formals {
+        SchemeValue::Symbol(_) => vec![formals.clone()],
+        SchemeValue::List(items) => items
+            .iter()
+            .filter(|item| !matches!(item, SchemeValue::Symbol(s) if s == "."))
+            .cloned()
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn rewrite_let_star_values(items: &[SchemeValue]) -> Result<SchemeValue, SchemeError> {
+    if items.len() < 3 {
+        return Err("let*-values requires bindings and a body".into());
+    }
+    let bindings = list_items(&items[1], "let*-values bindings")?;
+    let Some((first, rest)) = bindings.split_first() else {
+        let mut body = vec![sym("let"), SchemeValue::Nil];
+        body.extend_from_slice(&items[2..]);
+        return Ok(SchemeValue::List(body));
+    };
+    let (formals, expr) = match first {
+        SchemeValue::List(pair) if pair.len() == 2 => (pair[0].clone(), pair[1].clone()),
+        _ => return Err("let*-values requires bindings of the form (formals expression)".into()),
+    };
+    let mut inner = vec![sym("let*-values"), if rest.is_empty() { SchemeValue::Nil } else { SchemeValue::List(rest.to_vec()) }];
+    inner.extend_from_slice(&items[2..]);
+    Ok(call_with_values(expr, SchemeValue::List(vec![sym("lambda"), formals, SchemeValue::List(inner)])))
+}
+
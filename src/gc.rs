@@ -0,0 +1,385 @@
+// Cycle collector for environment frames and closures.
+//
+// Values are reference counted, which frees everything except cycles. Here
+// cycles run through environments: a closure keeps its defining frame
+// alive, and that frame, or one inside it, holds the closure. Every named
+// `let` and every local recursive procedure makes one.
+//
+// The collector uses trial deletion, like CPython's. Every frame and
+// closure is registered. A collection subtracts the references that come
+// from other registered objects, so whatever is still referenced from
+// elsewhere (the Rust stack, the host, a native closure) is a root. Frames
+// that no root reaches are garbage and are emptied. That breaks their
+// cycles, and reference counting frees the rest. Only references that are
+// visibly inside the graph are subtracted, so a collection is safe at any
+// point during evaluation.
+//
+// Weak hash tables and ephemerons are registered too. The value of an
+// entry is only traced once its key has been reached some other way, so a
+// value that refers back to its own key does not keep the entry alive.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use crate::vm::{Closure, Frame};
+use crate::weak::{Ephemeron, WeakRef, WeakTable};
+use crate::{Env, Environment, Lambda, PromiseState, SchemeValue};
+
+/// Registry size below which no automatic collection runs.
+const MIN_THRESHOLD: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// Collections run so far, automatic or requested.
+    pub collections: u64,
+    /// Frames, closures, weak tables and ephemerons currently alive.
+    pub live_objects: usize,
+    /// Approximate bytes held by those objects.
+    pub heap_bytes: usize,
+    /// Objects freed by all collections so far.
+    pub freed: u64,
+}
+
+/// A registered object, held weakly so that registration keeps nothing alive.
+pub(crate) enum Tracked {
+    Frame(Weak<RefCell<Environment>>),
+    Closure(Weak<Lambda>),
+    Table(Weak<WeakTable>),
+    Ephemeron(Weak<Ephemeron>),
+    VmFrame(Weak<Frame>),
+    VmClosure(Weak<Closure>),
+}
+
+impl Tracked {
+    fn upgrade(&self) -> Option<Node> {
+        match self {
+            Tracked::Frame(w) => w.upgrade().map(Node::Frame),
+            Tracked::Closure(w) => w.upgrade().map(Node::Closure),
+            Tracked::Table(w) => w.upgrade().map(Node::Table),
+            Tracked::Ephemeron(w) => w.upgrade().map(Node::Ephemeron),
+            Tracked::VmFrame(w) => w.upgrade().map(Node::VmFrame),
+            Tracked::VmClosure(w) => w.upgrade().map(Node::VmClosure),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match self {
+            Tracked::Frame(w) => w.strong_count() > 0,
+            Tracked::Closure(w) => w.strong_count() > 0,
+            Tracked::Table(w) => w.strong_count() > 0,
+            Tracked::Ephemeron(w) => w.strong_count() > 0,
+            Tracked::VmFrame(w) => w.strong_count() > 0,
+            Tracked::VmClosure(w) => w.strong_count() > 0,
+        }
+    }
+}
+
+enum Node {
+    Frame(Env),
+    Closure(Rc<Lambda>),
+    Table(Rc<WeakTable>),
+    Ephemeron(Rc<Ephemeron>),
+    VmFrame(Rc<Frame>),
+    VmClosure(Rc<Closure>),
+}
+
+impl Node {
+    fn address(&self) -> usize {
+        match self {
+            Node::Frame(rc) => address(rc),
+            Node::Closure(rc) => address(rc),
+            Node::Table(rc) => address(rc),
+            Node::Ephemeron(rc) => address(rc),
+            Node::VmFrame(rc) => address(rc),
+            Node::VmClosure(rc) => address(rc),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Frame(rc) => Rc::strong_count(rc),
+            Node::Closure(rc) => Rc::strong_count(rc),
+            Node::Table(rc) => Rc::strong_count(rc),
+            Node::Ephemeron(rc) => Rc::strong_count(rc),
+            Node::VmFrame(rc) => Rc::strong_count(rc),
+            Node::VmClosure(rc) => Rc::strong_count(rc),
+        }
+    }
+
+    fn approximate_size(&self) -> usize {
+        match self {
+            Node::Frame(frame) => {
+                size_of::<RefCell<Environment>>()
+                    + frame.try_borrow().map_or(0, |frame| {
+                        frame.vars.keys().map(|k| k.len() + size_of::<(String, SchemeValue)>()).sum()
+                    })
+            }
+            Node::Closure(closure) => size_of::<Lambda>() + closure.body.len() * size_of::<SchemeValue>(),
+            Node::Table(table) => {
+                size_of::<WeakTable>()
+                    + table.entries.try_borrow().map_or(0, |e| e.len() * size_of::<(usize, (WeakRef, SchemeValue))>())
+            }
+            Node::Ephemeron(_) => size_of::<Ephemeron>(),
+            Node::VmFrame(frame) => {
+                size_of::<Frame>() + frame.slots.try_borrow().map_or(0, |s| s.len() * size_of::<Option<SchemeValue>>())
+            }
+            Node::VmClosure(_) => size_of::<Closure>(),
+        }
+    }
+}
+
+/// What a collection took out of the heap. Dropping it frees the garbage,
+/// which must happen after the heap is no longer borrowed.
+pub(crate) struct Garbage {
+    _frames: Vec<Environment>,
+    _values: Vec<SchemeValue>,
+}
+
+pub(crate) struct Heap {
+    objects: Vec<Tracked>,
+    /// Registry size that triggers the next automatic collection.
+    threshold: usize,
+    collections: u64,
+    freed: u64,
+    last_freed: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap { objects: Vec::new(), threshold: MIN_THRESHOLD, collections: 0, freed: 0, last_freed: 0 }
+    }
+}
+
+impl Heap {
+    /// Registers an object. Returns true when the registry has grown enough
+    /// that a collection is due.
+    pub(crate) fn track(&mut self, object: Tracked) -> bool {
+        self.objects.push(object);
+        self.objects.len() >= self.threshold
+    }
+
+    pub(crate) fn stats(&self) -> GcStats {
+        let mut stats = GcStats { collections: self.collections, freed: self.freed, ..GcStats::default() };
+        for node in self.objects.iter().filter_map(Tracked::upgrade) {
+            stats.live_objects += 1;
+            stats.heap_bytes += node.approximate_size();
+        }
+        stats
+    }
+
+    /// Finds objects that only cycles keep alive, empties them, and hands
+    /// back their old contents for the caller to drop after releasing its
+    /// borrow of the heap.
+    pub(crate) fn collect(&mut self) -> Garbage {
+        let nodes: Vec<Node> = self.objects.iter().filter_map(Tracked::upgrade).collect();
+        let index: HashMap<usize, usize> = nodes.iter().enumerate().map(|(i, node)| (node.address(), i)).collect();
+
+        // References not explained by edges inside the graph. The upgrades
+        // above account for one each.
+        let mut external: Vec<usize> = nodes.iter().map(|node| node.strong_count() - 1).collect();
+        for node in &nodes {
+            edges(node, true, &mut |child| {
+                if let Some(&i) = index.get(&child) {
+                    external[i] = external[i].saturating_sub(1);
+                }
+            });
+        }
+
+        // Mark everything reachable from a root. The value of a weak table
+        // entry or ephemeron counts once its key is marked, which can take
+        // several rounds.
+        let mut reachable: Vec<bool> = external.iter().map(|&n| n > 0).collect();
+        let mut pending: Vec<usize> = (0..nodes.len()).filter(|&i| reachable[i]).collect();
+        let mut children = Vec::new();
+        while !pending.is_empty() {
+            while let Some(i) = pending.pop() {
+                edges(&nodes[i], false, &mut |child| children.push(child));
+                mark(&mut children, &index, &mut reachable, &mut pending);
+            }
+            for (i, node) in nodes.iter().enumerate() {
+                if reachable[i] {
+                    entries(node, &mut |key, value| {
+                        if key_is_reachable(key, &index, &reachable) {
+                            value_edges(value, false, &mut |child| children.push(child));
+                        }
+                    });
+                }
+            }
+            mark(&mut children, &index, &mut reachable, &mut pending);
+        }
+
+        let mut frames = Vec::new();
+        let mut values = Vec::new();
+        for (node, &live) in nodes.iter().zip(&reachable) {
+            match node {
+                Node::Frame(frame) if !live => {
+                    if let Ok(mut frame) = frame.try_borrow_mut() {
+                        frames.push(std::mem::take(&mut *frame));
+                    }
+                }
+                Node::VmFrame(frame) if !live => {
+                    if let Ok(mut slots) = frame.slots.try_borrow_mut() {
+                        values.extend(slots.drain(..).flatten());
+                    }
+                }
+                Node::Table(table) => {
+                    if let Ok(mut entries) = table.entries.try_borrow_mut() {
+                        let dead: Vec<usize> = (entries.iter())
+                            .filter(|(_, (key, _))| !live || !key_is_reachable(key, &index, &reachable))
+                            .map(|(&k, _)| k)
+                            .collect();
+                        values.extend(dead.iter().filter_map(|k| entries.remove(k)).map(|(_, v)| v));
+                    }
+                }
+                Node::Ephemeron(ephemeron) if !live || !key_is_reachable(&ephemeron.key, &index, &reachable) => {
+                    if let Ok(mut datum) = ephemeron.datum.try_borrow_mut() {
+                        values.extend(datum.take());
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.collections += 1;
+        self.last_freed = reachable.iter().filter(|&&r| !r).count();
+        self.freed += self.last_freed as u64;
+        Garbage { _frames: frames, _values: values }
+    }
+
+    /// Prunes dead registry entries after the caller has dropped the
+    /// garbage, and sets the size for the next automatic collection.
+    /// Returns the number of objects the collection freed.
+    pub(crate) fn finish_collection(&mut self) -> usize {
+        self.objects.retain(Tracked::is_alive);
+        self.threshold = MIN_THRESHOLD.max(2 * self.objects.len());
+        self.last_freed
+    }
+}
+
+fn address<T>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const () as usize
+}
+
+fn mark(children: &mut Vec<usize>, index: &HashMap<usize, usize>, reachable: &mut [bool], pending: &mut Vec<usize>) {
+    for child in children.drain(..) {
+        if let Some(&i) = index.get(&child) {
+            if !reachable[i] {
+                reachable[i] = true;
+                pending.push(i);
+            }
+        }
+    }
+}
+
+/// A key is reachable if it is alive and either marked or not one of the
+/// registered objects, which the collector never frees.
+fn key_is_reachable(key: &WeakRef, index: &HashMap<usize, usize>, reachable: &[bool]) -> bool {
+    key.is_alive() && index.get(&key.address()).is_none_or(|&i| reachable[i])
+}
+
+/// Calls `f` with the address of every object `node` holds a strong
+/// reference to. With `exact`, shared intermediate objects (a promise or
+/// error object held elsewhere too) are not looked into, since their
+/// references cannot be attributed to `node`. Without it, the entries of
+/// weak tables and ephemerons are left to `entries`.
+fn edges(node: &Node, exact: bool, f: &mut dyn FnMut(usize)) {
+    match node {
+        Node::Frame(frame) => {
+            // A frame borrowed right now is in use; leaving its edges in
+            // place keeps everything it holds alive.
+            if let Ok(frame) = frame.try_borrow() {
+                for value in frame.vars.values() {
+                    value_edges(value, exact, f);
+                }
+                if let Some(parent) = &frame.parent {
+                    f(address(parent));
+                }
+            }
+        }
+        Node::Closure(closure) => {
+            f(address(&closure.env));
+            for form in &closure.body {
+                value_edges(form, exact, f);
+            }
+        }
+        Node::VmFrame(frame) => {
+            if let Ok(slots) = frame.slots.try_borrow() {
+                for value in slots.iter().flatten() {
+                    value_edges(value, exact, f);
+                }
+            }
+            if let Some(parent) = &frame.parent {
+                f(address(parent));
+            }
+        }
+        Node::VmClosure(closure) => {
+            if let Some(frame) = &closure.frame {
+                f(address(frame));
+            }
+            f(address(&closure.globals));
+        }
+        Node::Table(_) | Node::Ephemeron(_) if exact => entries(node, &mut |_, value| value_edges(value, exact, f)),
+        Node::Table(_) | Node::Ephemeron(_) => {}
+    }
+}
+
+/// Calls `f` with each key and value of a weak table or ephemeron.
+fn entries(node: &Node, f: &mut dyn FnMut(&WeakRef, &SchemeValue)) {
+    match node {
+        Node::Table(table) => {
+            if let Ok(entries) = table.entries.try_borrow() {
+                for (key, value) in entries.values() {
+                    f(key, value);
+                }
+            }
+        }
+        Node::Ephemeron(ephemeron) => {
+            if let Ok(datum) = ephemeron.datum.try_borrow() {
+                if let Some(value) = &*datum {
+                    f(&ephemeron.key, value);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn value_edges(value: &SchemeValue, exact: bool, f: &mut dyn FnMut(usize)) {
+    match value {
+        SchemeValue::Lambda(closure) => f(address(closure)),
+        SchemeValue::Closure(closure) => f(address(closure)),
+        SchemeValue::Environment(frame) => f(address(frame)),
+        SchemeValue::WeakTable(table) => f(address(table)),
+        SchemeValue::Ephemeron(ephemeron) => f(address(ephemeron)),
+        SchemeValue::List(items) | SchemeValue::Vector(items) | SchemeValue::Values(items) => {
+            for item in items {
+                value_edges(item, exact, f);
+            }
+        }
+        SchemeValue::HashTable(table) => {
+            for item in table.values() {
+                value_edges(item, exact, f);
+            }
+        }
+        SchemeValue::Promise(promise) if !exact || Rc::strong_count(promise) == 1 => {
+            if let Ok(state) = promise.state.try_borrow() {
+                if !exact || Rc::strong_count(&state) == 1 {
+                    if let Ok(state) = state.try_borrow() {
+                        match &*state {
+                            PromiseState::Done(v) | PromiseState::Delay(v) | PromiseState::DelayForce(v) => {
+                                value_edges(v, exact, f)
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        SchemeValue::Macro(m) if !exact || Rc::strong_count(m) == 1 => value_edges(&m.transformer, exact, f),
+        SchemeValue::ErrorObject(e) if !exact || Rc::strong_count(e) == 1 => {
+            for irritant in &e.irritants {
+                value_edges(irritant, exact, f);
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,241 @@
+//! HTML from SXML: nested lists with the tag name first.
+//!
+//! ```scheme
+//! (html (head (title "x"))
+//!       (body (p (@ (class "a") (hidden)) "text " (b "bold"))))
+//! ```
+//!
+//! | Node                                   | Written as                            |
+//! |----------------------------------------|---------------------------------------|
+//! | `(tag (@ (name value) ...) child ...)` | an element; the `@` list is optional  |
+//! | string, number                         | escaped text                          |
+//! | `(*RAW* "html" ...)`                   | the strings unescaped                 |
+//! | `(*DECL* DOCTYPE html)`                | `<!DOCTYPE html>`                     |
+//! | `(*TOP* node ...)`, `(node ...)`       | each node in turn                     |
+//! | `#f`, `()`                             | nothing, for use with `if` and `map`  |
+//!
+//! An attribute whose value is `#t` or missing is written as a bare name,
+//! and one whose value is `#f` is left out. Void elements such as `br` and
+//! `img` have no end tag and may not have children. The text of `script`
+//! and `style` is written unescaped, as HTML does not decode it, but may
+//! not contain its own end tag. Tag and attribute names are checked, so no
+//! value can break out of the markup except through `*RAW*`.
+//!
+//! Output goes to a sink in chunks of about `CHUNK_BYTES`, so a large page
+//! can be sent while it is rendered. Scheme code reaches this through
+//! `(sxml->html node)`, which returns a string, and `(sxml->html node write)`,
+//! which calls `write` with each chunk.
+
+use std::io;
+
+use crate::{SchemeError, SchemeInterpreter, SchemeValue};
+
+/// How much output is collected before it is passed on.
+pub const CHUNK_BYTES: usize = 4096;
+
+/// Elements nested deeper than this are rejected.
+const MAX_NESTING: usize = 512;
+
+const VOID_ELEMENTS: [&str; 13] =
+    ["area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track", "wbr"];
+
+/// Escapes text for use in an element or a quoted attribute value.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders `node` into `out` as it goes.
+pub fn write<W: io::Write>(node: &SchemeValue, out: &mut W) -> Result<(), SchemeError> {
+    render(node, &mut |chunk| out.write_all(chunk.as_bytes()).map_err(|e| SchemeError::Error(e.to_string())))
+}
+
+/// Renders `node` into a string.
+pub fn to_string(node: &SchemeValue) -> Result<String, SchemeError> {
+    let mut html = String::new();
+    render(node, &mut |chunk| {
+        html.push_str(chunk);
+        Ok(())
+    })?;
+    Ok(html)
+}
+
+/// Renders `node`, passing the output to `sink` in chunks.
+pub fn render(node: &SchemeValue, sink: &mut dyn FnMut(&str) -> Result<(), SchemeError>) -> Result<(), SchemeError> {
+    let mut renderer = Renderer { buffer: String::new(), sink };
+    renderer.node(node, 0).map_err(|e| match e {
+        SchemeError::Error(message) => SchemeError::Error(format!("sxml->html: {}", message)),
+        other => other,
+    })?;
+    renderer.flush()
+}
+
+impl SchemeInterpreter {
+    pub(crate) fn define_html_procedures(&self) {
+        self.define_native("sxml->html", 1..=2, |ctx, args| match args.get(1) {
+            None => Ok(SchemeValue::String(to_string(&args[0])?)),
+            Some(write) => {
+                render(&args[0], &mut |chunk| ctx.apply(write, vec![SchemeValue::String(chunk.to_string())]).map(drop))?;
+                Ok(SchemeValue::Nil)
+            }
+        });
+    }
+}
+
+struct Renderer<'a> {
+    buffer: String,
+    sink: &'a mut dyn FnMut(&str) -> Result<(), SchemeError>,
+}
+
+impl Renderer<'_> {
+    fn push(&mut self, text: &str) -> Result<(), SchemeError> {
+        self.buffer.push_str(text);
+        if self.buffer.len() >= CHUNK_BYTES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), SchemeError> {
+        if !self.buffer.is_empty() {
+            (self.sink)(&self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    fn escaped(&mut self, text: &str, quotes: bool) -> Result<(), SchemeError> {
+        let mut rest = text;
+        while let Some(i) = rest.find(|c| matches!(c, '&' | '<' | '>') || (quotes && c == '"')) {
+            self.push(&rest[..i])?;
+            self.push(match rest.as_bytes()[i] {
+                b'&' => "&amp;",
+                b'<' => "&lt;",
+                b'>' => "&gt;",
+                _ => "&quot;",
+            })?;
+            rest = &rest[i + 1..];
+        }
+        self.push(rest)
+    }
+
+    fn node(&mut self, node: &SchemeValue, depth: usize) -> Result<(), SchemeError> {
+        if depth > MAX_NESTING {
+            return Err(format!("nested deeper than {}", MAX_NESTING).into());
+        }
+        match node {
+            SchemeValue::String(text) => self.escaped(text, false),
+            SchemeValue::Number(n) => self.push(&n.to_string()),
+            SchemeValue::Boolean(false) | SchemeValue::Nil => Ok(()),
+            SchemeValue::List(items) => match items.first() {
+                None => Ok(()),
+                Some(SchemeValue::Symbol(tag)) if tag == "*TOP*" => self.nodes(&items[1..], depth),
+                Some(SchemeValue::Symbol(tag)) if tag == "*RAW*" => items[1..].iter().try_for_each(|item| match item {
+                    SchemeValue::String(html) => self.push(html),
+                    other => Err(format!("*RAW* takes strings, got {}", other).into()),
+                }),
+                Some(SchemeValue::Symbol(tag)) if tag == "*DECL*" => {
+                    self.push("<!")?;
+                    for (i, word) in items[1..].iter().enumerate() {
+                        match word {
+                            SchemeValue::Symbol(s) if is_name(s) => self.push(s)?,
+                            other => return Err(format!("*DECL* takes names, got {}", other).into()),
+                        }
+                        if i + 2 < items.len() {
+                            self.push(" ")?;
+                        }
+                    }
+                    self.push(">")
+                }
+                Some(SchemeValue::Symbol(tag)) => self.element(tag, &items[1..], depth),
+                _ => self.nodes(items, depth),
+            },
+            other => Err(format!("cannot render {} as HTML", other).into()),
+        }
+    }
+
+    fn nodes(&mut self, nodes: &[SchemeValue], depth: usize) -> Result<(), SchemeError> {
+        nodes.iter().try_for_each(|node| self.node(node, depth + 1))
+    }
+
+    fn element(&mut self, tag: &str, rest: &[SchemeValue], depth: usize) -> Result<(), SchemeError> {
+        if !is_name(tag) {
+            return Err(format!("{:?} is not a valid tag name", tag).into());
+        }
+        let (attributes, children) = match rest.first() {
+            Some(SchemeValue::List(items)) if matches!(items.first(), Some(SchemeValue::Symbol(s)) if s == "@") => {
+                (&items[1..], &rest[1..])
+            }
+            _ => (&[][..], rest),
+        };
+        self.push("<")?;
+        self.push(tag)?;
+        for attribute in attributes {
+            self.attribute(attribute)?;
+        }
+        self.push(">")?;
+        let lower = tag.to_ascii_lowercase();
+        if VOID_ELEMENTS.contains(&lower.as_str()) {
+            if !children.is_empty() {
+                return Err(format!("{} is a void element and cannot have content", tag).into());
+            }
+            return Ok(());
+        }
+        if lower == "script" || lower == "style" {
+            let end = format!("</{}", lower);
+            for child in children {
+                match child {
+                    SchemeValue::String(text) if !text.to_ascii_lowercase().contains(&end) => self.push(text)?,
+                    SchemeValue::String(_) => return Err(format!("{} text cannot contain {}", tag, end).into()),
+                    other => return Err(format!("{} can only contain strings, got {}", tag, other).into()),
+                }
+            }
+        } else {
+            self.nodes(children, depth)?;
+        }
+        self.push("</")?;
+        self.push(tag)?;
+        self.push(">")
+    }
+
+    fn attribute(&mut self, attribute: &SchemeValue) -> Result<(), SchemeError> {
+        let (name, value) = match attribute {
+            SchemeValue::List(pair) if pair.len() <= 2 => match &pair[0] {
+                SchemeValue::Symbol(name) if is_name(name) => (name, pair.get(1)),
+                other => return Err(format!("{} is not a valid attribute name", other).into()),
+            },
+            other => return Err(format!("expected an attribute (name value), got {}", other).into()),
+        };
+        let text = match value {
+            Some(SchemeValue::Boolean(false)) => return Ok(()),
+            None | Some(SchemeValue::Boolean(true)) => None,
+            Some(SchemeValue::String(s)) | Some(SchemeValue::Symbol(s)) => Some(s.clone()),
+            Some(SchemeValue::Number(n)) => Some(n.to_string()),
+            Some(other) => return Err(format!("attribute {} cannot have the value {}", name, other).into()),
+        };
+        self.push(" ")?;
+        self.push(name)?;
+        if let Some(text) = text {
+            self.push("=\"")?;
+            self.escaped(&text, true)?;
+            self.push("\"")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `name` can be written as a tag or attribute name as it is.
+fn is_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'))
+}
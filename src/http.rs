@@ -0,0 +1,171 @@
+//! HTTP requests and responses for handlers written in Scheme.
+//!
+//! A handler is a procedure `(handle request)`. It reads the request with
+//! `request-method`, `request-path`, `request-query`, `request-header` and
+//! `request-body`, and answers with `(make-response status headers body)`,
+//! where `headers` is a list of `(name value)` entries. It may also answer
+//! with a string, which is sent as `200` plain text, or with `#f` to leave
+//! the request to the host.
+//!
+//! These procedures belong to `Profile::Request`. Requests and responses are
+//! values of their own, so a script can neither change a request nor pass
+//! off other data as one.
+//!
+//! ```scheme
+//! (define (handle request)
+//!   (if (equal? (request-path request) "/hello")
+//!       (make-response 200 '(("content-type" "text/plain")) "Hello\n")
+//!       #f))
+//! ```
+
+use std::rc::Rc;
+
+use crate::{FromScheme, IntoScheme, Profile, SchemeError, SchemeInterpreter, SchemeValue};
+
+/// The content type of a response that does not set one.
+pub const DEFAULT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// The profiles the Compute service runs `handle` with.
+pub const HANDLER_PROFILES: [Profile; 3] = [Profile::Pure, Profile::Data, Profile::Request];
+
+/// An incoming request, as the host received it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// The query string without the `?`, empty if there is none.
+    pub query: String,
+    /// Header names are matched without regard to case. A repeated header
+    /// appears once per value.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+/// A response built by a handler.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    /// Always includes `content-type`, `DEFAULT_CONTENT_TYPE` unless the
+    /// handler chose another.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+/// A request becomes an opaque value that only the `request-` procedures
+/// read.
+impl IntoScheme for Request {
+    fn into_scheme(self) -> Result<SchemeValue, SchemeError> {
+        Ok(SchemeValue::Request(Rc::new(self)))
+    }
+}
+
+/// Accepts what `make-response` returns, or a string for a `200` response.
+impl FromScheme for Response {
+    fn from_scheme(value: &SchemeValue) -> Result<Self, SchemeError> {
+        match value {
+            SchemeValue::String(body) => Response::new(200, Vec::new(), body.clone()),
+            SchemeValue::Response(response) => Ok(Response::clone(response)),
+            _ => Err(not_a_response(value)),
+        }
+    }
+}
+
+impl IntoScheme for Response {
+    fn into_scheme(self) -> Result<SchemeValue, SchemeError> {
+        Ok(SchemeValue::Response(Rc::new(self)))
+    }
+}
+
+impl Response {
+    /// Checks the status and headers and lowercases header names, adding a
+    /// `content-type` if there is none.
+    pub fn new(status: u16, headers: Vec<(String, String)>, body: String) -> Result<Response, SchemeError> {
+        if !(100..=599).contains(&status) {
+            return Err(format!("status {} is not between 100 and 599", status).into());
+        }
+        for (name, value) in &headers {
+            let token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+            if name.is_empty() || !name.chars().all(token) {
+                return Err(format!("{:?} is not a valid header name", name).into());
+            }
+            if value.chars().any(|c| c.is_ascii_control() && c != '\t') {
+                return Err(format!("header {} has a control character in its value", name).into());
+            }
+        }
+        let mut headers: Vec<_> = headers.into_iter().map(|(name, value)| (name.to_ascii_lowercase(), value)).collect();
+        if !headers.iter().any(|(name, _)| name == "content-type") {
+            headers.push(("content-type".to_string(), DEFAULT_CONTENT_TYPE.to_string()));
+        }
+        Ok(Response { status, headers, body })
+    }
+}
+
+/// Calls `(handle request)` in `interpreter`. `None` means the handler
+/// returned `#f`.
+pub fn handle(interpreter: &SchemeInterpreter, request: Request) -> Result<Option<Response>, SchemeError> {
+    match interpreter.call("handle", vec![request.into_scheme()?])? {
+        SchemeValue::Boolean(false) => Ok(None),
+        value => Response::from_scheme(&value).map(Some).map_err(|e| format!("handle: {}", e).into()),
+    }
+}
+
+impl SchemeInterpreter {
+    pub(crate) fn define_http_procedures(&self) {
+        self.define_request_field("request-method", |request| &request.method);
+        self.define_request_field("request-path", |request| &request.path);
+        self.define_request_field("request-query", |request| &request.query);
+        self.define_request_field("request-body", |request| &request.body);
+        self.define_native("request-header", 2, |_ctx, args| {
+            let request = as_request("request-header", &args[0])?;
+            let name = String::from_scheme(&args[1]).map_err(|e| format!("request-header: {}", e))?;
+            let value = request.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(&name));
+            Ok(value.map_or(SchemeValue::Boolean(false), |(_, value)| SchemeValue::String(value.clone())))
+        });
+        self.define_native("make-response", 3, |_ctx, args| {
+            let response = (|| {
+                Response::new(u16::from_scheme(&args[0])?, headers_from_scheme(&args[1])?, String::from_scheme(&args[2])?)
+            })();
+            response.and_then(Response::into_scheme).map_err(|e| format!("make-response: {}", e).into())
+        });
+    }
+
+    fn define_request_field(&self, name: &'static str, field: fn(&Request) -> &String) {
+        self.define_native(name, 1, move |_ctx, args| Ok(SchemeValue::String(field(as_request(name, &args[0])?).clone())));
+    }
+}
+
+fn as_request<'a>(name: &str, value: &'a SchemeValue) -> Result<&'a Request, SchemeError> {
+    match value {
+        SchemeValue::Request(request) => Ok(request),
+        _ => Err(format!("{}: expected a request, got {}", name, value).into()),
+    }
+}
+
+/// Reads a list of `(name value)` entries, where the name is a string or a
+/// symbol and the value a string.
+fn headers_from_scheme(value: &SchemeValue) -> Result<Vec<(String, String)>, SchemeError> {
+    let entries = match value {
+        SchemeValue::Nil => return Ok(Vec::new()),
+        SchemeValue::List(entries) => entries,
+        _ => return Err(format!("headers must be a list of (name value) entries, got {}", value).into()),
+    };
+    (entries.iter())
+        .map(|entry| match entry {
+            SchemeValue::List(pair) if pair.len() == 2 => {
+                let name = match &pair[0] {
+                    SchemeValue::String(s) | SchemeValue::Symbol(s) => s.clone(),
+                    other => return Err(format!("header name must be a string or symbol, got {}", other).into()),
+                };
+                match &pair[1] {
+                    SchemeValue::String(value) => Ok((name, value.clone())),
+                    other => Err(format!("header {} must have a string value, got {}", name, other).into()),
+                }
+            }
+            other => Err(format!("headers must be a list of (name value) entries, got {}", other).into()),
+        })
+        .collect()
+}
+
+fn not_a_response(value: &SchemeValue) -> SchemeError {
+    format!("expected a response from make-response or a string, got {}", value).into()
+}
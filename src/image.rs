@@ -0,0 +1,503 @@
+//! Precompiled program images.
+//!
+//! An image holds Scheme programs that have already been read, expanded and
+//! compiled to bytecode, so loading one skips the reader and the expander.
+//! It also holds a snapshot of an interpreter that has loaded the prelude,
+//! which loading restores instead of reading the prelude again.
+//! Build it ahead of time with [`compile`], embed the bytes in the binary
+//! with `include_bytes!`, and load them with `SchemeInterpreter::from_image`.
+//!
+//! Compiling runs each program once in a fresh interpreter, because a form
+//! can only be expanded after the macros and procedures defined before it
+//! exist. A form that defines a macro is stored as source and expanded again
+//! when it runs, so the macro is also available to `eval` at run time.
+//!
+//! The format is tied to the compiler: `VERSION` changes whenever the
+//! bytecode does, and loading an image of another version fails.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::rc::Rc;
+
+use crate::compile::{self, Code, Op};
+use crate::{reader, Primitive, SchemeError, SchemeInterpreter, SchemeValue};
+
+const MAGIC: &[u8; 8] = b"LISPIMG\0";
+const VERSION: u32 = 2;
+
+/// A program in an image, in the order its forms run.
+#[derive(Debug)]
+pub(crate) struct Program {
+    pub(crate) name: String,
+    pub(crate) forms: Vec<ImageForm>,
+}
+
+#[derive(Debug)]
+pub(crate) struct ImageForm {
+    pub(crate) line: usize,
+    pub(crate) source: String,
+    pub(crate) body: Body,
+}
+
+#[derive(Debug)]
+pub(crate) enum Body {
+    Compiled(Rc<Code>),
+    /// The datum of a form that defines macros, expanded when it runs.
+    Source(SchemeValue),
+}
+
+/// A decoded image.
+pub(crate) struct Image<'a> {
+    /// A snapshot of a fresh interpreter with every profile.
+    pub(crate) prelude: &'a [u8],
+    pub(crate) programs: Vec<Rc<Program>>,
+    /// The build interpreter's gensym counter, so aliases made while
+    /// loading cannot collide with those in the compiled code.
+    pub(crate) gensyms: usize,
+}
+
+/// Reads, expands and compiles `(name, source)` programs into an image.
+/// Fails with the first error any of them raises.
+pub fn compile(programs: &[(&str, &str)]) -> Result<Vec<u8>, SchemeError> {
+    let interpreter = SchemeInterpreter::new();
+    let prelude = interpreter.snapshot()?;
+    let env = interpreter.toplevel.borrow().clone();
+    let sources = (programs.iter())
+        .map(|&(name, source)| Ok((name, reader::read_program(source)?)))
+        .collect::<Result<Vec<_>, SchemeError>>()?;
+    // The programs can run one after another in one interpreter, so none
+    // folds a builtin that any of them rebinds.
+    for form in sources.iter().flat_map(|(_, forms)| forms) {
+        interpreter.note_assignments(&form.datum);
+    }
+    let mut compiled = Vec::new();
+    for (name, source_forms) in sources {
+        let mut forms = Vec::new();
+        for form in source_forms {
+            let macros = macro_bindings(&interpreter);
+            let expanded = interpreter.expand(&form.datum, &env)?;
+            let expanded = interpreter.optimize(expanded, &env);
+            let body = if macro_bindings(&interpreter) == macros {
+                Body::Compiled(Rc::new(compile::compile(&expanded)))
+            } else {
+                Body::Source(form.datum.clone())
+            };
+            interpreter.eval_expanded(&expanded, &env).map_err(|e| {
+                SchemeError::Error(format!("{} line {}: {}", name, form.line, e))
+            })?;
+            forms.push(ImageForm { line: form.line, source: form.source, body });
+        }
+        compiled.push(Rc::new(Program { name: name.to_string(), forms }));
+    }
+
+    let mut out = Encoder::default();
+    out.bytes.extend_from_slice(MAGIC);
+    out.u32(VERSION);
+    out.u64(interpreter.gensym_counter.get() as u64);
+    out.u32(prelude.len() as u32);
+    out.bytes.extend_from_slice(&prelude);
+    out.u32(compiled.len() as u32);
+    for program in &compiled {
+        out.str(&program.name);
+        out.u32(program.forms.len() as u32);
+        for form in &program.forms {
+            out.u32(form.line as u32);
+            out.str(&form.source);
+            match &form.body {
+                Body::Compiled(code) => {
+                    out.u8(0);
+                    out.code(code)?;
+                }
+                Body::Source(datum) => {
+                    out.u8(1);
+                    out.value(datum)?;
+                }
+            }
+        }
+    }
+    Ok(out.bytes)
+}
+
+/// The top-level macros, by address, to tell whether a form defined one.
+fn macro_bindings(interpreter: &SchemeInterpreter) -> HashMap<String, usize> {
+    let toplevel = interpreter.toplevel.borrow();
+    let vars = &toplevel.borrow().vars;
+    (vars.iter())
+        .filter_map(|(name, value)| match value {
+            SchemeValue::Macro(mac) => Some((name.clone(), Rc::as_ptr(mac) as usize)),
+            _ => None,
+        })
+        .collect()
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<Image<'_>, SchemeError> {
+    let mut input = Decoder::new(bytes, "image");
+    if input.take(MAGIC.len())? != MAGIC {
+        return Err(image_error("not a program image"));
+    }
+    let version = input.u32()?;
+    if version != VERSION {
+        return Err(image_error(&format!("version {} is not supported (expected {})", version, VERSION)));
+    }
+    let gensyms = input.u64()? as usize;
+    let prelude_len = input.index()?;
+    let prelude = input.take(prelude_len)?;
+    let mut programs = Vec::new();
+    for _ in 0..input.u32()? {
+        let name = input.str()?;
+        let mut forms = Vec::new();
+        for _ in 0..input.u32()? {
+            let line = input.u32()? as usize;
+            let source = input.str()?;
+            let body = match input.u8()? {
+                0 => Body::Compiled(Rc::new(input.code()?)),
+                1 => Body::Source(input.value()?),
+                tag => return Err(image_error(&format!("unknown form tag {}", tag))),
+            };
+            forms.push(ImageForm { line, source, body });
+        }
+        programs.push(Rc::new(Program { name, forms }));
+    }
+    if input.pos != bytes.len() {
+        return Err(image_error("trailing bytes"));
+    }
+    Ok(Image { prelude, programs, gensyms })
+}
+
+fn image_error(message: &str) -> SchemeError {
+    SchemeError::Error(format!("Invalid image: {}", message))
+}
+
+/// The address of a value with identity, which snapshots use to write
+/// each object once.
+pub(crate) fn identity(value: &SchemeValue) -> Option<usize> {
+    fn address<T: ?Sized>(rc: &Rc<T>) -> usize {
+        Rc::as_ptr(rc) as *const () as usize
+    }
+    Some(match value {
+        SchemeValue::Function(f) => *f as usize,
+        SchemeValue::Native(rc) => address(rc),
+        SchemeValue::Lambda(rc) => address(rc),
+        SchemeValue::Closure(rc) => address(rc),
+        SchemeValue::Macro(rc) => address(rc),
+        SchemeValue::Continuation(rc) => address(rc),
+        SchemeValue::ErrorObject(rc) => address(rc),
+        SchemeValue::Promise(rc) => address(rc),
+        SchemeValue::Environment(rc) => address(rc),
+        SchemeValue::WeakBox(rc) => address(rc),
+        SchemeValue::WeakTable(rc) => address(rc),
+        SchemeValue::Ephemeron(rc) => address(rc),
+        SchemeValue::Request(rc) => address(rc),
+        SchemeValue::Response(rc) => address(rc),
+        _ => return None,
+    })
+}
+
+#[derive(Default)]
+pub(crate) struct Encoder {
+    pub(crate) bytes: Vec<u8>,
+    /// Objects with identity, by address, that may be written as a
+    /// reference to their index. Always empty for images.
+    pub(crate) objects: HashMap<usize, u32>,
+}
+
+impl Encoder {
+    pub(crate) fn u8(&mut self, n: u8) {
+        self.bytes.push(n);
+    }
+
+    pub(crate) fn u32(&mut self, n: u32) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    pub(crate) fn u64(&mut self, n: u64) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    pub(crate) fn str(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    pub(crate) fn values(&mut self, items: &[SchemeValue]) -> Result<(), SchemeError> {
+        self.u32(items.len() as u32);
+        items.iter().try_for_each(|item| self.value(item))
+    }
+
+    /// Writes plain data. Procedures and other objects with identity fail.
+    pub(crate) fn value(&mut self, value: &SchemeValue) -> Result<(), SchemeError> {
+        match value {
+            SchemeValue::Nil => self.u8(0),
+            SchemeValue::Boolean(b) => self.u8(if *b { 2 } else { 1 }),
+            SchemeValue::Number(n) => {
+                self.u8(3);
+                self.u64(n.to_bits());
+            }
+            SchemeValue::String(s) => {
+                self.u8(4);
+                self.str(s);
+            }
+            SchemeValue::Symbol(s) => {
+                self.u8(5);
+                self.str(s);
+            }
+            SchemeValue::List(items) => {
+                self.u8(6);
+                self.values(items)?;
+            }
+            SchemeValue::Vector(items) => {
+                self.u8(7);
+                self.values(items)?;
+            }
+            SchemeValue::HashTable(table) => {
+                self.u8(8);
+                let mut keys: Vec<&String> = table.keys().collect();
+                keys.sort();
+                self.u32(keys.len() as u32);
+                for key in keys {
+                    self.str(key);
+                    self.value(&table[key])?;
+                }
+            }
+            SchemeValue::Values(items) => {
+                self.u8(9);
+                self.values(items)?;
+            }
+            SchemeValue::Primitive(primitive) => {
+                self.u8(10);
+                self.str(primitive.name());
+            }
+            other if identity(other).is_some_and(|a| self.objects.contains_key(&a)) => {
+                self.u8(11);
+                self.u32(self.objects[&identity(other).unwrap()]);
+            }
+            other => return Err(format!("{} cannot be stored in an image", other).into()),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn code(&mut self, code: &Code) -> Result<(), SchemeError> {
+        self.u32(code.ops.len() as u32);
+        for op in &code.ops {
+            self.op(*op);
+        }
+        self.values(&code.constants)?;
+        self.u32(code.names.len() as u32);
+        for name in &code.names {
+            self.str(name);
+        }
+        self.u32(code.functions.len() as u32);
+        for function in &code.functions {
+            self.code(function)?;
+        }
+        self.u32(code.params as u32);
+        self.u8(code.rest as u8);
+        self.u32(code.frame_size.map_or(u32::MAX, |n| n as u32));
+        self.u8(code.captures as u8);
+        Ok(())
+    }
+
+    fn op(&mut self, op: Op) {
+        let (tag, operands): (u8, &[usize]) = match op {
+            Op::Const(i) => (0, &[i]),
+            Op::Arg(slot) => (1, &[slot]),
+            Op::SetArg(slot) => (2, &[slot]),
+            Op::Local { depth, slot, name } => (3, &[depth, slot, name]),
+            Op::SetLocal { depth, slot, name } => (4, &[depth, slot, name]),
+            Op::DefineLocal { slot, name } => (5, &[slot, name]),
+            Op::Global(i) => (6, &[i]),
+            Op::SetGlobal(i) => (7, &[i]),
+            Op::DefineGlobal(i) => (8, &[i]),
+            Op::Closure(i) => (9, &[i]),
+            Op::Jump(target) => (10, &[target]),
+            Op::JumpIfFalse(target) => (11, &[target]),
+            Op::Pop => (12, &[]),
+            Op::Call(argc) => (13, &[argc]),
+            Op::TailCall(argc) => (14, &[argc]),
+            Op::Return => (15, &[]),
+            Op::Fail(i) => (16, &[i]),
+        };
+        self.u8(tag);
+        for &n in operands {
+            self.u32(n as u32);
+        }
+    }
+}
+
+pub(crate) struct Decoder<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) pos: usize,
+    /// "image" or "snapshot", for error messages.
+    pub(crate) kind: &'static str,
+    /// The objects read so far, for references by index.
+    pub(crate) objects: Vec<SchemeValue>,
+}
+
+impl<'a> Decoder<'a> {
+    pub(crate) fn new(bytes: &'a [u8], kind: &'static str) -> Self {
+        Decoder { bytes, pos: 0, kind, objects: Vec::new() }
+    }
+
+    pub(crate) fn error(&self, message: &str) -> SchemeError {
+        SchemeError::Error(format!("Invalid {}: {}", self.kind, message))
+    }
+
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8], SchemeError> {
+        let end = (self.pos.checked_add(n).filter(|&end| end <= self.bytes.len())).ok_or_else(|| self.error("truncated"))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, SchemeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, SchemeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, SchemeError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn index(&mut self) -> Result<usize, SchemeError> {
+        Ok(self.u32()? as usize)
+    }
+
+    pub(crate) fn str(&mut self) -> Result<String, SchemeError> {
+        let len = self.index()?;
+        let bytes = self.take(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| self.error("string is not UTF-8"))
+    }
+
+    pub(crate) fn values(&mut self) -> Result<Vec<SchemeValue>, SchemeError> {
+        (0..self.u32()?).map(|_| self.value()).collect()
+    }
+
+    pub(crate) fn value(&mut self) -> Result<SchemeValue, SchemeError> {
+        Ok(match self.u8()? {
+            0 => SchemeValue::Nil,
+            1 => SchemeValue::Boolean(false),
+            2 => SchemeValue::Boolean(true),
+            3 => SchemeValue::Number(f64::from_bits(self.u64()?)),
+            4 => SchemeValue::String(self.str()?),
+            5 => SchemeValue::Symbol(self.str()?),
+            6 => SchemeValue::List(self.values()?),
+            7 => SchemeValue::Vector(self.values()?),
+            8 => {
+                let mut table = HashMap::new();
+                for _ in 0..self.u32()? {
+                    let key = self.str()?;
+                    table.insert(key, self.value()?);
+                }
+                SchemeValue::HashTable(table)
+            }
+            9 => SchemeValue::Values(self.values()?),
+            10 => {
+                let name = self.str()?;
+                let primitive = Primitive::ALL.iter().find(|p| p.name() == name);
+                SchemeValue::Primitive(*primitive.ok_or_else(|| self.error(&format!("unknown primitive {}", name)))?)
+            }
+            11 => {
+                let index = self.index()?;
+                self.objects.get(index).cloned().ok_or_else(|| self.error("reference to a missing object"))?
+            }
+            tag => return Err(self.error(&format!("unknown value tag {}", tag))),
+        })
+    }
+
+    pub(crate) fn code(&mut self) -> Result<Code, SchemeError> {
+        let ops = (0..self.u32()?).map(|_| self.op()).collect::<Result<Vec<Op>, SchemeError>>()?;
+        let constants = self.values()?;
+        let names = (0..self.u32()?).map(|_| self.str()).collect::<Result<Vec<String>, SchemeError>>()?;
+        let functions = (0..self.u32()?).map(|_| self.code().map(Rc::new)).collect::<Result<Vec<_>, SchemeError>>()?;
+        let params = self.index()?;
+        let rest = self.u8()? != 0;
+        let frame_size = Some(self.index()?).filter(|&n| n != u32::MAX as usize);
+        let captures = self.u8()? != 0;
+        let code = Code { ops, constants, names, functions, params, rest, frame_size, captures };
+        if !is_well_formed(&code) {
+            return Err(self.error("malformed bytecode"));
+        }
+        Ok(code)
+    }
+
+    fn op(&mut self) -> Result<Op, SchemeError> {
+        Ok(match self.u8()? {
+            0 => Op::Const(self.index()?),
+            1 => Op::Arg(self.index()?),
+            2 => Op::SetArg(self.index()?),
+            3 => Op::Local { depth: self.index()?, slot: self.index()?, name: self.index()? },
+            4 => Op::SetLocal { depth: self.index()?, slot: self.index()?, name: self.index()? },
+            5 => Op::DefineLocal { slot: self.index()?, name: self.index()? },
+            6 => Op::Global(self.index()?),
+            7 => Op::SetGlobal(self.index()?),
+            8 => Op::DefineGlobal(self.index()?),
+            9 => Op::Closure(self.index()?),
+            10 => Op::Jump(self.index()?),
+            11 => Op::JumpIfFalse(self.index()?),
+            12 => Op::Pop,
+            13 => Op::Call(self.index()?),
+            14 => Op::TailCall(self.index()?),
+            15 => Op::Return,
+            16 => Op::Fail(self.index()?),
+            tag => return Err(self.error(&format!("unknown instruction {}", tag))),
+        })
+    }
+}
+
+/// Whether every operand is in range and no instruction can find the stack
+/// short, so the VM cannot trip over the code with a panic. Lexical
+/// addresses depend on the frames a closure is created in, so the VM checks
+/// those as it runs.
+fn is_well_formed(code: &Code) -> bool {
+    let stack_args = code.params + code.rest as usize;
+    let ok = |i: usize, len: usize| i < len;
+    let valid = code.ops.iter().all(|op| match *op {
+        Op::Const(i) | Op::Fail(i) => ok(i, code.constants.len()),
+        Op::Arg(slot) | Op::SetArg(slot) => code.frame_size.is_none() && ok(slot, stack_args),
+        Op::Local { name, .. } | Op::SetLocal { name, .. } => ok(name, code.names.len()),
+        Op::DefineLocal { slot, name } => ok(slot, code.frame_size.unwrap_or(0)) && ok(name, code.names.len()),
+        Op::Global(i) | Op::SetGlobal(i) | Op::DefineGlobal(i) => ok(i, code.names.len()),
+        Op::Closure(i) => ok(i, code.functions.len()),
+        Op::Jump(target) | Op::JumpIfFalse(target) => ok(target, code.ops.len()),
+        Op::Pop | Op::Call(_) | Op::TailCall(_) | Op::Return => true,
+    });
+    valid && matches!(code.ops.last(), Some(Op::Return)) && has_balanced_stack(code)
+}
+
+/// Follows every path through `code` from the start, checking that each
+/// instruction finds the values it pops and that paths which meet do so
+/// with the same number of values on the stack.
+fn has_balanced_stack(code: &Code) -> bool {
+    let mut heights = vec![None; code.ops.len()];
+    let mut pending: Vec<(usize, usize)> = vec![(0, 0)];
+    while let Some((pc, height)) = pending.pop() {
+        match heights[pc] {
+            Some(seen) if seen == height => continue,
+            Some(_) => return false,
+            None => heights[pc] = Some(height),
+        }
+        let op = code.ops[pc];
+        let (pops, pushes) = match op {
+            Op::Const(_) | Op::Arg(_) | Op::Local { .. } | Op::Global(_) | Op::Closure(_) => (0, 1),
+            Op::SetArg(_) | Op::SetLocal { .. } | Op::SetGlobal(_) | Op::DefineLocal { .. } | Op::DefineGlobal(_) => (1, 1),
+            Op::JumpIfFalse(_) | Op::Pop | Op::Return => (1, 0),
+            Op::Call(argc) | Op::TailCall(argc) => (argc.saturating_add(1), 1),
+            Op::Jump(_) | Op::Fail(_) => (0, 0),
+        };
+        let after = match height.checked_sub(pops) {
+            Some(rest) => rest + pushes,
+            None => return false,
+        };
+        // The last instruction is a `Return`, so `pc + 1` is in range.
+        match op {
+            Op::Return | Op::Fail(_) => {}
+            Op::Jump(target) => pending.push((target, after)),
+            Op::JumpIfFalse(target) => pending.extend([(target, after), (pc + 1, after)]),
+            _ => pending.push((pc + 1, after)),
+        }
+    }
+    true
+}
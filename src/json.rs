@@ -0,0 +1,471 @@
+//! JSON reading and writing.
+//!
+//! | JSON            | Scheme                                                    |
+//! |-----------------|-----------------------------------------------------------|
+//! | object          | hash table keyed by strings, or an alist of `(key value)` |
+//! | array           | vector                                                    |
+//! | string          | string                                                    |
+//! | number          | number                                                    |
+//! | `true`, `false` | `#t`, `#f`                                                |
+//! | `null`          | the symbol `null`                                         |
+//!
+//! When writing, a list whose elements are all two-element lists starting
+//! with a string or symbol is an object, and any other list is an array.
+//! Hash tables have no order of their own, so their keys are always written
+//! sorted; alists keep their order unless `sort_keys` is set.
+//!
+//! Numbers are all floating point for now. Integral values are written
+//! without a fraction, and integers beyond 2^53 lose precision when read.
+//!
+//! Scheme code reaches this through `string->json` (also `json-read`) and
+//! `json->string` (also `json-write`).
+
+use std::fmt::Write;
+
+use crate::{string_arg, SchemeError, SchemeInterpreter, SchemeValue};
+
+/// Arrays and objects nested deeper than this are rejected when reading.
+const MAX_NESTING: usize = 512;
+
+/// How `read` represents objects.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Objects {
+    /// Hash tables keyed by strings. A repeated key keeps its last value.
+    #[default]
+    HashTables,
+    /// Lists of `(key value)` entries in document order, with string keys.
+    Alists,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriteOptions {
+    /// Indent by two spaces, one member or element per line.
+    pub pretty: bool,
+    /// Write alist keys in sorted order.
+    pub sort_keys: bool,
+}
+
+/// Reads a JSON document. Errors give the line and column of the problem.
+pub fn read(text: &str, objects: Objects) -> Result<SchemeValue, SchemeError> {
+    read_document(text, objects).map_err(|e| SchemeError::Error(format!("Invalid JSON: {}", e)))
+}
+
+/// Writes a value as JSON. Fails on values JSON cannot represent, such as
+/// procedures or infinite numbers.
+pub fn write(value: &SchemeValue, options: WriteOptions) -> Result<String, SchemeError> {
+    write_document(value, options).map_err(SchemeError::Error)
+}
+
+impl SchemeInterpreter {
+    pub(crate) fn define_json_procedures(&self) {
+        for name in ["string->json", "json-read"] {
+            self.define_native(name, 1..=2, move |_ctx, args| {
+                let text = string_arg(name, &args[0])?;
+                let objects = match args.get(1) {
+                    None => Objects::HashTables,
+                    Some(SchemeValue::Symbol(s)) if s == "hash-table" => Objects::HashTables,
+                    Some(SchemeValue::Symbol(s)) if s == "alist" => Objects::Alists,
+                    Some(other) => return Err(format!("{}: expected hash-table or alist, got {}", name, other).into()),
+                };
+                read_document(text, objects).map_err(|e| format!("{}: {}", name, e).into())
+            });
+        }
+        for name in ["json->string", "json-write"] {
+            self.define_native(name, 1.., move |_ctx, args| {
+                let mut options = WriteOptions::default();
+                for option in &args[1..] {
+                    match option {
+                        SchemeValue::Symbol(s) if s == "pretty" => options.pretty = true,
+                        SchemeValue::Symbol(s) if s == "sort-keys" => options.sort_keys = true,
+                        other => return Err(format!("{}: unknown option {}", name, other).into()),
+                    }
+                }
+                let json = write_document(&args[0], options).map_err(|e| format!("{}: {}", name, e))?;
+                Ok(SchemeValue::String(json))
+            });
+        }
+    }
+}
+
+fn read_document(text: &str, objects: Objects) -> Result<SchemeValue, String> {
+    let mut reader = Reader { text, pos: 0, objects, depth: 0 };
+    let value = reader.value()?;
+    reader.skip_whitespace();
+    if reader.pos < text.len() {
+        return Err(reader.unexpected());
+    }
+    Ok(value)
+}
+
+struct Reader<'a> {
+    text: &'a str,
+    /// Byte offset of the next character.
+    pos: usize,
+    objects: Objects,
+    depth: usize,
+}
+
+impl Reader<'_> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn error(&self, message: &str) -> String {
+        let before = &self.text[..self.pos];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        format!("{} at line {}, column {}", message, line, column)
+    }
+
+    fn unexpected(&self) -> String {
+        match self.peek() {
+            Some(c) => self.error(&format!("unexpected character {:?}", c)),
+            None => self.error("unexpected end of input"),
+        }
+    }
+
+    fn expected(&self, what: &str) -> String {
+        match self.peek() {
+            Some(c) => self.error(&format!("expected {}, found {:?}", what, c)),
+            None => self.error(&format!("expected {}, found end of input", what)),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn value(&mut self) -> Result<SchemeValue, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.nested(Self::object),
+            Some('[') => self.nested(Self::array),
+            Some('"') => self.string().map(SchemeValue::String),
+            Some('-' | '0'..='9') => self.number(),
+            Some('t') => self.word("true", SchemeValue::Boolean(true)),
+            Some('f') => self.word("false", SchemeValue::Boolean(false)),
+            Some('n') => self.word("null", SchemeValue::Symbol("null".to_string())),
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<SchemeValue, String>) -> Result<SchemeValue, String> {
+        if self.depth == MAX_NESTING {
+            return Err(self.error(&format!("nesting deeper than {} levels", MAX_NESTING)));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn word(&mut self, word: &str, value: SchemeValue) -> Result<SchemeValue, String> {
+        for c in word.chars() {
+            if self.peek() != Some(c) {
+                return Err(self.unexpected());
+            }
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    fn array(&mut self) -> Result<SchemeValue, String> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(SchemeValue::Vector(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(SchemeValue::Vector(items));
+                }
+                _ => return Err(self.expected("',' or ']'")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<SchemeValue, String> {
+        self.pos += 1;
+        let mut entries = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+        } else {
+            loop {
+                self.skip_whitespace();
+                if self.peek() != Some('"') {
+                    return Err(self.expected("a string key"));
+                }
+                let key = self.string()?;
+                self.skip_whitespace();
+                if self.peek() != Some(':') {
+                    return Err(self.expected("':'"));
+                }
+                self.pos += 1;
+                entries.push((key, self.value()?));
+                self.skip_whitespace();
+                match self.peek() {
+                    Some(',') => self.pos += 1,
+                    Some('}') => {
+                        self.pos += 1;
+                        break;
+                    }
+                    _ => return Err(self.expected("',' or '}'")),
+                }
+            }
+        }
+        Ok(match self.objects {
+            Objects::HashTables => SchemeValue::HashTable(entries.into_iter().collect()),
+            Objects::Alists => SchemeValue::List(
+                (entries.into_iter())
+                    .map(|(key, value)| SchemeValue::List(vec![SchemeValue::String(key), value]))
+                    .collect(),
+            ),
+        })
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated string")),
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                }
+                Some('\\') => {
+                    self.pos += 1;
+                    out.push(self.escape()?);
+                }
+                Some(c) if c < ' ' => return Err(self.error("control character in string")),
+                Some(c) => {
+                    out.push(c);
+                    self.pos += c.len_utf8();
+                }
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, String> {
+        let c = match self.peek() {
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('/') => '/',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('u') => {
+                self.pos += 1;
+                return self.unicode();
+            }
+            _ => return Err(self.expected("an escape character")),
+        };
+        self.pos += 1;
+        Ok(c)
+    }
+
+    /// The code point of a `\u` escape, which may be a surrogate pair.
+    fn unicode(&mut self) -> Result<char, String> {
+        let start = self.pos;
+        let high = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&high) && self.text[self.pos..].starts_with("\\u") {
+            self.pos += 2;
+            let low = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                self.pos = start;
+                return Err(self.error("unpaired surrogate in \\u escape"));
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| {
+            self.pos = start;
+            self.error("unpaired surrogate in \\u escape")
+        })
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = (self.text.get(self.pos..self.pos + 4))
+            .filter(|digits| digits.chars().all(|c| c.is_ascii_hexdigit()))
+            .ok_or_else(|| self.error("expected four hex digits"))?;
+        self.pos += 4;
+        Ok(u32::from_str_radix(digits, 16).expect("checked hex digits"))
+    }
+
+    fn number(&mut self) -> Result<SchemeValue, String> {
+        let start = self.pos;
+        let bytes = self.text.as_bytes();
+        if bytes[self.pos] == b'-' {
+            self.pos += 1;
+        }
+        match bytes.get(self.pos) {
+            Some(b'0') => self.pos += 1,
+            Some(b'1'..=b'9') => self.pos += digits(&bytes[self.pos..]),
+            _ => return Err(self.expected("a digit")),
+        }
+        if bytes.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            self.required_digits()?;
+        }
+        if let Some(b'e' | b'E') = bytes.get(self.pos) {
+            self.pos += 1;
+            if let Some(b'+' | b'-') = bytes.get(self.pos) {
+                self.pos += 1;
+            }
+            self.required_digits()?;
+        }
+        let n: f64 = self.text[start..self.pos].parse().expect("checked number syntax");
+        if !n.is_finite() {
+            self.pos = start;
+            return Err(self.error("number out of range"));
+        }
+        Ok(SchemeValue::Number(n))
+    }
+
+    /// The digits after a decimal point or exponent, of which there must be
+    /// at least one.
+    fn required_digits(&mut self) -> Result<(), String> {
+        match digits(&self.text.as_bytes()[self.pos..]) {
+            0 => Err(self.expected("a digit")),
+            n => {
+                self.pos += n;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn digits(bytes: &[u8]) -> usize {
+    bytes.iter().take_while(|b| b.is_ascii_digit()).count()
+}
+
+fn write_document(value: &SchemeValue, options: WriteOptions) -> Result<String, String> {
+    let mut writer = Writer { out: String::new(), options };
+    writer.value(value, 0)?;
+    Ok(writer.out)
+}
+
+struct Writer {
+    out: String,
+    options: WriteOptions,
+}
+
+impl Writer {
+    fn value(&mut self, value: &SchemeValue, indent: usize) -> Result<(), String> {
+        match value {
+            SchemeValue::Boolean(b) => self.out.push_str(if *b { "true" } else { "false" }),
+            SchemeValue::Symbol(s) if s == "null" => self.out.push_str("null"),
+            SchemeValue::Number(n) if n.is_finite() => write!(self.out, "{}", n).expect("writing to a String"),
+            SchemeValue::String(s) => self.string(s),
+            SchemeValue::Vector(items) => self.array(items, indent)?,
+            SchemeValue::Nil => self.out.push_str("[]"),
+            SchemeValue::List(items) => match alist_entries(items) {
+                Some(mut entries) => {
+                    if self.options.sort_keys {
+                        entries.sort_by_key(|&(key, _)| key);
+                    }
+                    self.object(&entries, indent)?
+                }
+                None => self.array(items, indent)?,
+            },
+            SchemeValue::HashTable(table) => {
+                let mut entries: Vec<_> = table.iter().map(|(key, value)| (key.as_str(), value)).collect();
+                entries.sort_by_key(|&(key, _)| key);
+                self.object(&entries, indent)?
+            }
+            other => return Err(format!("cannot represent {} as JSON", other)),
+        }
+        Ok(())
+    }
+
+    fn array(&mut self, items: &[SchemeValue], indent: usize) -> Result<(), String> {
+        self.out.push('[');
+        for (i, item) in items.iter().enumerate() {
+            self.separator(i, indent + 1);
+            self.value(item, indent + 1)?;
+        }
+        self.close(items.is_empty(), indent, ']');
+        Ok(())
+    }
+
+    fn object(&mut self, entries: &[(&str, &SchemeValue)], indent: usize) -> Result<(), String> {
+        self.out.push('{');
+        for (i, (key, value)) in entries.iter().enumerate() {
+            self.separator(i, indent + 1);
+            self.string(key);
+            self.out.push_str(if self.options.pretty { ": " } else { ":" });
+            self.value(value, indent + 1)?;
+        }
+        self.close(entries.is_empty(), indent, '}');
+        Ok(())
+    }
+
+    fn separator(&mut self, index: usize, indent: usize) {
+        if index > 0 {
+            self.out.push(',');
+        }
+        self.newline(indent);
+    }
+
+    fn close(&mut self, empty: bool, indent: usize, bracket: char) {
+        if !empty {
+            self.newline(indent);
+        }
+        self.out.push(bracket);
+    }
+
+    fn newline(&mut self, indent: usize) {
+        if self.options.pretty {
+            self.out.push('\n');
+            self.out.push_str(&"  ".repeat(indent));
+        }
+    }
+
+    fn string(&mut self, s: &str) {
+        self.out.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => self.out.push_str("\\\""),
+                '\\' => self.out.push_str("\\\\"),
+                '\n' => self.out.push_str("\\n"),
+                '\r' => self.out.push_str("\\r"),
+                '\t' => self.out.push_str("\\t"),
+                '\u{8}' => self.out.push_str("\\b"),
+                '\u{c}' => self.out.push_str("\\f"),
+                c if c < ' ' => write!(self.out, "\\u{:04x}", c as u32).expect("writing to a String"),
+                c => self.out.push(c),
+            }
+        }
+        self.out.push('"');
+    }
+}
+
+/// The entries of a list that reads as an alist: every element a
+/// two-element list whose first item is a string or symbol.
+fn alist_entries(items: &[SchemeValue]) -> Option<Vec<(&str, &SchemeValue)>> {
+    if items.is_empty() {
+        return None;
+    }
+    (items.iter())
+        .map(|item| match item {
+            SchemeValue::List(entry) => match entry.as_slice() {
+                [SchemeValue::String(key) | SchemeValue::Symbol(key), value] => Some((key.as_str(), value)),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
@@ -1,8 +1,189 @@
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+mod compile;
+mod convert;
+mod expand;
+mod gc;
+pub mod html;
+pub mod http;
+pub mod image;
+pub mod json;
+mod optimize;
+mod reader;
+mod sandbox;
+pub mod service;
+mod snapshot;
+pub mod template;
+pub mod url;
+mod vm;
+mod weak;
+
+pub use convert::{FromScheme, IntoScheme, TypedFn};
+pub use gc::GcStats;
+pub use sandbox::{Profile, SchemeInterpreterBuilder};
+pub use vm::{Backend, Closure};
+pub use weak::{Ephemeron, WeakBox, WeakTable};
+
+pub type BuiltinFn = fn(&[SchemeValue], &mut HashMap<String, SchemeValue>) -> Result<SchemeValue, String>;
+
+pub type Env = Rc<RefCell<Environment>>;
+
+/// The Rust side of a procedure registered with `define_native`.
+pub type NativeFn = dyn Fn(&mut Ctx, &[SchemeValue]) -> Result<SchemeValue, SchemeError>;
+
+/// Library procedures written in Scheme, loaded into the builtins frame.
+const PRELUDE: &str = include_str!("prelude.scm");
 
 // Simple Scheme interpreter for demonstration
 pub struct SchemeInterpreter {
-    env: HashMap<String, SchemeValue>,
+    global: Env,
+    toplevel: RefCell<Env>,
+    gensym_counter: Cell<usize>,
+    rename_scopes: RefCell<Vec<HashMap<String, String>>>,
+    handlers: RefCell<Vec<Handler>>,
+    /// Steps left before evaluation stops with `ResourceLimit`, if limited.
+    fuel: Cell<Option<u64>>,
+    fuel_consumed: Cell<u64>,
+    deadline: Cell<Option<Instant>>,
+    cancellation: RefCell<Option<CancellationToken>>,
+    limits: InterpreterLimits,
+    /// Nesting depth of `eval_in`, checked against `limits.max_depth`.
+    depth: Cell<usize>,
+    /// Approximate bytes allocated by the current `eval`, `run_program` or
+    /// `call`, checked against `limits.max_allocated_bytes`.
+    allocated: Cell<usize>,
+    profiles: Vec<Profile>,
+    backend: Backend,
+    /// Whether expanded forms go through `optimize` before they run.
+    optimizer: bool,
+    /// Set once a script or the host rebinds a builtin the optimizer folds.
+    /// Folding stops, and folds inside procedures take their original path.
+    rebound: Cell<bool>,
+    /// Every builtin and prelude procedure with its profile, for `environment`.
+    builtins: HashMap<String, Profile>,
+    /// Frames and closures, for the cycle collector.
+    heap: RefCell<gc::Heap>,
+    /// Precompiled programs from `from_image`.
+    programs: Vec<Rc<image::Program>>,
+}
+
+/// Number of steps between checks of the deadline and cancellation token.
+const INTERRUPT_CHECK_INTERVAL: u64 = 1024;
+
+/// A flag the host can set to stop a running evaluation. Clones share the
+/// flag, and it may be set from another thread.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Caps on what a single `eval`, `run_program` or `call` may use. `None`
+/// means unlimited. Going over a cap fails with `SchemeError::MemoryLimit`,
+/// which scripts can catch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InterpreterLimits {
+    /// Approximate bytes allocated for lists, vectors, strings and hash
+    /// tables built by procedures. Memory that is freed again still counts.
+    pub max_allocated_bytes: Option<usize>,
+    /// Longest list or vector a procedure may return.
+    pub max_list_length: Option<usize>,
+    /// Longest string, in bytes, a procedure may return.
+    pub max_string_length: Option<usize>,
+    /// Most entries a hash table may hold.
+    pub max_hash_table_size: Option<usize>,
+    /// Deepest nesting of non-tail evaluations. Tail calls do not count.
+    /// Also bounds how deeply a form may nest once macros and derived
+    /// forms are expanded, which is never more than 512 levels.
+    pub max_depth: Option<usize>,
+}
+
+impl Default for InterpreterLimits {
+    /// Only the depth is limited by default, so that deep recursion fails
+    /// with an error instead of overflowing a 1 MiB wasm stack.
+    fn default() -> Self {
+        InterpreterLimits {
+            max_allocated_bytes: None,
+            max_list_length: None,
+            max_string_length: None,
+            max_hash_table_size: None,
+            max_depth: Some(1_000),
+        }
+    }
+}
+
+/// An entry in the stack of installed exception handlers.
+enum Handler {
+    /// Installed by `with-exception-handler`; called in place by `raise`.
+    Procedure(SchemeValue),
+    /// Installed by `guard`; `raise` unwinds back to the guard instead.
+    Guard,
+}
+
+/// One frame of bindings. Lookups that miss fall through to the parent frame;
+/// the outermost frame holds the builtins.
+#[derive(Default)]
+pub struct Environment {
+    vars: HashMap<String, SchemeValue>,
+    parent: Option<Env>,
+}
+
+impl Environment {
+    pub fn new_child(parent: &Env) -> Env {
+        Rc::new(RefCell::new(Environment {
+            vars: HashMap::new(),
+            parent: Some(parent.clone()),
+        }))
+    }
+
+    pub fn get(&self, name: &str) -> Option<SchemeValue> {
+        match self.vars.get(name) {
+            Some(value) => Some(value.clone()),
+            None => self.parent.as_ref().and_then(|p| p.borrow().get(name)),
+        }
+    }
+
+    pub fn define(&mut self, name: &str, value: SchemeValue) {
+        self.vars.insert(name.to_string(), value);
+    }
+
+    /// Assigns to an existing binding, searching outwards. Returns false if
+    /// the name is unbound.
+    pub fn set(&mut self, name: &str, value: SchemeValue) -> bool {
+        if let Some(slot) = self.vars.get_mut(name) {
+            *slot = value;
+            return true;
+        }
+        match &self.parent {
+            Some(p) => p.borrow_mut().set(name, value),
+            None => false,
+        }
+    }
+}
+
+// Frames are reachable from closures stored in those same frames, so the
+// derived Debug would recurse forever.
+impl fmt::Debug for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#<environment ({} bindings)>", self.vars.len())
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -13,18 +194,417 @@ pub enum SchemeValue {
     List(Vec<SchemeValue>),
     Vector(Vec<SchemeValue>),
     HashTable(std::collections::HashMap<String, SchemeValue>),
-    Function(fn(&[SchemeValue], &mut HashMap<String, SchemeValue>) -> Result<SchemeValue, String>),
-    Lambda {
-        params: Vec<String>,
-        body: Vec<String>,
-        env: HashMap<String, SchemeValue>,
-    },
+    Function(BuiltinFn),
+    Native(Rc<Native>),
+    Primitive(Primitive),
+    Lambda(Rc<Lambda>),
+    /// A procedure compiled by the bytecode backend.
+    Closure(Rc<Closure>),
+    Macro(Rc<Macro>),
+    Continuation(Rc<Continuation>),
+    ErrorObject(Rc<ErrorObject>),
+    /// Zero or several results from `values`. A single value is never
+    /// wrapped, so ordinary returns stay allocation-free.
+    Values(Vec<SchemeValue>),
+    Promise(Rc<Promise>),
+    /// A first-class environment from `environment` or
+    /// `interaction-environment`, for use with `eval`.
+    Environment(Env),
+    WeakBox(Rc<WeakBox>),
+    WeakTable(Rc<WeakTable>),
+    Ephemeron(Rc<Ephemeron>),
+    /// An HTTP request passed to a handler. Scripts can only read it.
+    Request(Rc<http::Request>),
+    /// A response built by `make-response`.
+    Response(Rc<http::Response>),
     Symbol(String),
     Nil,
 }
 
+impl SchemeValue {
+    pub fn error_object(message: String, irritants: Vec<SchemeValue>) -> SchemeValue {
+        SchemeValue::ErrorObject(Rc::new(ErrorObject { message, irritants }))
+    }
+}
+
+impl fmt::Display for SchemeValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemeValue::String(s) => f.write_str(s),
+            SchemeValue::Number(n) => write!(f, "{}", n),
+            SchemeValue::Boolean(b) => write!(f, "{}", b),
+            SchemeValue::List(list) => {
+                f.write_str("[")?;
+                for (i, item) in list.iter().enumerate() {
+                    if i > 0 { f.write_str(", ")?; }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            }
+            SchemeValue::Vector(vec) => {
+                f.write_str("#(")?;
+                for (i, item) in vec.iter().enumerate() {
+                    if i > 0 { f.write_str(" ")?; }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str(")")
+            }
+            SchemeValue::HashTable(_) => f.write_str("#<hash-table>"),
+            SchemeValue::Function(_) => f.write_str("#<function>"),
+            SchemeValue::Native(native) => write!(f, "#<function {}>", native.name),
+            SchemeValue::Primitive(p) => write!(f, "#<function {}>", p.name()),
+            SchemeValue::Lambda(_) | SchemeValue::Closure(_) => f.write_str("#<lambda>"),
+            SchemeValue::Macro(_) => f.write_str("#<macro>"),
+            SchemeValue::Continuation(_) => f.write_str("#<continuation>"),
+            SchemeValue::ErrorObject(e) => write!(f, "#<error {}>", e),
+            SchemeValue::Promise(_) => f.write_str("#<promise>"),
+            SchemeValue::Environment(_) => f.write_str("#<environment>"),
+            SchemeValue::WeakBox(_) => f.write_str("#<weak-box>"),
+            SchemeValue::WeakTable(_) => f.write_str("#<weak-hash-table>"),
+            SchemeValue::Ephemeron(_) => f.write_str("#<ephemeron>"),
+            SchemeValue::Request(request) => write!(f, "#<request {} {}>", request.method, request.path),
+            SchemeValue::Response(response) => write!(f, "#<response {}>", response.status),
+            SchemeValue::Values(values) => {
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 { f.write_str(" ")?; }
+                    write!(f, "{}", value)?;
+                }
+                Ok(())
+            }
+            SchemeValue::Symbol(s) => f.write_str(s),
+            SchemeValue::Nil => f.write_str("()"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Lambda {
+    pub params: Vec<String>,
+    pub rest: Option<String>,
+    pub body: Vec<SchemeValue>,
+    pub env: Env,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MacroKind {
+    /// `define-macro`: the transformer receives the unevaluated operands.
+    DefineMacro,
+    /// `er-macro-transformer`: the transformer receives the whole form plus
+    /// `rename` and `compare` procedures.
+    ExplicitRenaming,
+}
+
+#[derive(Debug)]
+pub struct Macro {
+    pub kind: MacroKind,
+    pub transformer: SchemeValue,
+}
+
+/// An escape-only continuation captured by `call/cc`. It can be invoked while
+/// the `call/cc` that created it is still running; afterwards it is dead and
+/// invoking it is an error.
+#[derive(Debug)]
+pub struct Continuation {
+    active: Cell<bool>,
+}
+
+/// The condition object created by `error` and by failing builtins.
+#[derive(Debug)]
+pub struct ErrorObject {
+    pub message: String,
+    pub irritants: Vec<SchemeValue>,
+}
+
+impl fmt::Display for ErrorObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)?;
+        for irritant in &self.irritants {
+            write!(f, " {}", irritant)?;
+        }
+        Ok(())
+    }
+}
+
+/// A host procedure registered with `SchemeInterpreter::define_native`.
+/// Unlike `Function`, it may capture state.
+pub struct Native {
+    pub name: String,
+    pub arity: Arity,
+    func: Box<NativeFn>,
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Native").field("name", &self.name).field("arity", &self.arity).finish()
+    }
+}
+
+/// How many arguments a native procedure accepts. Checked before the Rust
+/// closure runs, so natives can index `args` without checking its length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+    Between(usize, usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match *self {
+            Arity::Exactly(n) => count == n,
+            Arity::AtLeast(min) => count >= min,
+            Arity::Between(min, max) => (min..=max).contains(&count),
+        }
+    }
+}
+
+impl From<usize> for Arity {
+    fn from(n: usize) -> Self {
+        Arity::Exactly(n)
+    }
+}
+
+impl From<std::ops::RangeFrom<usize>> for Arity {
+    fn from(range: std::ops::RangeFrom<usize>) -> Self {
+        Arity::AtLeast(range.start)
+    }
+}
+
+impl From<std::ops::RangeInclusive<usize>> for Arity {
+    fn from(range: std::ops::RangeInclusive<usize>) -> Self {
+        Arity::Between(*range.start(), *range.end())
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arity::Exactly(1) => f.write_str("1 argument"),
+            Arity::Exactly(n) => write!(f, "{} arguments", n),
+            Arity::AtLeast(1) => f.write_str("at least 1 argument"),
+            Arity::AtLeast(n) => write!(f, "at least {} arguments", n),
+            Arity::Between(min, max) => write!(f, "{} to {} arguments", min, max),
+        }
+    }
+}
+
+/// What a native procedure can reach while it runs.
+pub struct Ctx<'a> {
+    interpreter: &'a SchemeInterpreter,
+}
+
+impl Ctx<'_> {
+    pub fn interpreter(&self) -> &SchemeInterpreter {
+        self.interpreter
+    }
+
+    /// Calls a Scheme procedure, e.g. a callback passed to the native.
+    /// Errors, including `raise` and continuation escapes, should be
+    /// returned from the native unchanged.
+    pub fn apply(&mut self, func: &SchemeValue, args: Vec<SchemeValue>) -> Result<SchemeValue, SchemeError> {
+        self.interpreter.apply(func, args)
+    }
+}
+
+/// A promise from `delay`, `delay-force` or `make-promise`. Following
+/// SRFI-45, a promise points at a shared state box. Forcing a `delay-force`
+/// chain re-points each link at one box, so long chains of lazy tail calls
+/// run in constant space.
+#[derive(Debug)]
+pub struct Promise {
+    state: RefCell<Rc<RefCell<PromiseState>>>,
+}
+
+#[derive(Clone, Debug)]
+enum PromiseState {
+    Done(SchemeValue),
+    /// `(delay expr)`: the thunk's result is the promise's value.
+    Delay(SchemeValue),
+    /// `(delay-force expr)`: the thunk returns another promise to continue with.
+    DelayForce(SchemeValue),
+}
+
+impl Promise {
+    fn make(state: PromiseState) -> SchemeValue {
+        SchemeValue::Promise(Rc::new(Promise { state: RefCell::new(Rc::new(RefCell::new(state))) }))
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(*self.state.borrow().borrow(), PromiseState::Done(_))
+    }
+}
+
+/// How an evaluation stopped early. An `Escape` is not a failure: it carries
+/// a value back up the Rust stack to the `call/cc` that created `target`,
+/// running `dynamic-wind` after thunks on the way.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SchemeError {
+    Error(String),
+    Escape { target: Rc<Continuation>, value: SchemeValue },
+    /// An object passed to `raise` on its way to the nearest `guard`.
+    Raise(SchemeValue),
+    /// The script ran out of fuel or another budget that ends the run.
+    /// Scripts cannot catch this with `guard` or `with-exception-handler`.
+    ResourceLimit(String),
+    /// The script went over one of its `InterpreterLimits`, or an allocation
+    /// it asked for failed. Scripts can catch this as an error object, and
+    /// the host sees it only if they do not.
+    MemoryLimit(String),
+    /// The host's deadline passed or its cancellation token was set. Like
+    /// `ResourceLimit`, scripts cannot catch it.
+    Interrupted(String),
+}
+
+impl From<String> for SchemeError {
+    fn from(message: String) -> Self {
+        SchemeError::Error(message)
+    }
+}
+
+impl From<&str> for SchemeError {
+    fn from(message: &str) -> Self {
+        SchemeError::Error(message.to_string())
+    }
+}
+
+impl std::error::Error for SchemeError {}
+
+impl fmt::Display for SchemeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemeError::Error(message) => f.write_str(message),
+            SchemeError::Escape { .. } => f.write_str("Continuation invoked outside the form that captured it"),
+            SchemeError::Raise(SchemeValue::ErrorObject(e)) => write!(f, "{}", e),
+            SchemeError::Raise(value) => write!(f, "Uncaught exception: {}", value),
+            SchemeError::ResourceLimit(message) | SchemeError::MemoryLimit(message) => write!(f, "Resource limit exceeded: {}", message),
+            SchemeError::Interrupted(message) => write!(f, "Interrupted: {}", message),
+        }
+    }
+}
+
+/// Builtins that need the interpreter itself, either to call back into
+/// Scheme procedures or to reach the macro expander.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Primitive {
+    MacroExpand,
+    MacroExpand1,
+    ExpandOptimize,
+    BuiltinsUnchanged,
+    ErMacroTransformer,
+    Rename,
+    Compare,
+    Gensym,
+    CallCC,
+    CallEC,
+    DynamicWind,
+    Raise,
+    RaiseContinuable,
+    WithExceptionHandler,
+    Error,
+    Guard,
+    CallWithValues,
+    Delay,
+    DelayForce,
+    Force,
+    Eval,
+    Environment,
+    InteractionEnvironment,
+}
+
+impl Primitive {
+    pub const ALL: [Primitive; 23] = [
+        Primitive::MacroExpand,
+        Primitive::MacroExpand1,
+        Primitive::ExpandOptimize,
+        Primitive::BuiltinsUnchanged,
+        Primitive::ErMacroTransformer,
+        Primitive::Rename,
+        Primitive::Compare,
+        Primitive::Gensym,
+        Primitive::CallCC,
+        Primitive::CallEC,
+        Primitive::DynamicWind,
+        Primitive::Raise,
+        Primitive::RaiseContinuable,
+        Primitive::WithExceptionHandler,
+        Primitive::Error,
+        Primitive::Guard,
+        Primitive::CallWithValues,
+        Primitive::Delay,
+        Primitive::DelayForce,
+        Primitive::Force,
+        Primitive::Eval,
+        Primitive::Environment,
+        Primitive::InteractionEnvironment,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Primitive::MacroExpand => "macroexpand",
+            Primitive::MacroExpand1 => "macroexpand-1",
+            Primitive::ExpandOptimize => "expand/optimize",
+            Primitive::BuiltinsUnchanged => "builtins-unchanged?",
+            Primitive::ErMacroTransformer => "er-macro-transformer",
+            Primitive::Rename => "rename",
+            Primitive::Compare => "compare",
+            Primitive::Gensym => "gensym",
+            Primitive::CallCC => "call-with-current-continuation",
+            Primitive::CallEC => "call-with-escape-continuation",
+            Primitive::DynamicWind => "dynamic-wind",
+            Primitive::Raise => "raise",
+            Primitive::RaiseContinuable => "raise-continuable",
+            Primitive::WithExceptionHandler => "with-exception-handler",
+            Primitive::Error => "error",
+            Primitive::Guard => "guard",
+            Primitive::CallWithValues => "call-with-values",
+            Primitive::Delay => "delay",
+            Primitive::DelayForce => "delay-force",
+            Primitive::Force => "force",
+            Primitive::Eval => "eval",
+            Primitive::Environment => "environment",
+            Primitive::InteractionEnvironment => "interaction-environment",
+        }
+    }
+}
+
 impl SchemeInterpreter {
+    /// An interpreter with every profile installed.
     pub fn new() -> Self {
+        Self::builder().with_profiles(Profile::ALL).build()
+    }
+
+    /// An interpreter with every profile installed and the given limits.
+    pub fn with_limits(limits: InterpreterLimits) -> Self {
+        Self::builder().with_profiles(Profile::ALL).with_limits(limits).build()
+    }
+
+    /// Starts building an interpreter that installs only `Profile::Pure`
+    /// plus the profiles added with `with_profile`.
+    pub fn builder() -> SchemeInterpreterBuilder {
+        SchemeInterpreterBuilder::default()
+    }
+
+    pub(crate) fn build(profiles: Vec<Profile>, limits: InterpreterLimits, backend: Backend, optimizer: bool) -> Self {
+        let mut interpreter = Self::build_natives(profiles, limits, backend, optimizer);
+        for form in reader::read_program(PRELUDE).expect("prelude must parse") {
+            if let Err(e) = interpreter.eval_toplevel(&form.datum, &interpreter.global) {
+                panic!("prelude failed on line {}: {}", form.line, e);
+            }
+        }
+        interpreter.classify_builtins();
+        interpreter.fuel_consumed.set(0);
+        interpreter
+    }
+
+    /// An interpreter with the builtins written in Rust but without the
+    /// prelude, which `build` loads and `restore` takes from the snapshot.
+    pub(crate) fn build_natives(
+        profiles: Vec<Profile>,
+        limits: InterpreterLimits,
+        backend: Backend,
+        optimizer: bool,
+    ) -> Self {
         let mut env = HashMap::new();
         
         // Add some basic functions
@@ -52,7 +632,7 @@ impl SchemeInterpreter {
         }));
 
         env.insert("-".to_string(), SchemeValue::Function(|args, _| {
-            if args.len() < 1 {
+            if args.is_empty() {
                 return Err("- requires at least one argument".to_string());
             }
             match &args[0] {
@@ -109,17 +689,6 @@ impl SchemeInterpreter {
             }
         }));
 
-        env.insert("if".to_string(), SchemeValue::Function(|args, _| {
-            if args.len() != 3 {
-                return Err("if requires exactly three arguments".to_string());
-            }
-            match &args[0] {
-                SchemeValue::Boolean(true) => Ok(args[1].clone()),
-                SchemeValue::Boolean(false) => Ok(args[2].clone()),
-                _ => Err("if condition must be boolean".to_string()),
-            }
-        }));
-
         // List operations
         env.insert("cons".to_string(), SchemeValue::Function(|args, _| {
             if args.len() != 2 {
@@ -167,6 +736,13 @@ impl SchemeInterpreter {
             }
         }));
 
+        // Compositions used when taking macro forms apart
+        env.insert("cadr".to_string(), SchemeValue::Function(|args, _| cxr("cadr", args)));
+        env.insert("cddr".to_string(), SchemeValue::Function(|args, _| cxr("cddr", args)));
+        env.insert("caddr".to_string(), SchemeValue::Function(|args, _| cxr("caddr", args)));
+        env.insert("cdddr".to_string(), SchemeValue::Function(|args, _| cxr("cdddr", args)));
+        env.insert("cadddr".to_string(), SchemeValue::Function(|args, _| cxr("cadddr", args)));
+
         env.insert("list".to_string(), SchemeValue::Function(|args, _| {
             Ok(SchemeValue::List(args.to_vec()))
         }));
@@ -235,90 +811,6 @@ impl SchemeInterpreter {
             }
         }));
 
-        env.insert("and".to_string(), SchemeValue::Function(|args, _| {
-            if args.is_empty() {
-                return Ok(SchemeValue::Boolean(true));
-            }
-            for arg in args {
-                match arg {
-                    SchemeValue::Boolean(false) => return Ok(SchemeValue::Boolean(false)),
-                    _ => continue,
-                }
-            }
-            Ok(args.last().unwrap().clone())
-        }));
-
-        env.insert("or".to_string(), SchemeValue::Function(|args, _| {
-            if args.is_empty() {
-                return Ok(SchemeValue::Boolean(false));
-            }
-            for arg in args {
-                match arg {
-                    SchemeValue::Boolean(true) => return Ok(SchemeValue::Boolean(true)),
-                    _ => continue,
-                }
-            }
-            Ok(args.last().unwrap().clone())
-        }));
-
-        // Advanced control flow
-        env.insert("begin".to_string(), SchemeValue::Function(|args, _env| {
-            if args.is_empty() {
-                return Ok(SchemeValue::Nil);
-            }
-            Ok(args.last().unwrap().clone())
-        }));
-
-        env.insert("let".to_string(), SchemeValue::Function(|args, _env| {
-            if args.is_empty() {
-                return Ok(SchemeValue::Nil);
-            }
-            // For now, just return the last argument (the body)
-            // In a full implementation, this would evaluate bindings and create a new environment
-            if args.len() >= 2 {
-                Ok(args.last().unwrap().clone())
-            } else {
-                Ok(args[0].clone())
-            }
-        }));
-
-        env.insert("cond".to_string(), SchemeValue::Function(|args, _env| {
-            if args.is_empty() {
-                return Ok(SchemeValue::Nil);
-            }
-            
-            // Process condition-result pairs
-            // Each pair should be a list with [condition result]
-            for i in (0..args.len()).step_by(2) {
-                if i + 1 < args.len() {
-                    // Check if this is a condition-result pair
-                    let condition = &args[i];
-                    let result = &args[i + 1];
-                    
-                    // Evaluate the condition
-                    match condition {
-                        SchemeValue::Boolean(true) => {
-                            return Ok(result.clone());
-                        }
-                        SchemeValue::Boolean(false) => {
-                            continue; // Try next pair
-                        }
-                        _ => {
-                            // For non-boolean conditions, treat as true if not false
-                            return Ok(result.clone());
-                        }
-                    }
-                }
-            }
-            
-            // If no condition was true, return the last argument or nil
-            if !args.is_empty() {
-                Ok(args.last().unwrap().clone())
-            } else {
-                Ok(SchemeValue::Nil)
-            }
-        }));
-
         // Data structures
         env.insert("vector".to_string(), SchemeValue::Function(|args, _| {
             Ok(SchemeValue::Vector(args.to_vec()))
@@ -342,13 +834,13 @@ impl SchemeInterpreter {
         }));
 
         env.insert("hash-ref".to_string(), SchemeValue::Function(|args, _| {
-            if args.len() != 2 {
-                return Err("hash-ref requires exactly two arguments".to_string());
+            if args.len() != 2 && args.len() != 3 {
+                return Err("hash-ref requires a hash table, a key and an optional default".to_string());
             }
             match (&args[0], &args[1]) {
-                (SchemeValue::HashTable(_), SchemeValue::String(key)) => {
-                    // In a full implementation, this would look up the key
-                    Ok(SchemeValue::String(format!("value for key: {}", key)))
+                (SchemeValue::HashTable(table), SchemeValue::String(key)) => {
+                    let default = args.get(2).cloned().unwrap_or(SchemeValue::Boolean(false));
+                    Ok(table.get(key).cloned().unwrap_or(default))
                 }
                 _ => Err("hash-ref requires a hash table and string key".to_string()),
             }
@@ -418,6 +910,7 @@ impl SchemeInterpreter {
             for arg in args {
                 match arg {
                     SchemeValue::List(list) => result.extend(list.clone()),
+                    SchemeValue::Nil => {}
                     _ => result.push(arg.clone()),
                 }
             }
@@ -457,218 +950,1266 @@ impl SchemeInterpreter {
             }
         }));
 
-        Self { env }
+
+        // Equality and type predicates
+        env.insert("not".to_string(), SchemeValue::Function(|args, _| {
+            if args.len() != 1 {
+                return Err("not requires exactly one argument".to_string());
+            }
+            Ok(SchemeValue::Boolean(matches!(args[0], SchemeValue::Boolean(false))))
+        }));
+
+        env.insert("eq?".to_string(), SchemeValue::Function(|args, _| {
+            if args.len() != 2 {
+                return Err("eq? requires exactly two arguments".to_string());
+            }
+            Ok(SchemeValue::Boolean(values_eqv(&args[0], &args[1])))
+        }));
+
+        env.insert("eqv?".to_string(), SchemeValue::Function(|args, _| {
+            if args.len() != 2 {
+                return Err("eqv? requires exactly two arguments".to_string());
+            }
+            Ok(SchemeValue::Boolean(values_eqv(&args[0], &args[1])))
+        }));
+
+        env.insert("equal?".to_string(), SchemeValue::Function(|args, _| {
+            if args.len() != 2 {
+                return Err("equal? requires exactly two arguments".to_string());
+            }
+            Ok(SchemeValue::Boolean(values_equal(&args[0], &args[1])))
+        }));
+
+        env.insert("symbol?".to_string(), SchemeValue::Function(|args, _| {
+            if args.len() != 1 {
+                return Err("symbol? requires exactly one argument".to_string());
+            }
+            Ok(SchemeValue::Boolean(matches!(args[0], SchemeValue::Symbol(_))))
+        }));
+
+        env.insert("number?".to_string(), SchemeValue::Function(|args, _| {
+            if args.len() != 1 {
+                return Err("number? requires exactly one argument".to_string());
+            }
+            Ok(SchemeValue::Boolean(matches!(args[0], SchemeValue::Number(_))))
+        }));
+
+        env.insert("string?".to_string(), SchemeValue::Function(|args, _| {
+            if args.len() != 1 {
+                return Err("string? requires exactly one argument".to_string());
+            }
+            Ok(SchemeValue::Boolean(matches!(args[0], SchemeValue::String(_))))
+        }));
+
+        env.insert("boolean?".to_string(), SchemeValue::Function(|args, _| {
+            if args.len() != 1 {
+                return Err("boolean? requires exactly one argument".to_string());
+            }
+            Ok(SchemeValue::Boolean(matches!(args[0], SchemeValue::Boolean(_))))
+        }));
+
+        env.insert("procedure?".to_string(), SchemeValue::Function(|args, _| {
+            if args.len() != 1 {
+                return Err("procedure? requires exactly one argument".to_string());
+            }
+            Ok(SchemeValue::Boolean(matches!(
+                args[0],
+                SchemeValue::Function(_)
+                    | SchemeValue::Native(_)
+                    | SchemeValue::Primitive(_)
+                    | SchemeValue::Lambda(_)
+                    | SchemeValue::Closure(_)
+                    | SchemeValue::Continuation(_)
+            )))
+        }));
+
+        env.insert("pair?".to_string(), SchemeValue::Function(|args, _| {
+            if args.len() != 1 {
+                return Err("pair? requires exactly one argument".to_string());
+            }
+            match &args[0] {
+                SchemeValue::List(list) => Ok(SchemeValue::Boolean(!list.is_empty())),
+                _ => Ok(SchemeValue::Boolean(false)),
+            }
+        }));
+
+        env.insert("nil".to_string(), SchemeValue::Nil);
+
+        // Macro support
+        for primitive in [
+            Primitive::MacroExpand,
+            Primitive::MacroExpand1,
+            Primitive::ExpandOptimize,
+            Primitive::ErMacroTransformer,
+            Primitive::Gensym,
+        ] {
+            env.insert(primitive.name().to_string(), SchemeValue::Primitive(primitive));
+        }
+
+        // Continuations
+        for primitive in [Primitive::CallCC, Primitive::CallEC, Primitive::DynamicWind] {
+            env.insert(primitive.name().to_string(), SchemeValue::Primitive(primitive));
+        }
+        env.insert("call/cc".to_string(), SchemeValue::Primitive(Primitive::CallCC));
+        env.insert("call/ec".to_string(), SchemeValue::Primitive(Primitive::CallEC));
+
+        // Multiple values. `receive`, `let-values`, `let*-values` and
+        // `define-values` are syntax built on call-with-values.
+        env.insert("values".to_string(), SchemeValue::Function(|args, _| {
+            Ok(multiple_values(args.to_vec()))
+        }));
+        env.insert("call-with-values".to_string(), SchemeValue::Primitive(Primitive::CallWithValues));
+
+        // Promises. `delay`, `delay-force` and `stream-cons` are syntax; the
+        // stream procedures are defined by the prelude.
+        env.insert("force".to_string(), SchemeValue::Primitive(Primitive::Force));
+
+        env.insert("make-promise".to_string(), SchemeValue::Function(|args, _| {
+            if args.len() != 1 {
+                return Err("make-promise requires exactly one argument".to_string());
+            }
+            match &args[0] {
+                SchemeValue::Promise(_) => Ok(args[0].clone()),
+                other => Ok(Promise::make(PromiseState::Done(other.clone()))),
+            }
+        }));
+
+        env.insert("promise?".to_string(), SchemeValue::Function(|args, _| {
+            if args.len() != 1 {
+                return Err("promise? requires exactly one argument".to_string());
+            }
+            Ok(SchemeValue::Boolean(matches!(args[0], SchemeValue::Promise(_))))
+        }));
+
+        // Exceptions. `guard` is syntax; the expander calls Primitive::Guard directly.
+        for primitive in [
+            Primitive::Raise,
+            Primitive::RaiseContinuable,
+            Primitive::WithExceptionHandler,
+            Primitive::Error,
+        ] {
+            env.insert(primitive.name().to_string(), SchemeValue::Primitive(primitive));
+        }
+
+        env.insert("error-object?".to_string(), SchemeValue::Function(|args, _| {
+            if args.len() != 1 {
+                return Err("error-object? requires exactly one argument".to_string());
+            }
+            Ok(SchemeValue::Boolean(matches!(args[0], SchemeValue::ErrorObject(_))))
+        }));
+
+        env.insert("error-object-message".to_string(), SchemeValue::Function(|args, _| {
+            if args.len() != 1 {
+                return Err("error-object-message requires exactly one argument".to_string());
+            }
+            match &args[0] {
+                SchemeValue::ErrorObject(e) => Ok(SchemeValue::String(e.message.clone())),
+                _ => Err("error-object-message requires an error object".to_string()),
+            }
+        }));
+
+        env.insert("error-object-irritants".to_string(), SchemeValue::Function(|args, _| {
+            if args.len() != 1 {
+                return Err("error-object-irritants requires exactly one argument".to_string());
+            }
+            match &args[0] {
+                SchemeValue::ErrorObject(e) if e.irritants.is_empty() => Ok(SchemeValue::Nil),
+                SchemeValue::ErrorObject(e) => Ok(SchemeValue::List(e.irritants.clone())),
+                _ => Err("error-object-irritants requires an error object".to_string()),
+            }
+        }));
+
+        // Strings
+        env.insert("string-length".to_string(), SchemeValue::Function(|args, _| match args {
+            [SchemeValue::String(s)] => Ok(SchemeValue::Number(s.chars().count() as f64)),
+            _ => Err("string-length requires a string".to_string()),
+        }));
+
+        env.insert("string-append".to_string(), SchemeValue::Function(|args, _| {
+            let mut result = String::new();
+            for arg in args {
+                result.push_str(string_arg("string-append", arg)?);
+            }
+            Ok(SchemeValue::String(result))
+        }));
+
+        env.insert("substring".to_string(), SchemeValue::Function(|args, _| {
+            let (s, start, end) = match args {
+                [s, SchemeValue::Number(start)] => (string_arg("substring", s)?, *start, None),
+                [s, SchemeValue::Number(start), SchemeValue::Number(end)] => (string_arg("substring", s)?, *start, Some(*end)),
+                _ => return Err("substring requires a string, a start and an optional end".to_string()),
+            };
+            let len = s.chars().count();
+            let end = end.map_or(len, |e| e as usize);
+            let start = start as usize;
+            if start > end || end > len {
+                return Err(format!("substring: range {}..{} is out of bounds for length {}", start, end, len));
+            }
+            Ok(SchemeValue::String(s.chars().skip(start).take(end - start).collect()))
+        }));
+
+        env.insert("string=?".to_string(), SchemeValue::Function(|args, _| {
+            let strings = args.iter().map(|a| string_arg("string=?", a)).collect::<Result<Vec<_>, _>>()?;
+            Ok(SchemeValue::Boolean(strings.windows(2).all(|w| w[0] == w[1])))
+        }));
+
+        env.insert("string<?".to_string(), SchemeValue::Function(|args, _| {
+            let strings = args.iter().map(|a| string_arg("string<?", a)).collect::<Result<Vec<_>, _>>()?;
+            Ok(SchemeValue::Boolean(strings.windows(2).all(|w| w[0] < w[1])))
+        }));
+
+        env.insert("string-upcase".to_string(), SchemeValue::Function(|args, _| match args {
+            [SchemeValue::String(s)] => Ok(SchemeValue::String(s.to_uppercase())),
+            _ => Err("string-upcase requires a string".to_string()),
+        }));
+
+        env.insert("string-downcase".to_string(), SchemeValue::Function(|args, _| match args {
+            [SchemeValue::String(s)] => Ok(SchemeValue::String(s.to_lowercase())),
+            _ => Err("string-downcase requires a string".to_string()),
+        }));
+
+        env.insert("string->number".to_string(), SchemeValue::Function(|args, _| match args {
+            [SchemeValue::String(s)] => Ok(match reader::parse_atom(s.trim()) {
+                number @ SchemeValue::Number(_) if !s.trim().is_empty() => number,
+                _ => SchemeValue::Boolean(false),
+            }),
+            _ => Err("string->number requires a string".to_string()),
+        }));
+
+        env.insert("number->string".to_string(), SchemeValue::Function(|args, _| match args {
+            [SchemeValue::Number(n)] => Ok(SchemeValue::String(n.to_string())),
+            _ => Err("number->string requires a number".to_string()),
+        }));
+
+        env.insert("string->symbol".to_string(), SchemeValue::Function(|args, _| match args {
+            [SchemeValue::String(s)] => Ok(SchemeValue::Symbol(s.clone())),
+            _ => Err("string->symbol requires a string".to_string()),
+        }));
+
+        env.insert("symbol->string".to_string(), SchemeValue::Function(|args, _| match args {
+            [SchemeValue::Symbol(s)] => Ok(SchemeValue::String(s.clone())),
+            _ => Err("symbol->string requires a symbol".to_string()),
+        }));
+
+        // Time. A jiffy is a microsecond.
+        env.insert("current-second".to_string(), SchemeValue::Function(|_args, _| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| e.to_string())?;
+            Ok(SchemeValue::Number(now.as_secs_f64()))
+        }));
+
+        env.insert("current-jiffy".to_string(), SchemeValue::Function(|_args, _| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| e.to_string())?;
+            Ok(SchemeValue::Number(now.as_micros() as f64))
+        }));
+
+        env.insert("jiffies-per-second".to_string(), SchemeValue::Function(|_args, _| {
+            Ok(SchemeValue::Number(1_000_000.0))
+        }));
+
+        // Logging
+        env.insert("log".to_string(), SchemeValue::Function(|args, _| {
+            let line: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+            eprintln!("{}", line.join(" "));
+            Ok(SchemeValue::Nil)
+        }));
+
+        // First-class environments
+        for primitive in [Primitive::Eval, Primitive::Environment, Primitive::InteractionEnvironment] {
+            env.insert(primitive.name().to_string(), SchemeValue::Primitive(primitive));
+        }
+
+        let global = Rc::new(RefCell::new(Environment { vars: env, parent: None }));
+        let mut interpreter = Self {
+            toplevel: RefCell::new(Environment::new_child(&global)),
+            global,
+            gensym_counter: Cell::new(0),
+            rename_scopes: RefCell::new(Vec::new()),
+            handlers: RefCell::new(Vec::new()),
+            fuel: Cell::new(None),
+            fuel_consumed: Cell::new(0),
+            deadline: Cell::new(None),
+            cancellation: RefCell::new(None),
+            limits,
+            depth: Cell::new(0),
+            allocated: Cell::new(0),
+            profiles,
+            backend,
+            optimizer,
+            rebound: Cell::new(false),
+            builtins: HashMap::new(),
+            heap: RefCell::new(gc::Heap::default()),
+            programs: Vec::new(),
+        };
+        interpreter.track_frame(interpreter.toplevel.borrow().clone());
+        interpreter.define_native("make-vector", 1..=2, |ctx, args| {
+            let len = usize::from_scheme(&args[0]).map_err(|e| format!("make-vector: {}", e))?;
+            ctx.interpreter().check_allocation(len, len.saturating_mul(size_of::<SchemeValue>()), "vector")?;
+            let fill = args.get(1).cloned().unwrap_or(SchemeValue::Number(0.0));
+            let mut items = Vec::new();
+            items.try_reserve_exact(len).map_err(|_| out_of_memory("vector", len))?;
+            items.resize(len, fill);
+            Ok(SchemeValue::Vector(items))
+        });
+        interpreter.define_native("make-string", 1..=2, |ctx, args| {
+            let len = usize::from_scheme(&args[0]).map_err(|e| format!("make-string: {}", e))?;
+            let fill = match args.get(1) {
+                None => ' ',
+                Some(SchemeValue::String(s)) if s.chars().count() == 1 => s.chars().next().unwrap(),
+                Some(_) => return Err("make-string: fill must be a one-character string".into()),
+            };
+            let bytes = len.saturating_mul(fill.len_utf8());
+            ctx.interpreter().check_allocation(bytes, bytes, "string")?;
+            let mut text = String::new();
+            text.try_reserve_exact(bytes).map_err(|_| out_of_memory("string", len))?;
+            text.extend(std::iter::repeat_n(fill, len));
+            Ok(SchemeValue::String(text))
+        });
+        interpreter.define_native("gc", 0, |ctx, _args| Ok(SchemeValue::Number(ctx.interpreter().collect_garbage() as f64)));
+        interpreter.define_native("gc-stats", 0, |ctx, _args| {
+            let stats = ctx.interpreter().gc_stats();
+            let entry = |name: &str, n: f64| SchemeValue::List(vec![SchemeValue::Symbol(name.to_string()), SchemeValue::Number(n)]);
+            Ok(SchemeValue::List(vec![
+                entry("collections", stats.collections as f64),
+                entry("live-objects", stats.live_objects as f64),
+                entry("heap-bytes", stats.heap_bytes as f64),
+                entry("freed", stats.freed as f64),
+            ]))
+        });
+        interpreter.define_weak_procedures();
+        interpreter.define_json_procedures();
+        interpreter.define_url_procedures();
+        interpreter.define_html_procedures();
+        interpreter.define_template_procedures();
+        interpreter.define_http_procedures();
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        let state = Cell::new(seed | 1);
+        interpreter.define_native("random", 0..=1, move |_ctx, args| {
+            // xorshift64*: not cryptographic, but cheap and good enough for sampling.
+            let mut x = state.get();
+            x ^= x >> 12;
+            x ^= x << 25;
+            x ^= x >> 27;
+            state.set(x);
+            let unit = (x.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64;
+            match args.first() {
+                None => Ok(SchemeValue::Number(unit)),
+                Some(SchemeValue::Number(n)) if *n > 0.0 && n.fract() == 0.0 => Ok(SchemeValue::Number((unit * n).floor())),
+                Some(SchemeValue::Number(n)) if *n > 0.0 => Ok(SchemeValue::Number(unit * n)),
+                Some(_) => Err("random requires a positive number".into()),
+            }
+        });
+
+        interpreter.classify_builtins();
+        let allowed = interpreter.profiles.clone();
+        interpreter.global.borrow_mut().vars.retain(|name, _| sandbox::builtin_profile(name).is_some_and(|p| allowed.contains(&p)));
+        interpreter.builtins.retain(|_, profile| allowed.contains(profile));
+        interpreter
     }
 
-    pub fn eval(&self, expr: &str) -> Result<SchemeValue, String> {
-        // Simple parser and evaluator
-        let expr = expr.trim();
-        
-        // Handle empty expressions
-        if expr.is_empty() {
-            return Err("Empty expression".to_string());
+    /// Records the profile of everything in the builtins frame, refusing a
+    /// builtin that `sandbox::builtin_profile` does not list.
+    fn classify_builtins(&mut self) {
+        let global = self.global.borrow();
+        let mut unlisted: Vec<_> = global.vars.keys().filter(|name| sandbox::builtin_profile(name).is_none()).collect();
+        unlisted.sort();
+        assert!(unlisted.is_empty(), "builtins without a profile in sandbox.rs: {:?}", unlisted);
+        self.builtins = (global.vars.keys()).filter_map(|name| Some((name.clone(), sandbox::builtin_profile(name)?))).collect();
+    }
+
+    /// Evaluates every form in `expr` and returns the value of the last one.
+    /// Definitions persist in the interpreter until `reset`.
+    pub fn eval(&self, expr: &str) -> Result<SchemeValue, SchemeError> {
+        let forms = reader::read_program(expr)?;
+        if forms.is_empty() {
+            return Err("Empty expression".into());
         }
-        
-        // Handle nil/empty list tokens first
-        if expr == "nil" || expr == "()" {
-            return Ok(SchemeValue::Nil);
+        self.start_run()?;
+        let env = self.toplevel.borrow().clone();
+        for form in &forms {
+            self.note_assignments(&form.datum);
         }
-        
-        // Handle function calls (expressions starting with '(')
-        if expr.starts_with('(') && expr.ends_with(')') {
-            let inner = &expr[1..expr.len()-1]; // Remove outer parentheses
-            let tokens = self.tokenize(inner)?;
-            
-            if tokens.is_empty() {
-                return Err("Empty function call".to_string());
-            }
-            
-            let func_name = &tokens[0];
-            
-            // Check if the function name is a boolean literal
-            if func_name == "#t" {
-                return Ok(SchemeValue::Boolean(true));
-            }
-            if func_name == "#f" {
-                return Ok(SchemeValue::Boolean(false));
-            }
-            
-            let args: Vec<SchemeValue> = tokens[1..]
-                .iter()
-                .map(|token| self.eval(token))
-                .collect::<Result<Vec<_>, _>>()?;
-            
-            if let Some(SchemeValue::Function(func)) = self.env.get(func_name) {
-                return func(&args, &mut HashMap::new());
-            } else {
-                return Err(format!("Unknown function: {}", func_name));
+        let mut result = SchemeValue::Nil;
+        for form in &forms {
+            result = self.eval_toplevel(&form.datum, &env)?;
+        }
+        Ok(result)
+    }
+
+    pub fn run_program(&self, program: &str) -> Result<String, SchemeError> {
+        let forms = reader::read_program(program)?;
+        let env = self.toplevel.borrow().clone();
+        self.start_run()?;
+        let mut output = String::new();
+        for form in &forms {
+            self.note_assignments(&form.datum);
+        }
+
+        for form in &forms {
+            // Debug: Print the form being processed
+            output.push_str(&format!("Processing line {}: '{}'\n", form.line, form.source));
+            report(&mut output, form.line, self.eval_toplevel(&form.datum, &env))?;
+        }
+
+        Ok(output)
+    }
+
+    /// An interpreter like `new` that can also run the programs in an
+    /// image built by `image::compile`. The prelude comes from the image's
+    /// snapshot, so nothing is read or expanded.
+    pub fn from_image(image: &[u8]) -> Result<Self, SchemeError> {
+        let image = image::decode(image)?;
+        let mut interpreter = Self::restore(image.prelude)?;
+        interpreter.gensym_counter.set(interpreter.gensym_counter.get().max(image.gensyms));
+        interpreter.programs = image.programs;
+        Ok(interpreter)
+    }
+
+    /// Runs a program from the interpreter's image, with the same output
+    /// as `run_program` on its source.
+    pub fn run_image_program(&self, name: &str) -> Result<String, SchemeError> {
+        let program = (self.programs.iter().find(|program| program.name == name).cloned())
+            .ok_or_else(|| format!("No program named {} in the image", name))?;
+        let env = self.toplevel.borrow().clone();
+        self.start_run()?;
+        let mut output = String::new();
+
+        for form in &program.forms {
+            output.push_str(&format!("Processing line {}: '{}'\n", form.line, form.source));
+            let result = match &form.body {
+                image::Body::Compiled(code) => self.run_code(code.clone(), &env),
+                image::Body::Source(datum) => self.eval_toplevel(datum, &env),
+            };
+            report(&mut output, form.line, result)?;
+        }
+
+        Ok(output)
+    }
+
+    pub fn display_value(&self, value: &SchemeValue) -> String {
+        value.to_string()
+    }
+
+    /// Registers a Rust closure as a builtin procedure. The closure may
+    /// capture host state; `arity` is checked before it is called.
+    pub fn define_native<F>(&self, name: &str, arity: impl Into<Arity>, func: F)
+    where
+        F: Fn(&mut Ctx, &[SchemeValue]) -> Result<SchemeValue, SchemeError> + 'static,
+    {
+        let native = Native { name: name.to_string(), arity: arity.into(), func: Box::new(func) };
+        self.global.borrow_mut().define(name, SchemeValue::Native(Rc::new(native)));
+        self.note_rebound(name);
+    }
+
+    /// Looks up a top-level binding, including the builtins.
+    pub fn get_global(&self, name: &str) -> Option<SchemeValue> {
+        self.toplevel.borrow().borrow().get(name)
+    }
+
+    /// Defines or replaces a top-level binding, as `define` would.
+    pub fn set_global(&self, name: &str, value: SchemeValue) {
+        self.toplevel.borrow().borrow_mut().define(name, value);
+        self.note_rebound(name);
+    }
+
+    /// Calls the procedure bound to `name` with already evaluated arguments.
+    pub fn call(&self, name: &str, args: Vec<SchemeValue>) -> Result<SchemeValue, SchemeError> {
+        let func = self.get_global(name).ok_or_else(|| format!("Unbound variable: {}", name))?;
+        self.start_run()?;
+        self.apply(&func, args)
+    }
+
+    /// Limits how many more evaluation steps and procedure calls may run
+    /// before evaluation fails with `SchemeError::ResourceLimit`. `None`
+    /// removes the limit. Also restarts the `fuel_consumed` count.
+    pub fn set_fuel(&self, fuel: Option<u64>) {
+        self.fuel.set(fuel);
+        self.fuel_consumed.set(0);
+    }
+
+    /// The fuel left, or `None` if evaluation is unlimited.
+    pub fn fuel_remaining(&self) -> Option<u64> {
+        self.fuel.get()
+    }
+
+    /// Steps and calls made since the last `set_fuel`, counted whether or
+    /// not a limit is set.
+    pub fn fuel_consumed(&self) -> u64 {
+        self.fuel_consumed.get()
+    }
+
+    /// Whether the interpreter was built with `profile`. Hosts check this
+    /// before registering `Fetch` procedures.
+    pub fn allows(&self, profile: Profile) -> bool {
+        self.profiles.contains(&profile)
+    }
+
+    pub fn limits(&self) -> &InterpreterLimits {
+        &self.limits
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Approximate bytes allocated by the last `eval`, `run_program` or `call`.
+    pub fn allocated_bytes(&self) -> usize {
+        self.allocated.get()
+    }
+
+    /// Fails if building a `kind` ("list", "vector", "string" or "hash
+    /// table") of `len` elements and `bytes` bytes would go over a limit.
+    /// Used before allocating in proportion to an argument; the result is
+    /// charged when the builtin returns.
+    pub(crate) fn check_allocation(&self, len: usize, bytes: usize, kind: &str) -> Result<(), SchemeError> {
+        let max_len = match kind {
+            "string" => self.limits.max_string_length,
+            "hash table" => self.limits.max_hash_table_size,
+            _ => self.limits.max_list_length,
+        };
+        if let Some(max) = max_len.filter(|&max| len > max) {
+            return Err(SchemeError::MemoryLimit(format!("{} of length {} is over the limit of {}", kind, len, max)));
+        }
+        if let Some(max) = self.limits.max_allocated_bytes {
+            if self.allocated.get().saturating_add(bytes) > max {
+                return Err(SchemeError::MemoryLimit(format!("more than {} bytes allocated", max)));
             }
         }
-        
-        // Handle simple cases (single tokens)
-        // Check if it's a number
-        if let Ok(num) = expr.parse::<f64>() {
-            return Ok(SchemeValue::Number(num));
+        Ok(())
+    }
+
+    /// Charges a value returned by a builtin against the allocation limits.
+    /// Only the outermost container is counted; its elements were charged
+    /// when they were built.
+    fn charge(&self, value: &SchemeValue) -> Result<(), SchemeError> {
+        let (len, bytes, kind) = match value {
+            SchemeValue::List(items) => (items.len(), items.len() * size_of::<SchemeValue>(), "list"),
+            SchemeValue::Vector(items) => (items.len(), items.len() * size_of::<SchemeValue>(), "vector"),
+            SchemeValue::String(s) => (s.len(), s.len(), "string"),
+            SchemeValue::HashTable(table) => (table.len(), table.len() * size_of::<(String, SchemeValue)>(), "hash table"),
+            _ => return Ok(()),
+        };
+        self.check_allocation(len, bytes, kind)?;
+        self.allocated.set(self.allocated.get() + bytes);
+        Ok(())
+    }
+
+    /// Stops evaluation with `SchemeError::Interrupted` once `deadline` has
+    /// passed. The clock is checked every few hundred steps, so evaluation
+    /// may overrun the deadline by a small amount. `None` removes it.
+    pub fn set_deadline(&self, deadline: Option<Instant>) {
+        self.deadline.set(deadline);
+    }
+
+    /// Stops evaluation with `SchemeError::Interrupted` once `token` is
+    /// cancelled. Checked as often as the deadline.
+    pub fn set_cancellation_token(&self, token: Option<CancellationToken>) {
+        *self.cancellation.borrow_mut() = token;
+    }
+
+    /// Resets the per-run counters at the start of `eval`, `run_program` and
+    /// `call`, and refuses to start once interrupted.
+    fn start_run(&self) -> Result<(), SchemeError> {
+        self.allocated.set(0);
+        self.check_interrupt()
+    }
+
+    fn check_interrupt(&self) -> Result<(), SchemeError> {
+        if self.deadline.get().is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(SchemeError::Interrupted("deadline exceeded".to_string()));
         }
-        
-        // Check if it's a string
-        if expr.starts_with('"') && expr.ends_with('"') {
-            return Ok(SchemeValue::String(expr[1..expr.len()-1].to_string()));
+        if self.cancellation.borrow().as_ref().is_some_and(CancellationToken::is_cancelled) {
+            return Err(SchemeError::Interrupted("cancelled by the host".to_string()));
         }
-        
-        // Check if it's a boolean
-        if expr == "#t" {
-            return Ok(SchemeValue::Boolean(true));
+        Ok(())
+    }
+
+    /// Charges one unit of fuel.
+    fn tick(&self) -> Result<(), SchemeError> {
+        if let Some(fuel) = self.fuel.get() {
+            if fuel == 0 {
+                return Err(SchemeError::ResourceLimit(format!(
+                    "out of fuel after {} steps",
+                    self.fuel_consumed.get()
+                )));
+            }
+            self.fuel.set(Some(fuel - 1));
         }
-        if expr == "#f" {
-            return Ok(SchemeValue::Boolean(false));
+        let consumed = self.fuel_consumed.get() + 1;
+        self.fuel_consumed.set(consumed);
+        if consumed.is_multiple_of(INTERRUPT_CHECK_INTERVAL) {
+            self.check_interrupt()?;
         }
-        
-        // Check if it's a variable/symbol
-        if let Some(value) = self.env.get(expr) {
-            return Ok(value.clone());
+        Ok(())
+    }
+
+    /// Registers a new object with the cycle collector, collecting first if
+    /// enough objects have been created since the last time.
+    pub(crate) fn track(&self, object: gc::Tracked) {
+        if self.heap.borrow_mut().track(object) {
+            self.collect_garbage();
         }
-        
-        // If not found, treat as a symbol
-        Ok(SchemeValue::Symbol(expr.to_string()))
-    }
-    
-    fn tokenize(&self, input: &str) -> Result<Vec<String>, String> {
-        let mut tokens = Vec::new();
-        let mut current = String::new();
-        let mut paren_count;
-        let mut in_string = false;
-        let mut chars = input.chars().peekable();
-        
-        while let Some(ch) = chars.next() {
-            match ch {
-                '"' => {
-                    if in_string {
-                        // End of string
-                        current.push(ch);
-                        tokens.push(current.clone());
-                        current.clear();
-                        in_string = false;
-                    } else {
-                        // Start of string
-                        if !current.is_empty() {
-                            tokens.push(current.clone());
-                            current.clear();
+    }
+
+    fn track_frame(&self, frame: Env) -> Env {
+        self.track(gc::Tracked::Frame(Rc::downgrade(&frame)));
+        frame
+    }
+
+    /// Frees frames and closures kept alive only by reference cycles, such
+    /// as a finished named `let`, and clears weak table entries and
+    /// ephemerons whose keys are unreachable. Returns how many objects were
+    /// freed. This also runs on its own as the number of objects grows.
+    pub fn collect_garbage(&self) -> usize {
+        let garbage = self.heap.borrow_mut().collect();
+        drop(garbage);
+        self.heap.borrow_mut().finish_collection()
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.borrow().stats()
+    }
+
+    /// Forgets every definition made by scripts and `set_global`. The
+    /// builtins, the prelude and procedures registered with `define_native`
+    /// or `register_fn` stay.
+    pub fn reset(&self) {
+        *self.toplevel.borrow_mut() = self.track_frame(Environment::new_child(&self.global));
+        self.handlers.borrow_mut().clear();
+    }
+
+    /// Expands and optimizes a top-level form and evaluates the result. Expansion and
+    /// evaluation are interleaved form by form, so a macro can use any
+    /// procedure defined by an earlier form.
+    fn eval_toplevel(&self, datum: &SchemeValue, env: &Env) -> Result<SchemeValue, SchemeError> {
+        let expanded = self.expand(datum, env)?;
+        self.eval_expanded(&self.optimize(expanded, env), env)
+    }
+
+    /// Evaluates an expanded form with the interpreter's backend.
+    pub(crate) fn eval_expanded(&self, expanded: &SchemeValue, env: &Env) -> Result<SchemeValue, SchemeError> {
+        match self.backend {
+            Backend::Bytecode => self.run_compiled(expanded, env),
+            Backend::TreeWalker => self.eval_in(expanded, env),
+        }
+    }
+
+    pub(crate) fn check_depth(&self, depth: usize) -> Result<(), SchemeError> {
+        match self.limits.max_depth.filter(|&max| depth >= max) {
+            Some(max) => Err(SchemeError::MemoryLimit(format!("recursion deeper than {} levels", max))),
+            None => Ok(()),
+        }
+    }
+
+    /// Evaluates fully expanded code, passing any error raised along the way
+    /// to the installed exception handlers.
+    fn eval_in(&self, expr: &SchemeValue, env: &Env) -> Result<SchemeValue, SchemeError> {
+        let depth = self.depth.get();
+        self.check_depth(depth)?;
+        self.depth.set(depth + 1);
+        let result = self.eval_core(expr, env).map_err(|e| self.signal(e));
+        self.depth.set(depth);
+        result
+    }
+
+    /// Only the core forms `quote`, `if`, `define`, `set!`, `lambda` and
+    /// `begin` reach this point; everything else has been rewritten by the
+    /// expander. Tail positions loop instead of recursing so iterative
+    /// procedures run in constant stack.
+    fn eval_core(&self, expr: &SchemeValue, env: &Env) -> Result<SchemeValue, SchemeError> {
+        let mut env = env.clone();
+        // Tail hops into a lambda body borrow from `held`, so the body is never cloned.
+        let mut held: Rc<Lambda>;
+        let mut expr = expr;
+        loop {
+            self.tick()?;
+            let items = match expr {
+                SchemeValue::Symbol(name) => {
+                    return env
+                        .borrow()
+                        .get(name)
+                        .ok_or_else(|| format!("Unbound variable: {}", name).into());
+                }
+                SchemeValue::List(items) if !items.is_empty() => items,
+                SchemeValue::List(_) => return Err("Empty function call".to_string().into()),
+                other => return Ok(other.clone()),
+            };
+
+            if let SchemeValue::Symbol(head) = &items[0] {
+                match head.as_str() {
+                    "if" => {
+                        if items.len() != 3 && items.len() != 4 {
+                            return Err("if requires two or three arguments".to_string().into());
                         }
-                        current.push(ch);
-                        in_string = true;
+                        let test = self.eval_in(&items[1], &env)?;
+                        expr = if is_true(&test) {
+                            &items[2]
+                        } else if items.len() == 4 {
+                            &items[3]
+                        } else {
+                            return Ok(SchemeValue::Nil);
+                        };
+                        continue;
                     }
-                }
-                '(' => {
-                    if in_string {
-                        current.push(ch);
-                    } else {
-                        if !current.is_empty() {
-                            tokens.push(current.clone());
-                            current.clear();
+                    "quote" | "define" | "set!" | "lambda" => return self.eval_special(head, items, &env),
+                    "begin" => {
+                        if items.len() == 1 {
+                            return Ok(SchemeValue::Nil);
                         }
-                        current.push(ch);
-                        paren_count = 1;
-                        
-                        // Collect the entire parenthesized expression
-                        while paren_count > 0 {
-                            match chars.next() {
-                                Some('(') => {
-                                    paren_count += 1;
-                                    current.push('(');
-                                }
-                                Some(')') => {
-                                    paren_count -= 1;
-                                    current.push(')');
-                                }
-                                Some(c) => current.push(c),
-                                None => return Err("Unmatched parentheses".to_string()),
-                            }
+                        for form in &items[1..items.len() - 1] {
+                            self.eval_in(form, &env)?;
                         }
-                        tokens.push(current.clone());
-                        current.clear();
+                        expr = &items[items.len() - 1];
+                        continue;
                     }
+                    _ => {}
                 }
-                ' ' | '\t' | '\n' => {
-                    if in_string {
-                        current.push(ch);
-                    } else if !current.is_empty() {
-                        tokens.push(current.clone());
-                        current.clear();
+            }
+
+            let func = self.eval_in(&items[0], &env)?;
+            let args = self.eval_args(&items[1..], &env)?;
+
+            match func {
+                SchemeValue::Lambda(lambda) => {
+                    env = self.track_frame(bind_arguments(&lambda, args)?);
+                    for form in &lambda.body[..lambda.body.len() - 1] {
+                        self.eval_in(form, &env)?;
                     }
+                    held = lambda;
+                    expr = &held.body[held.body.len() - 1];
+                }
+                other => return self.apply(&other, args),
+            }
+        }
+    }
+
+    /// The core forms that never continue in tail position. Kept out of
+    /// `eval_core` so its stack frame, paid once per nesting level, stays small.
+    fn eval_special(&self, head: &str, items: &[SchemeValue], env: &Env) -> Result<SchemeValue, SchemeError> {
+        match head {
+            "quote" => {
+                if items.len() != 2 {
+                    return Err("quote requires exactly one argument".to_string().into());
+                }
+                Ok(items[1].clone())
+            }
+            "define" => {
+                let name = match items.get(1) {
+                    Some(SchemeValue::Symbol(name)) if items.len() == 3 => name,
+                    _ => return Err("define requires a name and a value".to_string().into()),
+                };
+                let value = self.eval_in(&items[2], env)?;
+                env.borrow_mut().define(name, value);
+                Ok(SchemeValue::Symbol(name.clone()))
+            }
+            "set!" => {
+                let name = match items.get(1) {
+                    Some(SchemeValue::Symbol(name)) if items.len() == 3 => name,
+                    _ => return Err("set! requires a name and a value".to_string().into()),
+                };
+                let value = self.eval_in(&items[2], env)?;
+                if !env.borrow_mut().set(name, value.clone()) {
+                    return Err(format!("Unbound variable: {}", name).into());
                 }
-                _ => {
-                    current.push(ch);
+                Ok(value)
+            }
+            "lambda" => {
+                if items.len() < 3 {
+                    return Err("lambda requires parameters and a body".to_string().into());
                 }
+                let (params, rest) = parse_params(&items[1])?;
+                let lambda = Rc::new(Lambda { params, rest, body: items[2..].to_vec(), env: env.clone() });
+                self.track(gc::Tracked::Closure(Rc::downgrade(&lambda)));
+                Ok(SchemeValue::Lambda(lambda))
             }
+            _ => unreachable!("not a special form: {}", head),
         }
-        
-        if !current.is_empty() {
-            tokens.push(current);
+    }
+
+    fn eval_args(&self, args: &[SchemeValue], env: &Env) -> Result<Vec<SchemeValue>, SchemeError> {
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(self.eval_in(arg, env)?);
         }
-        
-        Ok(tokens)
+        Ok(values)
     }
-    
-    pub fn run_program(&self, program: &str) -> Result<String, String> {
-        let lines: Vec<&str> = program.lines().collect();
-        let mut output = String::new();
-        
-        for (i, line) in lines.iter().enumerate() {
-            let line = line.trim();
-            if !line.is_empty() && !line.starts_with(';') {
-                // Debug: Print the line being processed
-                output.push_str(&format!("Processing line {}: '{}'\n", i, line));
-                
-                match self.eval(line) {
-                    Ok(result) => {
-                        match result {
-                            SchemeValue::String(s) => output.push_str(&s),
-                            SchemeValue::Number(n) => output.push_str(&n.to_string()),
-                            SchemeValue::Boolean(b) => output.push_str(&b.to_string()),
-                            _ => output.push_str("result"),
+
+    /// Calls a procedure value with already evaluated arguments.
+    pub(crate) fn apply(&self, func: &SchemeValue, args: Vec<SchemeValue>) -> Result<SchemeValue, SchemeError> {
+        self.tick()?;
+        match func {
+            SchemeValue::Function(f) => {
+                let result = f(&args, &mut HashMap::new()).map_err(|e| self.signal(e.into()))?;
+                self.charge(&result)?;
+                Ok(result)
+            }
+            SchemeValue::Native(native) => {
+                if !native.arity.accepts(args.len()) {
+                    let message = format!("{} expects {}, got {}", native.name, native.arity, args.len());
+                    return Err(self.signal(message.into()));
+                }
+                let result = (native.func)(&mut Ctx { interpreter: self }, &args).map_err(|e| self.signal(e))?;
+                self.charge(&result)?;
+                Ok(result)
+            }
+            SchemeValue::Primitive(p) => self.call_primitive(*p, args),
+            SchemeValue::Closure(closure) => self.apply_closure(closure, args),
+            SchemeValue::Lambda(lambda) => {
+                let env = self.track_frame(bind_arguments(lambda, args)?);
+                let mut result = SchemeValue::Nil;
+                for form in &lambda.body {
+                    result = self.eval_in(form, &env)?;
+                }
+                Ok(result)
+            }
+            SchemeValue::Continuation(k) => {
+                if !k.active.get() {
+                    return Err("Continuation invoked after its call/cc returned; re-entrant continuations are not supported"
+                        .to_string()
+                        .into());
+                }
+                Err(SchemeError::Escape { target: k.clone(), value: multiple_values(args) })
+            }
+            SchemeValue::Macro(_) => Err("Macro used as a procedure".to_string().into()),
+            other => Err(format!("Not a procedure: {}", self.display_value(other)).into()),
+        }
+    }
+
+    fn call_primitive(&self, primitive: Primitive, args: Vec<SchemeValue>) -> Result<SchemeValue, SchemeError> {
+        let name = primitive.name();
+        match primitive {
+            Primitive::MacroExpand | Primitive::MacroExpand1 => {
+                if args.len() != 1 {
+                    return Err(format!("{} requires exactly one argument", name).into());
+                }
+                let env = self.toplevel.borrow().clone();
+                let mut form = args[0].clone();
+                while let Some(expanded) = self.expand_once(&form, &env)? {
+                    form = expanded;
+                    if primitive == Primitive::MacroExpand1 {
+                        break;
+                    }
+                }
+                Ok(form)
+            }
+            Primitive::ExpandOptimize => {
+                if args.len() != 1 {
+                    return Err(format!("{} requires exactly one argument", name).into());
+                }
+                let env = self.toplevel.borrow().clone();
+                let expanded = self.expand(&args[0], &env)?;
+                Ok(self.optimized(expanded, &env))
+            }
+            Primitive::BuiltinsUnchanged => Ok(SchemeValue::Boolean(!self.rebound.get())),
+            Primitive::ErMacroTransformer => {
+                if args.len() != 1 {
+                    return Err(format!("{} requires exactly one argument", name).into());
+                }
+                Ok(SchemeValue::Macro(Rc::new(Macro {
+                    kind: MacroKind::ExplicitRenaming,
+                    transformer: args[0].clone(),
+                })))
+            }
+            Primitive::Rename => match args.as_slice() {
+                [SchemeValue::Symbol(sym)] => Ok(SchemeValue::Symbol(self.rename(sym))),
+                _ => Err("rename requires a symbol".to_string().into()),
+            },
+            Primitive::Compare => match args.as_slice() {
+                [SchemeValue::Symbol(a), SchemeValue::Symbol(b)] => {
+                    Ok(SchemeValue::Boolean(expand::strip_alias(a) == expand::strip_alias(b)))
+                }
+                [a, b] => Ok(SchemeValue::Boolean(values_eqv(a, b))),
+                _ => Err("compare requires exactly two arguments".to_string().into()),
+            },
+            Primitive::Gensym => {
+                let prefix = match args.as_slice() {
+                    [] => "g".to_string(),
+                    [SchemeValue::Symbol(s)] | [SchemeValue::String(s)] => s.clone(),
+                    _ => return Err("gensym accepts an optional symbol or string prefix".to_string().into()),
+                };
+                Ok(SchemeValue::Symbol(self.gensym(&prefix)))
+            }
+            Primitive::CallCC | Primitive::CallEC => {
+                if args.len() != 1 {
+                    return Err(format!("{} requires exactly one argument", name).into());
+                }
+                let k = Rc::new(Continuation { active: Cell::new(true) });
+                let result = self.apply(&args[0], vec![SchemeValue::Continuation(k.clone())]);
+                k.active.set(false);
+                match result {
+                    Err(SchemeError::Escape { target, value }) if Rc::ptr_eq(&target, &k) => Ok(value),
+                    other => other,
+                }
+            }
+            Primitive::Raise | Primitive::RaiseContinuable => {
+                if args.len() != 1 {
+                    return Err(format!("{} requires exactly one argument", name).into());
+                }
+                let obj = args.into_iter().next().unwrap();
+                self.raise(obj, primitive == Primitive::RaiseContinuable)
+            }
+            Primitive::Error => {
+                let mut args = args.into_iter();
+                let message = match args.next() {
+                    Some(SchemeValue::String(s)) => s,
+                    Some(other) => other.to_string(),
+                    None => return Err("error requires a message".to_string().into()),
+                };
+                self.raise(SchemeValue::error_object(message, args.collect()), false)
+            }
+            Primitive::WithExceptionHandler => {
+                if args.len() != 2 {
+                    return Err(format!("{} requires exactly two arguments", name).into());
+                }
+                // `raise` may leave the handler uninstalled, so truncate rather than pop.
+                let installed = self.handlers.borrow().len();
+                self.handlers.borrow_mut().push(Handler::Procedure(args[0].clone()));
+                let result = self.apply(&args[1], Vec::new());
+                self.handlers.borrow_mut().truncate(installed);
+                result
+            }
+            Primitive::Guard => {
+                // (guard-thunk, handler): the handler is the guard's clauses
+                // wrapped in a procedure that re-raises if none match.
+                if args.len() != 2 {
+                    return Err(format!("{} requires exactly two arguments", name).into());
+                }
+                self.handlers.borrow_mut().push(Handler::Guard);
+                let result = self.apply(&args[0], Vec::new());
+                self.handlers.borrow_mut().pop();
+                match result {
+                    Err(SchemeError::Raise(obj)) => self.apply(&args[1], vec![obj]),
+                    Err(SchemeError::Error(message) | SchemeError::MemoryLimit(message)) => {
+                        self.apply(&args[1], vec![SchemeValue::error_object(message, Vec::new())])
+                    }
+                    other => other,
+                }
+            }
+            Primitive::CallWithValues => {
+                if args.len() != 2 {
+                    return Err(format!("{} requires exactly two arguments", name).into());
+                }
+                let produced = match self.apply(&args[0], Vec::new())? {
+                    SchemeValue::Values(values) => values,
+                    single => vec![single],
+                };
+                self.apply(&args[1], produced)
+            }
+            Primitive::Delay | Primitive::DelayForce => {
+                if args.len() != 1 {
+                    return Err(format!("{} requires exactly one argument", name).into());
+                }
+                let thunk = args.into_iter().next().unwrap();
+                Ok(Promise::make(if primitive == Primitive::Delay {
+                    PromiseState::Delay(thunk)
+                } else {
+                    PromiseState::DelayForce(thunk)
+                }))
+            }
+            Primitive::Eval => {
+                let env = match args.as_slice() {
+                    [_, SchemeValue::Environment(env)] => env.clone(),
+                    _ => return Err(format!("{} requires an expression and an environment", name).into()),
+                };
+                self.eval_toplevel(&args[0], &env)
+            }
+            Primitive::Environment => {
+                let mut requested = vec![Profile::Pure];
+                for arg in &args {
+                    let profile = match arg {
+                        SchemeValue::Symbol(s) => {
+                            Profile::from_name(s).ok_or_else(|| format!("{}: unknown profile {}", name, s))?
                         }
-                        output.push('\n');
+                        other => return Err(format!("{}: expected a profile name, got {}", name, other).into()),
+                    };
+                    if !self.allows(profile) {
+                        return Err(format!("{}: profile {} is not available", name, profile.name()).into());
+                    }
+                    requested.push(profile);
+                }
+                // Code evaluated in the result must not be able to reach a
+                // wider environment, so these two are left out.
+                let global = self.global.borrow();
+                let vars = (self.builtins.iter())
+                    .filter(|(name, _)| *name != "environment" && *name != "interaction-environment")
+                    .filter(|(_, profile)| requested.contains(profile))
+                    .filter_map(|(name, _)| Some((name.clone(), global.vars.get(name)?.clone())))
+                    .collect();
+                let frame = Rc::new(RefCell::new(Environment { vars, parent: None }));
+                Ok(SchemeValue::Environment(self.track_frame(frame)))
+            }
+            Primitive::InteractionEnvironment => {
+                if !args.is_empty() {
+                    return Err(format!("{} takes no arguments", name).into());
+                }
+                Ok(SchemeValue::Environment(self.toplevel.borrow().clone()))
+            }
+            Primitive::Force => match args.as_slice() {
+                [SchemeValue::Promise(promise)] => self.force(promise),
+                [other] => Ok(other.clone()),
+                _ => Err(format!("{} requires exactly one argument", name).into()),
+            },
+            Primitive::DynamicWind => {
+                if args.len() != 3 {
+                    return Err(format!("{} requires exactly three arguments", name).into());
+                }
+                self.apply(&args[0], Vec::new())?;
+                // Runs on normal return, on errors and on continuation escapes
+                // alike, since all of them come back through here.
+                let result = self.apply(&args[1], Vec::new());
+                self.apply(&args[2], Vec::new())?;
+                result
+            }
+        }
+    }
+
+    /// Forces a promise iteratively, as in the R7RS reference implementation.
+    /// Each `delay-force` step moves the next promise's state into this
+    /// promise's box and makes the next promise share the box, then loops.
+    fn force(&self, promise: &Rc<Promise>) -> Result<SchemeValue, SchemeError> {
+        loop {
+            let state = promise.state.borrow().borrow().clone();
+            match state {
+                PromiseState::Done(value) => return Ok(value),
+                PromiseState::Delay(thunk) => {
+                    let value = self.apply(&thunk, Vec::new())?;
+                    // Forcing the thunk may have forced this promise re-entrantly;
+                    // the first value computed wins.
+                    if !promise.is_done() {
+                        *promise.state.borrow().borrow_mut() = PromiseState::Done(value);
                     }
-                    Err(e) => {
-                        output.push_str(&format!("Error on line {}: {}\n", i, e));
-                        return Err(e);
+                }
+                PromiseState::DelayForce(thunk) => {
+                    let next = match self.apply(&thunk, Vec::new())? {
+                        SchemeValue::Promise(next) => next,
+                        other => return Err(format!("delay-force: expression returned {}, not a promise", other).into()),
+                    };
+                    if !promise.is_done() {
+                        let next_state = next.state.borrow().borrow().clone();
+                        let shared = promise.state.borrow().clone();
+                        *shared.borrow_mut() = next_state;
+                        *next.state.borrow_mut() = shared;
                     }
                 }
             }
         }
-        
-        Ok(output)
     }
 
-    pub fn display_value(&self, value: &SchemeValue) -> String {
-        match value {
-            SchemeValue::String(s) => s.clone(),
-            SchemeValue::Number(n) => n.to_string(),
-            SchemeValue::Boolean(b) => b.to_string(),
-            SchemeValue::List(list) => {
-                let mut result = String::from("[");
-                for (i, item) in list.iter().enumerate() {
-                    if i > 0 { result.push_str(", "); }
-                    result.push_str(&self.display_value(item));
+    /// Hands `obj` to the innermost exception handler. A procedure handler is
+    /// called in place with itself uninstalled, so a raise inside it goes to
+    /// the next handler out. A `guard` (or no handler at all) is reached by
+    /// unwinding.
+    ///
+    /// The handler is put back only if it returns to a continuable raise.
+    /// Otherwise it stays uninstalled while the error unwinds to its
+    /// `with-exception-handler`, so that error is not handed to it again.
+    fn raise(&self, obj: SchemeValue, continuable: bool) -> Result<SchemeValue, SchemeError> {
+        let handler = match self.handlers.borrow().last() {
+            Some(Handler::Procedure(handler)) => handler.clone(),
+            Some(Handler::Guard) | None => return Err(SchemeError::Raise(obj)),
+        };
+        let installed = self.handlers.borrow_mut().pop();
+        match self.apply(&handler, vec![obj.clone()]) {
+            Ok(value) if continuable => {
+                self.handlers.borrow_mut().extend(installed);
+                Ok(value)
+            }
+            Ok(_) => self.raise(
+                SchemeValue::error_object("exception handler returned from non-continuable raise".to_string(), vec![obj]),
+                false,
+            ),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Turns a builtin's error message into a raised error object when a
+    /// procedure handler wants to see it. Guards convert messages themselves
+    /// when they catch them, so nothing is allocated on the common path.
+    fn signal(&self, err: SchemeError) -> SchemeError {
+        match err {
+            SchemeError::Error(message) | SchemeError::MemoryLimit(message)
+                if matches!(self.handlers.borrow().last(), Some(Handler::Procedure(_))) =>
+            {
+                match self.raise(SchemeValue::error_object(message, Vec::new()), false) {
+                    Err(e) => e,
+                    Ok(_) => unreachable!("non-continuable raise returned"),
                 }
-                result.push(']');
-                result
             }
-            SchemeValue::Vector(vec) => {
-                let mut result = String::from("#(");
-                for (i, item) in vec.iter().enumerate() {
-                    if i > 0 { result.push_str(" "); }
-                    result.push_str(&self.display_value(item));
+            other => other,
+        }
+    }
+
+    /// Returns a symbol that cannot be written in source code.
+    pub(crate) fn gensym(&self, prefix: &str) -> String {
+        let n = self.gensym_counter.get() + 1;
+        self.gensym_counter.set(n);
+        format!("#:{}{}", prefix, n)
+    }
+}
+
+impl Default for SchemeInterpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Appends one form's result to `run_program` output, or its error.
+fn report(output: &mut String, line: usize, result: Result<SchemeValue, SchemeError>) -> Result<(), SchemeError> {
+    match result {
+        Ok(result) => {
+            match result {
+                SchemeValue::String(s) => output.push_str(&s),
+                SchemeValue::Number(n) => output.push_str(&n.to_string()),
+                SchemeValue::Boolean(b) => output.push_str(&b.to_string()),
+                _ => output.push_str("result"),
+            }
+            output.push('\n');
+            Ok(())
+        }
+        Err(e) => {
+            output.push_str(&format!("Error on line {}: {}\n", line, e));
+            Err(e)
+        }
+    }
+}
+
+/// The error for a `make-vector` or `make-string` the host could not
+/// allocate, whatever the limits allow.
+fn out_of_memory(kind: &str, len: usize) -> SchemeError {
+    SchemeError::MemoryLimit(format!("not enough memory for a {} of length {}", kind, len))
+}
+
+fn string_arg<'a>(name: &str, value: &'a SchemeValue) -> Result<&'a str, String> {
+    match value {
+        SchemeValue::String(s) => Ok(s),
+        _ => Err(format!("{} requires string arguments", name)),
+    }
+}
+
+/// Packs procedure results the way `values` does: one result is returned
+/// as-is, anything else is wrapped.
+pub fn multiple_values(mut values: Vec<SchemeValue>) -> SchemeValue {
+    if values.len() == 1 {
+        values.pop().unwrap()
+    } else {
+        SchemeValue::Values(values)
+    }
+}
+
+/// Everything except `#f` counts as true.
+pub fn is_true(value: &SchemeValue) -> bool {
+    !matches!(value, SchemeValue::Boolean(false))
+}
+
+pub fn values_eqv(a: &SchemeValue, b: &SchemeValue) -> bool {
+    match (a, b) {
+        (SchemeValue::Number(x), SchemeValue::Number(y)) => x == y,
+        (SchemeValue::Boolean(x), SchemeValue::Boolean(y)) => x == y,
+        (SchemeValue::String(x), SchemeValue::String(y)) => x == y,
+        (SchemeValue::Symbol(x), SchemeValue::Symbol(y)) => x == y,
+        (SchemeValue::Nil, SchemeValue::Nil) => true,
+        (SchemeValue::Nil, SchemeValue::List(l)) | (SchemeValue::List(l), SchemeValue::Nil) => l.is_empty(),
+        (SchemeValue::List(x), SchemeValue::List(y)) => x.is_empty() && y.is_empty(),
+        (SchemeValue::Function(f), SchemeValue::Function(g)) => *f as usize == *g as usize,
+        (SchemeValue::Native(f), SchemeValue::Native(g)) => Rc::ptr_eq(f, g),
+        (SchemeValue::Primitive(p), SchemeValue::Primitive(q)) => p == q,
+        (SchemeValue::Lambda(f), SchemeValue::Lambda(g)) => Rc::ptr_eq(f, g),
+        (SchemeValue::Closure(f), SchemeValue::Closure(g)) => Rc::ptr_eq(f, g),
+        (SchemeValue::Macro(f), SchemeValue::Macro(g)) => Rc::ptr_eq(f, g),
+        (SchemeValue::Continuation(f), SchemeValue::Continuation(g)) => Rc::ptr_eq(f, g),
+        (SchemeValue::ErrorObject(f), SchemeValue::ErrorObject(g)) => Rc::ptr_eq(f, g),
+        (SchemeValue::Promise(f), SchemeValue::Promise(g)) => Rc::ptr_eq(f, g),
+        (SchemeValue::Environment(f), SchemeValue::Environment(g)) => Rc::ptr_eq(f, g),
+        (SchemeValue::WeakBox(f), SchemeValue::WeakBox(g)) => Rc::ptr_eq(f, g),
+        (SchemeValue::WeakTable(f), SchemeValue::WeakTable(g)) => Rc::ptr_eq(f, g),
+        (SchemeValue::Ephemeron(f), SchemeValue::Ephemeron(g)) => Rc::ptr_eq(f, g),
+        (SchemeValue::Request(f), SchemeValue::Request(g)) => Rc::ptr_eq(f, g),
+        (SchemeValue::Response(f), SchemeValue::Response(g)) => Rc::ptr_eq(f, g),
+        _ => false,
+    }
+}
+
+pub fn values_equal(a: &SchemeValue, b: &SchemeValue) -> bool {
+    match (a, b) {
+        (SchemeValue::List(x), SchemeValue::List(y)) | (SchemeValue::Vector(x), SchemeValue::Vector(y)) => {
+            x.len() == y.len() && x.iter().zip(y).all(|(p, q)| values_equal(p, q))
+        }
+        (SchemeValue::HashTable(x), SchemeValue::HashTable(y)) => {
+            x.len() == y.len() && x.iter().all(|(k, v)| y.get(k).is_some_and(|w| values_equal(v, w)))
+        }
+        _ => values_eqv(a, b),
+    }
+}
+
+/// Applies the `a`/`d` steps of a name like `caddr` right to left.
+fn cxr(name: &str, args: &[SchemeValue]) -> Result<SchemeValue, String> {
+    if args.len() != 1 {
+        return Err(format!("{} requires exactly one argument", name));
+    }
+    let mut value = args[0].clone();
+    for step in name[1..name.len() - 1].chars().rev() {
+        value = match value {
+            SchemeValue::List(list) if !list.is_empty() => match step {
+                'a' => list[0].clone(),
+                _ if list.len() == 1 => SchemeValue::Nil,
+                _ => SchemeValue::List(list[1..].to_vec()),
+            },
+            _ => return Err(format!("{} requires a list with enough elements", name)),
+        };
+    }
+    Ok(value)
+}
+
+/// Splits a lambda list into required parameters and an optional rest
+/// parameter. Accepts `(a b)`, `(a . rest)` and a bare `args` symbol.
+pub(crate) fn parse_params(spec: &SchemeValue) -> Result<(Vec<String>, Option<String>), String> {
+    match spec {
+        SchemeValue::Nil => Ok((Vec::new(), None)),
+        SchemeValue::Symbol(rest) => Ok((Vec::new(), Some(rest.clone()))),
+        SchemeValue::List(items) => {
+            let mut params = Vec::new();
+            let mut iter = items.iter();
+            while let Some(item) = iter.next() {
+                match item {
+                    SchemeValue::Symbol(s) if s == "." => {
+                        return match (iter.next(), iter.next()) {
+                            (Some(SchemeValue::Symbol(rest)), None) => Ok((params, Some(rest.clone()))),
+                            _ => Err("lambda: malformed rest parameter".to_string()),
+                        };
+                    }
+                    SchemeValue::Symbol(s) => params.push(s.clone()),
+                    _ => return Err("lambda parameters must be symbols".to_string()),
                 }
-                result.push(')');
-                result
             }
-            SchemeValue::HashTable(_) => "#<hash-table>".to_string(),
-            SchemeValue::Function(_) => "#<function>".to_string(),
-            SchemeValue::Lambda { .. } => "#<lambda>".to_string(),
-            SchemeValue::Symbol(s) => s.clone(),
-            SchemeValue::Nil => "()".to_string(),
+            Ok((params, None))
+        }
+        _ => Err("lambda parameters must be a list".to_string()),
+    }
+}
+
+fn bind_arguments(lambda: &Lambda, args: Vec<SchemeValue>) -> Result<Env, String> {
+    let arity_ok = match lambda.rest {
+        Some(_) => args.len() >= lambda.params.len(),
+        None => args.len() == lambda.params.len(),
+    };
+    if !arity_ok {
+        return Err(format!(
+            "Procedure expects {}{} arguments, got {}",
+            if lambda.rest.is_some() { "at least " } else { "" },
+            lambda.params.len(),
+            args.len()
+        ));
+    }
+    let env = Environment::new_child(&lambda.env);
+    {
+        let mut frame = env.borrow_mut();
+        let mut args = args.into_iter();
+        for param in &lambda.params {
+            frame.define(param, args.next().unwrap());
+        }
+        if let Some(rest) = &lambda.rest {
+            let rest_values: Vec<SchemeValue> = args.collect();
+            frame.define(rest, if rest_values.is_empty() { SchemeValue::Nil } else { SchemeValue::List(rest_values) });
         }
     }
-} 
\ No newline at end of file
+    Ok(env)
+}
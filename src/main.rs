@@ -1,198 +1,268 @@
 #[cfg(feature = "fastly-binary")]
-use fastly::http::{Method, StatusCode};
+use fastly::http::{header, Method, StatusCode};
 #[cfg(feature = "fastly-binary")]
 use fastly::{mime, Error, Request, Response};
-use lisp_compute::SchemeInterpreter;
+#[cfg(feature = "fastly-binary")]
+use lisp_compute::json;
+#[cfg(feature = "fastly-binary")]
+use lisp_compute::service::{self, Route};
+#[cfg(feature = "fastly-binary")]
+use lisp_compute::{html, http};
+use lisp_compute::{SchemeError, SchemeInterpreter, SchemeValue};
+#[cfg(feature = "fastly-binary")]
+use std::time::{Duration, Instant};
+
+/// How long a request may run. Set this to the service's execution limit.
+#[cfg(feature = "fastly-binary")]
+const EXECUTION_LIMIT: Duration = Duration::from_millis(100);
+
+/// Scripts are stopped this long before `EXECUTION_LIMIT`, leaving time to
+/// send a 503 instead of being killed by the platform.
+#[cfg(feature = "fastly-binary")]
+const SHUTDOWN_MARGIN: Duration = Duration::from_millis(5);
+
+/// A snapshot of an interpreter with `http::HANDLER_PROFILES` that has
+/// loaded `handler.scm`, the request handler for paths without a built-in
+/// route. The tests check that it is up to date.
+#[cfg(feature = "fastly-binary")]
+const HANDLER_SNAPSHOT: &[u8] = include_bytes!("../handler.snapshot");
+
+/// The example programs and `demo.scm`, which defines the demo page served
+/// at `/`, read, expanded and compiled ahead of time by
+/// `lisp_compute::image::compile`. The tests check that it is up to date.
+#[cfg(feature = "fastly-binary")]
+const EXAMPLES_IMAGE: &[u8] = include_bytes!("../examples/examples.img");
+
+/// The programs in `EXAMPLES_IMAGE` and their headings, in display order.
+#[cfg(feature = "fastly-binary")]
+const EXAMPLES: [(&str, &str); 5] = [
+    ("fibonacci.scm", "FIBONACCI"),
+    ("advanced.scm", "ADVANCED"),
+    ("list-processing.scm", "LIST PROCESSING"),
+    ("turing-complete.scm", "TURING COMPLETE"),
+    ("computational-patterns.scm", "COMPUTATIONAL PATTERNS"),
+];
 
 #[cfg(feature = "fastly-binary")]
 #[fastly::main]
-fn main(req: Request) -> Result<Response, Error> {
-    // Include the example files at compile time
-    const FIBONACCI_EXAMPLE: &str = include_str!("../examples/fibonacci.scm");
-    const ADVANCED_EXAMPLE: &str = include_str!("../examples/advanced.scm");
-    const LIST_PROCESSING_EXAMPLE: &str = include_str!("../examples/list-processing.scm");
-    const TURING_COMPLETE_EXAMPLE: &str = include_str!("../examples/turing-complete.scm");
-    const COMPUTATIONAL_PATTERNS_EXAMPLE: &str = include_str!("../examples/computational-patterns.scm");
-    
-    // Only allow GET requests
-    match req.get_method() {
-        &Method::GET => (),
-        _ => {
-            return Ok(Response::from_status(StatusCode::METHOD_NOT_ALLOWED)
+fn main(mut req: Request) -> Result<Response, Error> {
+    let deadline = Instant::now() + EXECUTION_LIMIT - SHUTDOWN_MARGIN;
+    let path = req.get_path().to_string();
+    let route = Route::for_path(&path);
+    let method = req.get_method().clone();
+
+    if route == Route::NotFound {
+        return Ok(scheme_handler(req, deadline));
+    }
+    let allow = route.methods().join(", ");
+    if method == Method::OPTIONS {
+        return Ok(Response::from_status(StatusCode::NO_CONTENT).with_header(header::ALLOW, allow));
+    }
+    if !route.allows(method.as_str()) {
+        return Ok(Response::from_status(StatusCode::METHOD_NOT_ALLOWED)
+            .with_header(header::ALLOW, allow)
+            .with_content_type(mime::TEXT_PLAIN_UTF_8)
+            .with_body("Method not allowed"));
+    }
+
+    let mut response = match route {
+        Route::Demo => demo(deadline),
+        Route::Examples => example_list(),
+        Route::Example(name) => match EXAMPLES.iter().find(|(file, _)| file.trim_end_matches(".scm") == name) {
+            Some((file, _)) => example(file, deadline),
+            None => not_found(&path),
+        },
+        Route::Eval => {
+            let content_type = req.get_header_str("content-type").map(str::to_string);
+            let body = req.take_body_bytes();
+            let reply = service::eval(&body, content_type.as_deref(), Some(deadline));
+            Response::from_status(reply.status)
+                .with_content_type(mime::APPLICATION_JSON)
+                .with_body(reply.body)
+        }
+        Route::Health => Response::from_status(StatusCode::OK)
+            .with_content_type(mime::TEXT_PLAIN_UTF_8)
+            .with_body("ok\n"),
+        Route::NotFound => unreachable!("answered above"),
+    };
+    // HEAD gets the headers GET would, without the body
+    if method == Method::HEAD {
+        response.take_body();
+    }
+    Ok(response)
+}
+
+/// An interpreter that can run the programs in `EXAMPLES_IMAGE`, stopped
+/// at `deadline`.
+#[cfg(feature = "fastly-binary")]
+fn load_examples(deadline: Instant) -> Result<SchemeInterpreter, SchemeError> {
+    let interpreter = SchemeInterpreter::from_image(EXAMPLES_IMAGE)?;
+    interpreter.set_deadline(Some(deadline));
+    Ok(interpreter)
+}
+
+/// Any other path: whatever `handle` in `HANDLER_SNAPSHOT` answers, or 404
+/// if it returns `#f`. The handler gets the same limits and fuel as
+/// `POST /eval`.
+#[cfg(feature = "fastly-binary")]
+fn scheme_handler(mut req: Request, deadline: Instant) -> Response {
+    let error = |e: &SchemeError| match e {
+        SchemeError::Interrupted(_) => service_unavailable(e),
+        _ => Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+            .with_content_type(mime::TEXT_PLAIN_UTF_8)
+            .with_body(format!("Handler error: {}\n", e)),
+    };
+    let interpreter = SchemeInterpreter::builder()
+        .with_profiles(http::HANDLER_PROFILES)
+        .with_limits(service::default_limits())
+        .restore(HANDLER_SNAPSHOT);
+    let interpreter = match interpreter {
+        Ok(interpreter) => interpreter,
+        Err(e) => return error(&e),
+    };
+    interpreter.set_fuel(Some(service::DEFAULT_FUEL));
+    interpreter.set_deadline(Some(deadline));
+    let is_head = req.get_method() == Method::HEAD;
+    let request = http::Request {
+        method: req.get_method_str().to_string(),
+        path: req.get_path().to_string(),
+        query: req.get_query_str().unwrap_or_default().to_string(),
+        headers: (req.get_headers())
+            .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+            .collect(),
+        body: req.take_body_str_lossy(),
+    };
+    let path = request.path.clone();
+    match http::handle(&interpreter, request) {
+        Ok(Some(reply)) => {
+            let mut response = Response::from_status(reply.status);
+            for (name, value) in reply.headers {
+                response.append_header(name, value);
+            }
+            if !is_head {
+                response.set_body(reply.body);
+            }
+            response
+        }
+        Ok(None) => not_found(&path),
+        Err(e) => error(&e),
+    }
+}
+
+/// `GET /examples`: the name, title and URL of each example, as JSON.
+#[cfg(feature = "fastly-binary")]
+fn example_list() -> Response {
+    let string = |s: &str| SchemeValue::String(s.to_string());
+    let examples = EXAMPLES
+        .iter()
+        .map(|(file, title)| {
+            let name = file.trim_end_matches(".scm");
+            SchemeValue::List(vec![
+                SchemeValue::List(vec![string("name"), string(name)]),
+                SchemeValue::List(vec![string("title"), string(title)]),
+                SchemeValue::List(vec![string("path"), string(&format!("/examples/{}", name))]),
+            ])
+        })
+        .collect();
+    let list = SchemeValue::List(vec![SchemeValue::List(vec![string("examples"), SchemeValue::Vector(examples)])]);
+    let options = json::WriteOptions { pretty: true, ..Default::default() };
+    Response::from_status(StatusCode::OK)
+        .with_content_type(mime::APPLICATION_JSON)
+        .with_body(json::write(&list, options).expect("the example list is JSON") + "\n")
+}
+
+/// `GET /examples/<name>`: the output of one example, as plain text.
+#[cfg(feature = "fastly-binary")]
+fn example(file: &str, deadline: Instant) -> Response {
+    let interpreter = match load_examples(deadline) {
+        Ok(interpreter) => interpreter,
+        Err(e) => {
+            return Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
                 .with_content_type(mime::TEXT_PLAIN_UTF_8)
-                .with_body("Method not allowed"));
+                .with_body(format!("Could not load examples: {}\n", e));
         }
+    };
+    match interpreter.run_image_program(file) {
+        Ok(output) => Response::from_status(StatusCode::OK)
+            .with_content_type(mime::TEXT_PLAIN_UTF_8)
+            .with_body(output),
+        Err(e @ SchemeError::Interrupted(_)) => service_unavailable(&e),
+        Err(e) => Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+            .with_content_type(mime::TEXT_PLAIN_UTF_8)
+            .with_body(format!("Error running {}: {}\n", file, e)),
     }
-    
-    // Create Scheme interpreter
-    let interpreter = SchemeInterpreter::new();
-    
+}
+
+/// `GET /`: the demo page, running every example.
+#[cfg(feature = "fastly-binary")]
+fn demo(deadline: Instant) -> Response {
+    let interpreter = match load_examples(deadline) {
+        Ok(interpreter) => interpreter,
+        Err(e) => {
+            return Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+                .with_content_type(mime::TEXT_PLAIN_UTF_8)
+                .with_body(format!("Could not load examples: {}\n", e));
+        }
+    };
+
     // Run the example files
     let mut output = String::new();
-    
-    // Run Fibonacci example
-    output.push_str("=== FIBONACCI EXAMPLE ===\n");
-    match interpreter.run_program(FIBONACCI_EXAMPLE) {
-        Ok(result) => output.push_str(&result),
-        Err(e) => output.push_str(&format!("Error running fibonacci.scm: {}\n", e)),
-    }
-    output.push_str("\n");
-    
-    // Run Advanced example
-    output.push_str("=== ADVANCED EXAMPLE ===\n");
-    match interpreter.run_program(ADVANCED_EXAMPLE) {
-        Ok(result) => output.push_str(&result),
-        Err(e) => output.push_str(&format!("Error running advanced.scm: {}\n", e)),
-    }
-    output.push_str("\n");
-    
-    // Run List Processing example
-    output.push_str("=== LIST PROCESSING EXAMPLE ===\n");
-    match interpreter.run_program(LIST_PROCESSING_EXAMPLE) {
-        Ok(result) => output.push_str(&result),
-        Err(e) => output.push_str(&format!("Error running list-processing.scm: {}\n", e)),
-    }
-    output.push_str("\n");
-    
-    // Run Turing Complete example
-    output.push_str("=== TURING COMPLETE EXAMPLE ===\n");
-    match interpreter.run_program(TURING_COMPLETE_EXAMPLE) {
-        Ok(result) => output.push_str(&result),
-        Err(e) => output.push_str(&format!("Error running turing-complete.scm: {}\n", e)),
+    for (file, title) in EXAMPLES {
+        output.push_str(&format!("=== {} EXAMPLE ===\n", title));
+        match interpreter.run_image_program(file) {
+            Ok(result) => output.push_str(&result),
+            Err(e @ SchemeError::Interrupted(_)) => return service_unavailable(&e),
+            Err(e) => output.push_str(&format!("Error running {}: {}\n", file, e)),
+        }
+        output.push('\n');
     }
-    output.push_str("\n");
     
-    // Run Computational Patterns example
-    output.push_str("=== COMPUTATIONAL PATTERNS EXAMPLE ===\n");
-    match interpreter.run_program(COMPUTATIONAL_PATTERNS_EXAMPLE) {
-        Ok(result) => output.push_str(&result),
-        Err(e) => output.push_str(&format!("Error running computational-patterns.scm: {}\n", e)),
+    match render_demo_page(&interpreter, output) {
+        Ok(html) => Response::from_status(StatusCode::OK)
+            .with_content_type(mime::TEXT_HTML_UTF_8)
+            .with_body(html),
+        Err(e @ SchemeError::Interrupted(_)) => service_unavailable(&e),
+        Err(e) => Response::from_status(StatusCode::INTERNAL_SERVER_ERROR)
+            .with_content_type(mime::TEXT_PLAIN_UTF_8)
+            .with_body(format!("Could not render the demo page: {}\n", e)),
     }
-    output.push_str("\n");
-    
-    let html_content = format!(
-        r#"<!DOCTYPE html>
+}
+
+/// Builds the demo page around `output` with `demo-page` from the image.
+#[cfg(feature = "fastly-binary")]
+fn render_demo_page(interpreter: &SchemeInterpreter, output: String) -> Result<String, SchemeError> {
+    interpreter.run_image_program("demo.scm")?;
+    let page = interpreter.call("demo-page", vec![SchemeValue::String(output)])?;
+    html::to_string(&page)
+}
+
+/// The answer for a path no route matches.
+#[cfg(feature = "fastly-binary")]
+fn not_found(path: &str) -> Response {
+    let path = path.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    Response::from_status(StatusCode::NOT_FOUND)
+        .with_content_type(mime::TEXT_HTML_UTF_8)
+        .with_body(format!(
+            r#"<!DOCTYPE html>
 <html lang="en">
 <head>
     <meta charset="UTF-8">
-    <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>Scheme Lisp on Fastly Compute</title>
-    <style>
-        body {{
-            font-family: 'Courier New', monospace;
-            max-width: 800px;
-            margin: 0 auto;
-            padding: 20px;
-            background: linear-gradient(135deg, #667eea 0%, #764ba2 100%);
-            color: white;
-            min-height: 100vh;
-        }}
-        .container {{
-            background: rgba(255, 255, 255, 0.1);
-            padding: 30px;
-            border-radius: 15px;
-            backdrop-filter: blur(10px);
-            box-shadow: 0 8px 32px rgba(0, 0, 0, 0.1);
-        }}
-        h1 {{
-            text-align: center;
-            margin-bottom: 30px;
-            font-size: 2.5em;
-            text-shadow: 2px 2px 4px rgba(0, 0, 0, 0.3);
-        }}
-        .scheme-output {{
-            background: rgba(0, 0, 0, 0.3);
-            padding: 20px;
-            border-radius: 10px;
-            border-left: 4px solid #4CAF50;
-            white-space: pre-wrap;
-            font-size: 1.1em;
-            line-height: 1.6;
-        }}
-        .info {{
-            margin-top: 30px;
-            padding: 20px;
-            background: rgba(255, 255, 255, 0.1);
-            border-radius: 10px;
-            border-left: 4px solid #2196F3;
-        }}
-        .highlight {{
-            color: #4CAF50;
-            font-weight: bold;
-        }}
-        .github-link {{
-            margin-top: 20px;
-            text-align: center;
-        }}
-        .github-link a {{
-            color: #4CAF50;
-            text-decoration: none;
-            font-weight: bold;
-            padding: 10px 20px;
-            border: 2px solid #4CAF50;
-            border-radius: 25px;
-            transition: all 0.3s ease;
-        }}
-        .github-link a:hover {{
-            background: #4CAF50;
-            color: white;
-        }}
-        .github-header {{
-            text-align: center;
-            margin-bottom: 30px;
-            padding: 30px;
-            background: linear-gradient(135deg, #4CAF50 0%, #45a049 100%);
-            border-radius: 20px;
-            border: 3px solid #2E7D32;
-            box-shadow: 0 8px 25px rgba(76, 175, 80, 0.3);
-        }}
-        .github-header a {{
-            color: white;
-            text-decoration: none;
-            font-weight: bold;
-            font-size: 1.5em;
-            padding: 20px 40px;
-            border: 3px solid white;
-            border-radius: 40px;
-            transition: all 0.3s ease;
-            display: inline-block;
-            background: rgba(255, 255, 255, 0.1);
-            text-shadow: 1px 1px 2px rgba(0, 0, 0, 0.3);
-        }}
-        .github-header a:hover {{
-            background: rgba(255, 255, 255, 0.2);
-            transform: scale(1.05);
-            box-shadow: 0 10px 30px rgba(255, 255, 255, 0.2);
-        }}
-    </style>
+    <title>Not Found</title>
 </head>
 <body>
-    <div class="container">
-        <h1>🚀 Scheme Lisp on Fastly Compute</h1>
-        
-        <div class="github-header">
-            <a href="https://github.com/aspires/scheme-on-compute" target="_blank">🐙 View on GitHub</a>
-        </div>
-        
-        <div class="scheme-output">{}</div>
-        
-        <div class="info">
-            <h3>About this demo:</h3>
-            <p>This page demonstrates a simple Scheme Lisp interpreter running on <span class="highlight">Fastly Compute@Edge</span>.</p>
-            <p>The Scheme code is executed server-side and the results are displayed above.</p>
-            <p>This shows how you can run custom programming languages at the edge for dynamic content generation.</p>
-            
-            <div class="github-link">
-                <a href="https://github.com/aspires/scheme-on-compute" target="_blank">🐙 View Source on GitHub</a>
-            </div>
-        </div>
-    </div>
+    <h1>404 Not Found</h1>
+    <p>Nothing is served at <code>{}</code>. Try the <a href="/">demo</a>, the <a href="/examples">examples</a> or <code>POST /eval</code>.</p>
 </body>
-</html>"#,
-        output
-    );
-    
-    Ok(Response::from_status(StatusCode::OK)
-        .with_content_type(mime::TEXT_HTML_UTF_8)
-        .with_body(html_content))
-} 
\ No newline at end of file
+</html>
+"#,
+            path
+        ))
+}
+
+/// The response sent when a script is stopped by the deadline.
+#[cfg(feature = "fastly-binary")]
+fn service_unavailable(error: &SchemeError) -> Response {
+    Response::from_status(StatusCode::SERVICE_UNAVAILABLE)
+        .with_content_type(mime::TEXT_PLAIN_UTF_8)
+        .with_body(format!("Script did not finish in time: {}\n", error))
+}
@@ -0,0 +1,440 @@
+// Optimizer over expanded core forms, run between the expander and the
+// backend unless turned off with `SchemeInterpreterBuilder::with_optimizer`.
+//
+// It folds calls to pure builtins whose arguments are all constants, drops
+// the branch of an `if` that a constant test can never take, and
+// beta-reduces immediately applied lambdas, which is what `let` expands to.
+// A parameter bound to a constant and never assigned is replaced by the
+// constant, and a call to a parameter bound to a small lambda is inlined.
+// Parameters left unused are removed along with their arguments when
+// evaluating those has no effect.
+//
+// Inlining only follows lexical bindings, whose every assignment is in the
+// form being optimized. Procedures defined at top level are never inlined,
+// since a later form can redefine them. Builtins can be rebound too, by a
+// form that has not been read yet, so a fold inside a procedure keeps the
+// original call: `(if (builtins-unchanged?) 6 (* 2 3))`. The test is false
+// once any folded builtin has been defined or assigned, and no more calls
+// are folded from then on. Code outside procedures runs as soon as it is
+// optimized and needs no test.
+//
+// The expander bounds how deeply a form nests, but inlining can add a few
+// levels, so anything deeper than `MAX_NESTING` is left as it is.
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::compile::scan;
+use crate::expand::MAX_NESTING;
+use crate::{parse_params, Env, Primitive, SchemeInterpreter, SchemeValue};
+
+/// Builtins without side effects, whose calls on constants are folded.
+const FOLDABLE: &[&str] = &[
+    "+", "-", "*", "/", "<", "=", ">", ">=", "<=", "abs", "sqrt", "expt", "not", "eq?", "eqv?", "equal?", "symbol?",
+    "number?", "string?", "boolean?", "pair?", "null?", "car", "cdr", "cadr", "cddr", "caddr", "cdddr", "cadddr",
+    "length", "string-length", "string=?", "string<?",
+];
+
+/// The largest lambda, counted in symbols and constants, that is inlined.
+const INLINE_SIZE: usize = 12;
+
+impl SchemeInterpreter {
+    /// Optimizes an expanded form that is about to run in `env`.
+    pub(crate) fn optimize(&self, expr: SchemeValue, env: &Env) -> SchemeValue {
+        // Even unoptimized forms can rebind a builtin folded in a snapshot.
+        self.note_assignments(&expr);
+        self.optimized(expr, env)
+    }
+
+    /// `expr` as `optimize` leaves it, or unchanged if the optimizer is
+    /// off, for `expand/optimize`.
+    pub(crate) fn optimized(&self, expr: SchemeValue, env: &Env) -> SchemeValue {
+        if !self.optimizer {
+            return expr;
+        }
+        let mut assigned = HashSet::new();
+        assignments(&expr, &mut assigned);
+        Optimizer { interpreter: self, env, assigned, scope: Vec::new(), procedures: 0, depth: 0 }.expr(&expr)
+    }
+
+    /// Notes any folded builtin that `form`, expanded or not, defines or
+    /// assigns. Run over a whole program before its first form, so that
+    /// procedures defined before the assignment keep their original calls.
+    pub(crate) fn note_assignments(&self, form: &SchemeValue) {
+        let items = match form {
+            SchemeValue::List(items) => items,
+            _ => return,
+        };
+        match items.as_slice() {
+            [SchemeValue::Symbol(head), ..] if head == "quote" => return,
+            [SchemeValue::Symbol(head), target, ..] if head == "define" || head == "set!" => {
+                let name = match target {
+                    SchemeValue::List(signature) => signature.first(),
+                    other => Some(other),
+                };
+                if let Some(SchemeValue::Symbol(name)) = name {
+                    self.note_rebound(name);
+                }
+            }
+            _ => {}
+        }
+        for item in items {
+            self.note_assignments(item);
+        }
+    }
+
+    pub(crate) fn note_rebound(&self, name: &str) {
+        if FOLDABLE.contains(&name) {
+            self.rebound.set(true);
+        }
+    }
+}
+
+/// What the optimizer knows about a lexical variable.
+enum Known {
+    Unknown,
+    /// Never assigned and bound to this constant expression.
+    Constant(SchemeValue),
+    /// Never assigned and bound to this lambda expression, which was
+    /// evaluated when the scope had `at` bindings.
+    Procedure { lambda: Rc<[SchemeValue]>, at: usize },
+}
+
+struct Optimizer<'a> {
+    interpreter: &'a SchemeInterpreter,
+    env: &'a Env,
+    /// Variables the form defines or assigns, whose calls are not folded.
+    assigned: HashSet<String>,
+    /// Variables bound by the enclosing lambdas, innermost last.
+    scope: Vec<(String, Known)>,
+    /// Number of enclosing lambdas that are not applied immediately.
+    procedures: usize,
+    /// Calls to `expr` in progress.
+    depth: usize,
+}
+
+impl Optimizer<'_> {
+    fn lookup(&self, name: &str) -> Option<&Known> {
+        self.scope.iter().rev().find(|(n, _)| n == name).map(|(_, known)| known)
+    }
+
+    fn expr(&mut self, expr: &SchemeValue) -> SchemeValue {
+        if self.depth == MAX_NESTING {
+            return expr.clone();
+        }
+        self.depth += 1;
+        let optimized = self.optimize_expr(expr);
+        self.depth -= 1;
+        optimized
+    }
+
+    fn optimize_expr(&mut self, expr: &SchemeValue) -> SchemeValue {
+        let items = match expr {
+            SchemeValue::Symbol(name) => {
+                return match self.lookup(name) {
+                    Some(Known::Constant(constant)) => constant.clone(),
+                    _ => expr.clone(),
+                };
+            }
+            SchemeValue::List(items) if !items.is_empty() => items,
+            other => return other.clone(),
+        };
+        if let SchemeValue::Symbol(head) = &items[0] {
+            match head.as_str() {
+                "quote" => return expr.clone(),
+                "if" if items.len() == 3 || items.len() == 4 => return self.if_(items),
+                "define" | "set!" if items.len() == 3 => {
+                    return SchemeValue::List(vec![items[0].clone(), items[1].clone(), self.expr(&items[2])]);
+                }
+                "lambda" => return self.lambda(items),
+                "begin" => {
+                    let mut out = vec![items[0].clone()];
+                    out.extend(self.body(&items[1..]));
+                    return match out.len() {
+                        2 => out.pop().unwrap(),
+                        _ => SchemeValue::List(out),
+                    };
+                }
+                _ => {}
+            }
+        }
+        let args: Vec<_> = items[1..].iter().map(|arg| self.expr(arg)).collect();
+        match &items[0] {
+            SchemeValue::List(lambda) if is_lambda(&items[0]) => self.beta(lambda, args),
+            SchemeValue::Symbol(name) => self.call(name, args),
+            head => {
+                let mut out = vec![self.expr(head)];
+                out.extend(args);
+                SchemeValue::List(out)
+            }
+        }
+    }
+
+    fn if_(&mut self, items: &[SchemeValue]) -> SchemeValue {
+        let test = self.expr(&items[1]);
+        match constant_value(&test) {
+            Some(value) if crate::is_true(&value) => self.expr(&items[2]),
+            Some(_) => match items.get(3) {
+                Some(otherwise) => self.expr(otherwise),
+                None => SchemeValue::Nil,
+            },
+            None => {
+                let mut out = vec![items[0].clone(), test];
+                out.extend(items[2..].iter().map(|branch| self.expr(branch)));
+                SchemeValue::List(out)
+            }
+        }
+    }
+
+    /// A sequence of forms, without the ones before the last that are
+    /// constants or lambdas and so have no effect.
+    fn body(&mut self, forms: &[SchemeValue]) -> Vec<SchemeValue> {
+        let mut out: Vec<_> = forms.iter().map(|form| self.expr(form)).collect();
+        if let Some(last) = out.pop() {
+            out.retain(|form| !is_pure(form));
+            out.push(last);
+        }
+        out
+    }
+
+    fn lambda(&mut self, items: &[SchemeValue]) -> SchemeValue {
+        let (params, rest) = match parse_params(items.get(1).unwrap_or(&SchemeValue::Nil)) {
+            Ok(params) if items.len() >= 3 => params,
+            _ => return SchemeValue::List(items.to_vec()),
+        };
+        let at = self.scope.len();
+        let locals = params.into_iter().chain(rest).chain(defines(&items[2..]));
+        self.scope.extend(locals.map(|name| (name, Known::Unknown)));
+        self.procedures += 1;
+        let mut out = items[..2].to_vec();
+        out.extend(self.body(&items[2..]));
+        self.procedures -= 1;
+        self.scope.truncate(at);
+        SchemeValue::List(out)
+    }
+
+    /// `((lambda (param ...) body ...) arg ...)` with the arguments already
+    /// optimized.
+    fn beta(&mut self, lambda: &[SchemeValue], args: Vec<SchemeValue>) -> SchemeValue {
+        let params = match parse_params(lambda.get(1).unwrap_or(&SchemeValue::Nil)) {
+            Ok((params, None)) if lambda.len() >= 3 && params.len() == args.len() => params,
+            _ => {
+                let mut out = vec![self.lambda(lambda)];
+                out.extend(args);
+                return SchemeValue::List(out);
+            }
+        };
+        let body = &lambda[2..];
+        let defines = defines(body);
+        let mut assigned = HashSet::new();
+        for form in body {
+            assignments(form, &mut assigned);
+        }
+
+        let at = self.scope.len();
+        for (param, arg) in params.iter().zip(&args) {
+            let known = match arg {
+                _ if assigned.contains(param) => Known::Unknown,
+                _ if is_atom(arg) => Known::Constant(arg.clone()),
+                SchemeValue::List(items) if is_lambda(arg) && size(arg) <= INLINE_SIZE => {
+                    Known::Procedure { lambda: items.as_slice().into(), at }
+                }
+                _ => Known::Unknown,
+            };
+            self.scope.push((param.clone(), known));
+        }
+        self.scope.extend(defines.iter().map(|name| (name.clone(), Known::Unknown)));
+        let mut body = self.body(body);
+        self.scope.truncate(at);
+
+        let mut used = HashSet::new();
+        for form in &body {
+            symbols(form, &mut used);
+        }
+        let (params, args): (Vec<_>, Vec<_>) = (params.into_iter().zip(args))
+            .filter(|(param, arg)| used.contains(param.as_str()) || !is_pure(arg))
+            .unzip();
+        if params.is_empty() && defines.is_empty() {
+            return match body.len() {
+                1 => body.pop().unwrap(),
+                _ => SchemeValue::List(std::iter::once(symbol("begin")).chain(body).collect()),
+            };
+        }
+        let params = match params.is_empty() {
+            true => SchemeValue::Nil,
+            false => SchemeValue::List(params.into_iter().map(SchemeValue::Symbol).collect()),
+        };
+        let mut out = vec![SchemeValue::List([vec![lambda[0].clone(), params], body].concat())];
+        out.extend(args);
+        SchemeValue::List(out)
+    }
+
+    /// A call to a variable, inlined if it is a known lambda and folded if
+    /// it is a pure builtin and every argument is constant.
+    fn call(&mut self, name: &str, args: Vec<SchemeValue>) -> SchemeValue {
+        match self.lookup(name) {
+            Some(Known::Procedure { lambda, at }) if self.inlinable(lambda, *at) => {
+                let lambda = lambda.clone();
+                return self.beta(&lambda, args);
+            }
+            None => {
+                if let Some(folded) = self.fold(name, &args) {
+                    return folded;
+                }
+            }
+            _ => {}
+        }
+        SchemeValue::List(std::iter::once(symbol(name)).chain(args).collect())
+    }
+
+    /// Whether a lambda can move to the current scope: no variable bound
+    /// since it was evaluated shadows one of its symbols.
+    fn inlinable(&self, lambda: &[SchemeValue], at: usize) -> bool {
+        let mut names = HashSet::new();
+        for item in lambda {
+            symbols(item, &mut names);
+        }
+        self.scope[at..].iter().all(|(name, _)| !names.contains(name.as_str()))
+    }
+
+    fn fold(&self, name: &str, args: &[SchemeValue]) -> Option<SchemeValue> {
+        if !FOLDABLE.contains(&name) || self.assigned.contains(name) || self.interpreter.rebound.get() {
+            return None;
+        }
+        let mut values = Vec::new();
+        let mut call = vec![symbol(name)];
+        for arg in args {
+            let (value, original) = match guarded(arg) {
+                Some((folded, original)) => (constant_value(folded)?, original.clone()),
+                None => (constant_value(arg)?, arg.clone()),
+            };
+            values.push(value);
+            call.push(original);
+        }
+        let current = self.env.borrow().get(name)?;
+        let builtin = self.interpreter.global.borrow().get(name)?;
+        let value = match (current, builtin) {
+            (SchemeValue::Function(f), SchemeValue::Function(g)) if f as usize == g as usize => {
+                // An error is left for the call to raise when it runs.
+                literal(f(&values, &mut HashMap::new()).ok()?)?
+            }
+            _ => return None,
+        };
+        if self.procedures == 0 {
+            return Some(value);
+        }
+        let test = SchemeValue::List(vec![SchemeValue::Primitive(Primitive::BuiltinsUnchanged)]);
+        Some(SchemeValue::List(vec![symbol("if"), test, value, SchemeValue::List(call)]))
+    }
+}
+
+fn symbol(name: &str) -> SchemeValue {
+    SchemeValue::Symbol(name.to_string())
+}
+
+fn is_lambda(expr: &SchemeValue) -> bool {
+    matches!(expr, SchemeValue::List(items) if matches!(items.first(), Some(SchemeValue::Symbol(head)) if head == "lambda"))
+}
+
+/// The folded value and the original call of a fold inside a procedure.
+fn guarded(expr: &SchemeValue) -> Option<(&SchemeValue, &SchemeValue)> {
+    match expr {
+        SchemeValue::List(items) => match items.as_slice() {
+            [SchemeValue::Symbol(head), SchemeValue::List(test), folded, original]
+                if head == "if" && matches!(test.as_slice(), [SchemeValue::Primitive(Primitive::BuiltinsUnchanged)]) =>
+            {
+                Some((folded, original))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The value of a constant expression.
+fn constant_value(expr: &SchemeValue) -> Option<SchemeValue> {
+    match expr {
+        SchemeValue::List(items) => match items.as_slice() {
+            [SchemeValue::Symbol(head), datum] if head == "quote" => Some(datum.clone()),
+            _ => None,
+        },
+        SchemeValue::Number(_) | SchemeValue::String(_) | SchemeValue::Boolean(_) | SchemeValue::Vector(_) => {
+            Some(expr.clone())
+        }
+        SchemeValue::Nil => Some(SchemeValue::Nil),
+        _ => None,
+    }
+}
+
+/// An expression evaluating to `value`, for the data a fold can produce.
+fn literal(value: SchemeValue) -> Option<SchemeValue> {
+    match value {
+        SchemeValue::Number(_) | SchemeValue::String(_) | SchemeValue::Boolean(_) | SchemeValue::Nil => Some(value),
+        SchemeValue::Symbol(_) | SchemeValue::List(_) => Some(SchemeValue::List(vec![symbol("quote"), value])),
+        _ => None,
+    }
+}
+
+/// A constant small enough to copy into every use of a variable.
+fn is_atom(expr: &SchemeValue) -> bool {
+    match constant_value(expr) {
+        Some(SchemeValue::List(items)) => items.is_empty(),
+        Some(SchemeValue::Vector(_)) | None => false,
+        Some(_) => true,
+    }
+}
+
+/// Whether evaluating `expr` can have no effect and cannot fail.
+fn is_pure(expr: &SchemeValue) -> bool {
+    constant_value(expr).is_some() || is_lambda(expr)
+}
+
+/// The internal definitions of a lambda body.
+fn defines(body: &[SchemeValue]) -> Vec<String> {
+    let mut defines = Vec::new();
+    for form in body {
+        scan(form, &mut defines, &mut false);
+    }
+    defines
+}
+
+/// Every variable `form` defines or assigns, at any depth.
+fn assignments(form: &SchemeValue, out: &mut HashSet<String>) {
+    let items = match form {
+        SchemeValue::List(items) => items,
+        _ => return,
+    };
+    match items.as_slice() {
+        [SchemeValue::Symbol(head), ..] if head == "quote" => return,
+        [SchemeValue::Symbol(head), SchemeValue::Symbol(name), ..] if head == "define" || head == "set!" => {
+            out.insert(name.clone());
+        }
+        _ => {}
+    }
+    for item in items {
+        assignments(item, out);
+    }
+}
+
+/// Every symbol in `form` outside quoted data.
+fn symbols<'a>(form: &'a SchemeValue, out: &mut HashSet<&'a str>) {
+    match form {
+        SchemeValue::Symbol(name) => {
+            out.insert(name);
+        }
+        SchemeValue::List(items) if !matches!(items.first(), Some(SchemeValue::Symbol(head)) if head == "quote") => {
+            for item in items {
+                symbols(item, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The number of symbols and constants in `form`.
+fn size(form: &SchemeValue) -> usize {
+    match form {
+        SchemeValue::List(items) => items.iter().map(size).sum(),
+        _ => 1,
+    }
+}
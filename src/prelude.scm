@@ -0,0 +1,62 @@
+;; Library procedures written in Scheme. Loaded into the builtins frame by
+;; SchemeInterpreter::new.
+
+;; Lists
+
+(define (reverse lst)
+  (let loop ((lst lst) (acc '()))
+    (if (null? lst) acc (loop (cdr lst) (cons (car lst) acc)))))
+
+;; Streams (SRFI-41 style). A stream is a promise that yields either () or a
+;; list of two promises: the element and the rest of the stream. The
+;; constructors wrap their bodies in delay-force so that walking a long
+;; stream runs in constant space.
+
+(define stream-null (make-promise '()))
+
+(define (stream? s) (promise? s))
+
+(define (stream-null? s) (null? (force s)))
+
+(define (stream-pair? s) (and (promise? s) (pair? (force s))))
+
+(define (stream-car s)
+  (if (stream-pair? s)
+      (force (car (force s)))
+      (error "stream-car: not a stream pair" s)))
+
+(define (stream-cdr s)
+  (if (stream-pair? s)
+      (cadr (force s))
+      (error "stream-cdr: not a stream pair" s)))
+
+(define (stream-map f s)
+  (delay-force
+    (if (stream-null? s)
+        stream-null
+        (stream-cons (f (stream-car s)) (stream-map f (stream-cdr s))))))
+
+(define (stream-filter keep? s)
+  (delay-force
+    (cond ((stream-null? s) stream-null)
+          ((keep? (stream-car s))
+           (stream-cons (stream-car s) (stream-filter keep? (stream-cdr s))))
+          (else (stream-filter keep? (stream-cdr s))))))
+
+(define (stream-take n s)
+  (delay-force
+    (if (or (<= n 0) (stream-null? s))
+        stream-null
+        (stream-cons (stream-car s) (stream-take (- n 1) (stream-cdr s))))))
+
+(define (list->stream lst)
+  (delay-force
+    (if (null? lst)
+        stream-null
+        (stream-cons (car lst) (list->stream (cdr lst))))))
+
+(define (stream->list s)
+  (let loop ((s s) (acc '()))
+    (if (stream-null? s)
+        (reverse acc)
+        (loop (stream-cdr s) (cons (stream-car s) acc)))))
@@ -0,0 +1,260 @@
+// Reader: turns program text into `SchemeValue` data for the expander.
+
+use crate::SchemeValue;
+
+/// Lists, vectors and quotes nested deeper than this are rejected, so that
+/// hostile input cannot exhaust the stack.
+const MAX_NESTING: usize = 512;
+
+/// A top-level datum together with where it came from, so `run_program` can
+/// report which form it is working on.
+pub(crate) struct Form {
+    pub datum: SchemeValue,
+    pub line: usize,
+    pub column: usize,
+    pub source: String,
+}
+
+/// A syntax error and where the reader was when it gave up. Lines and
+/// columns count from 0, like `Form::line`.
+pub(crate) struct ReadError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+pub(crate) fn read_program(src: &str) -> Result<Vec<Form>, String> {
+    read_forms(src).map_err(|e| e.message)
+}
+
+/// `read_program`, but with the position of a syntax error.
+pub(crate) fn read_forms(src: &str) -> Result<Vec<Form>, ReadError> {
+    let mut reader = Reader { src, pos: 0, line: 0, depth: 0 };
+    let mut forms = Vec::new();
+    loop {
+        reader.skip_atmosphere().map_err(|message| reader.error(message))?;
+        if reader.peek().is_none() {
+            break;
+        }
+        let start = reader.pos;
+        let line = reader.line;
+        let column = reader.column();
+        let datum = reader.read().map_err(|message| reader.error(message))?;
+        forms.push(Form {
+            datum,
+            line,
+            column,
+            source: src[start..reader.pos].to_string(),
+        });
+    }
+    Ok(forms)
+}
+
+struct Reader<'a> {
+    src: &'a str,
+    pos: usize,
+    line: usize,
+    depth: usize,
+}
+
+impl Reader<'_> {
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn rest(&self) -> &str {
+        &self.src[self.pos..]
+    }
+
+    /// Characters since the start of the current line.
+    fn column(&self) -> usize {
+        let before = &self.src[..self.pos];
+        before[before.rfind('\n').map_or(0, |i| i + 1)..].chars().count()
+    }
+
+    fn error(&self, message: String) -> ReadError {
+        ReadError { message, line: self.line, column: self.column() }
+    }
+
+    /// Skips whitespace, `;` line comments, `#| |#` block comments and `#;`
+    /// datum comments.
+    fn skip_atmosphere(&mut self) -> Result<(), String> {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => {
+                    self.next();
+                }
+                Some(';') => {
+                    while let Some(c) = self.next() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                }
+                Some('#') if self.rest().starts_with("#|") => {
+                    let line = self.line;
+                    self.next();
+                    self.next();
+                    let mut depth = 1;
+                    while depth > 0 {
+                        if self.rest().starts_with("|#") {
+                            depth -= 1;
+                            self.next();
+                        } else if self.rest().starts_with("#|") {
+                            depth += 1;
+                            self.next();
+                        }
+                        if self.next().is_none() {
+                            return Err(format!("Unterminated block comment starting on line {}", line));
+                        }
+                    }
+                }
+                Some('#') if self.rest().starts_with("#;") => {
+                    self.next();
+                    self.next();
+                    self.read()?;
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn read(&mut self) -> Result<SchemeValue, String> {
+        if self.depth == MAX_NESTING {
+            return Err(format!("Nesting deeper than {} levels on line {}", MAX_NESTING, self.line));
+        }
+        self.depth += 1;
+        let datum = self.read_datum();
+        self.depth -= 1;
+        datum
+    }
+
+    fn read_datum(&mut self) -> Result<SchemeValue, String> {
+        self.skip_atmosphere()?;
+        let line = self.line;
+        match self.next() {
+            None => Err("Unexpected end of input".to_string()),
+            Some('(') | Some('[') => {
+                let items = self.read_list(line)?;
+                Ok(if items.is_empty() { SchemeValue::Nil } else { SchemeValue::List(items) })
+            }
+            Some(')') | Some(']') => Err(format!("Unexpected ')' on line {}", line)),
+            Some('\'') => self.read_abbreviation("quote"),
+            Some('`') => self.read_abbreviation("quasiquote"),
+            Some(',') => {
+                if self.peek() == Some('@') {
+                    self.next();
+                    self.read_abbreviation("unquote-splicing")
+                } else {
+                    self.read_abbreviation("unquote")
+                }
+            }
+            Some('"') => self.read_string(line),
+            Some('#') => {
+                if self.peek() == Some('(') {
+                    self.next();
+                    return Ok(SchemeValue::Vector(self.read_list(line)?));
+                }
+                match self.read_token().as_str() {
+                    "t" | "true" => Ok(SchemeValue::Boolean(true)),
+                    "f" | "false" => Ok(SchemeValue::Boolean(false)),
+                    other => Err(format!("Unknown syntax #{} on line {}", other, line)),
+                }
+            }
+            Some(c) => {
+                let mut token = c.to_string();
+                token.push_str(&self.read_token());
+                Ok(parse_atom(&token))
+            }
+        }
+    }
+
+    fn read_list(&mut self, line: usize) -> Result<Vec<SchemeValue>, String> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_atmosphere()?;
+            match self.peek() {
+                None => {
+                    return Err(format!("Unmatched parentheses: list opened on line {} is never closed", line));
+                }
+                Some(')') | Some(']') => {
+                    self.next();
+                    return Ok(items);
+                }
+                _ => items.push(self.read()?),
+            }
+        }
+    }
+
+    fn read_abbreviation(&mut self, name: &str) -> Result<SchemeValue, String> {
+        let datum = self.read()?;
+        Ok(SchemeValue::List(vec![SchemeValue::Symbol(name.to_string()), datum]))
+    }
+
+    fn read_string(&mut self, line: usize) -> Result<SchemeValue, String> {
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                None => return Err(format!("Unterminated string starting on line {}", line)),
+                Some('"') => return Ok(SchemeValue::String(s)),
+                Some('\\') => match self.next() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('r') => s.push('\r'),
+                    Some('0') => s.push('\0'),
+                    Some('x') => {
+                        let mut hex = String::new();
+                        loop {
+                            match self.next() {
+                                Some(';') => break,
+                                Some(c) if c.is_ascii_hexdigit() => hex.push(c),
+                                _ => return Err(format!("Malformed \\x escape in string on line {}", line)),
+                            }
+                        }
+                        let ch = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("Invalid \\x escape in string on line {}", line))?;
+                        s.push(ch);
+                    }
+                    Some(c) => s.push(c),
+                    None => return Err(format!("Unterminated string starting on line {}", line)),
+                },
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn read_token(&mut self) -> String {
+        let mut token = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || "()[]\";'`,".contains(c) {
+                break;
+            }
+            token.push(c);
+            self.next();
+        }
+        token
+    }
+}
+
+pub(crate) fn parse_atom(token: &str) -> SchemeValue {
+    // `f64::from_str` also accepts words like "inf" and "nan", which must stay symbols.
+    let unsigned = token.strip_prefix(['+', '-']).unwrap_or(token);
+    let unsigned = unsigned.strip_prefix('.').unwrap_or(unsigned);
+    if unsigned.starts_with(|c: char| c.is_ascii_digit()) {
+        if let Ok(n) = token.parse::<f64>() {
+            return SchemeValue::Number(n);
+        }
+    }
+    SchemeValue::Symbol(token.to_string())
+}
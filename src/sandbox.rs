@@ -0,0 +1,169 @@
+// Capability profiles: which groups of builtins an interpreter installs.
+//
+// Every builtin belongs to exactly one profile. An interpreter built with a
+// set of profiles removes every other builtin from its global frame, so a
+// script cannot reach a disallowed procedure under any name.
+// `(environment 'pure ...)` builds a further restricted environment for
+// `eval` from the same table.
+
+use crate::{Backend, InterpreterLimits, SchemeError, SchemeInterpreter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Profile {
+    /// Arithmetic, lists, vectors, predicates, control flow and macros.
+    /// Always installed.
+    Pure,
+    /// Strings, hash tables and conversions between them.
+    Data,
+    /// `current-second`, `current-jiffy` and `jiffies-per-second`.
+    Time,
+    /// `random`.
+    Random,
+    /// The request accessors and `make-response` for HTTP handlers; see
+    /// `lisp_compute::http`.
+    Request,
+    /// `log`, which writes to standard error, and the collector's `gc` and
+    /// `gc-stats`.
+    Logging,
+    /// Requests to backends. The host registers these procedures itself
+    /// when `SchemeInterpreter::allows` says so.
+    Fetch,
+}
+
+impl Profile {
+    pub const ALL: [Profile; 7] = [
+        Profile::Pure,
+        Profile::Data,
+        Profile::Time,
+        Profile::Random,
+        Profile::Request,
+        Profile::Logging,
+        Profile::Fetch,
+    ];
+
+    /// The symbol naming this profile in `(environment ...)`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Profile::Pure => "pure",
+            Profile::Data => "data",
+            Profile::Time => "time",
+            Profile::Random => "random",
+            Profile::Request => "request",
+            Profile::Logging => "logging",
+            Profile::Fetch => "fetch",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Profile> {
+        Profile::ALL.iter().copied().find(|p| p.name() == name)
+    }
+}
+
+/// The profile a builtin belongs to, or `None` if it is not listed here.
+/// `build` refuses to start with a builtin that has no profile, so a new
+/// builtin cannot end up installed everywhere by accident.
+pub(crate) fn builtin_profile(name: &str) -> Option<Profile> {
+    let profile = match name {
+        "*" | "+" | "-" | "/" | "<" | "<=" | "=" | ">" | ">=" | "abs" | "expt" | "sqrt" | "number?" | "boolean?"
+        | "string?" | "symbol?" | "procedure?" | "not" | "eq?" | "eqv?" | "equal?" | "nil" | "cons" | "car" | "cdr"
+        | "cadr" | "cddr" | "caddr" | "cdddr" | "cadddr" | "list" | "length" | "append" | "reverse" | "null?"
+        | "pair?" | "for-each" | "vector" | "vector-length" | "vector-ref" | "make-vector" | "values"
+        | "call-with-values" | "call/cc" | "call-with-current-continuation" | "call/ec"
+        | "call-with-escape-continuation" | "dynamic-wind" | "while" | "error" | "raise" | "raise-continuable"
+        | "with-exception-handler" | "error-object?" | "error-object-message" | "error-object-irritants" | "eval"
+        | "environment" | "interaction-environment" | "er-macro-transformer" | "macroexpand" | "macroexpand-1"
+        | "expand/optimize" | "gensym" | "force" | "make-promise" | "promise?" | "stream?" | "stream-null"
+        | "stream-null?" | "stream-pair?" | "stream-car" | "stream-cdr" | "stream-map" | "stream-filter"
+        | "stream-take" | "stream->list" | "list->stream" | "make-weak-box" | "weak-box?" | "weak-box-value"
+        | "make-ephemeron" | "ephemeron?" | "ephemeron-key" | "ephemeron-datum" | "ephemeron-broken?" => Profile::Pure,
+        // `newline` is not a builtin, but hosts that capture output define it next to `display`.
+        "display" | "newline" | "make-string" | "string-length" | "string-append" | "substring" | "string=?"
+        | "string<?" | "string-upcase" | "string-downcase" | "string->number" | "number->string" | "string->symbol"
+        | "symbol->string" | "make-hash-table" | "hash-set!" | "hash-ref" | "make-weak-hash-table" | "weak-hash-table?"
+        | "weak-hash-table-set!" | "weak-hash-table-ref" | "weak-hash-table-delete!" | "weak-hash-table-count"
+        | "string->json" | "json-read" | "json->string" | "json-write" | "url-decode" | "url-encode"
+        | "parse-query-string" | "parse-form-urlencoded" | "build-query-string" | "sxml->html"
+        | "render-template" => Profile::Data,
+        "current-second" | "current-jiffy" | "jiffies-per-second" => Profile::Time,
+        "random" => Profile::Random,
+        "request-method" | "request-path" | "request-query" | "request-header" | "request-body" | "make-response" => {
+            Profile::Request
+        }
+        "log" | "gc" | "gc-stats" => Profile::Logging,
+        _ => return None,
+    };
+    Some(profile)
+}
+
+/// Builds an interpreter with a chosen set of profiles, limits and backend.
+/// `SchemeInterpreter::builder()` starts with only `Profile::Pure`.
+#[derive(Clone, Debug)]
+pub struct SchemeInterpreterBuilder {
+    profiles: Vec<Profile>,
+    limits: InterpreterLimits,
+    backend: Backend,
+    optimizer: bool,
+}
+
+impl Default for SchemeInterpreterBuilder {
+    fn default() -> Self {
+        SchemeInterpreterBuilder {
+            profiles: Vec::new(),
+            limits: InterpreterLimits::default(),
+            backend: Backend::default(),
+            optimizer: true,
+        }
+    }
+}
+
+impl SchemeInterpreterBuilder {
+    pub fn with_profile(mut self, profile: Profile) -> Self {
+        if !self.profiles.contains(&profile) {
+            self.profiles.push(profile);
+        }
+        self
+    }
+
+    pub fn with_profiles(self, profiles: impl IntoIterator<Item = Profile>) -> Self {
+        profiles.into_iter().fold(self, Self::with_profile)
+    }
+
+    pub fn with_limits(mut self, limits: InterpreterLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// `Backend::Bytecode` unless set otherwise.
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Whether expanded code is optimized before it runs. On unless
+    /// turned off here.
+    pub fn with_optimizer(mut self, enabled: bool) -> Self {
+        self.optimizer = enabled;
+        self
+    }
+
+    pub fn build(self) -> SchemeInterpreter {
+        let builder = self.with_profile(Profile::Pure);
+        SchemeInterpreter::build(builder.profiles, builder.limits, builder.backend, builder.optimizer)
+    }
+
+    /// Builds the interpreter from a snapshot taken with
+    /// `SchemeInterpreter::snapshot` instead of loading the prelude. Fails
+    /// unless this builder's profiles give the builtins the snapshot was
+    /// taken with.
+    pub fn restore(self, snapshot: &[u8]) -> Result<SchemeInterpreter, SchemeError> {
+        let builder = self.with_profile(Profile::Pure);
+        let mut interpreter = SchemeInterpreter::build_natives(
+            builder.profiles,
+            builder.limits,
+            builder.backend,
+            builder.optimizer,
+        );
+        interpreter.load_snapshot(snapshot)?;
+        Ok(interpreter)
+    }
+}
@@ -0,0 +1,357 @@
+//! Routing for the Compute service, and its `POST /eval` endpoint.
+//!
+//! These live here rather than in `main.rs` so that they can be tested
+//! without the `fastly` crate; `main.rs` only moves the body and status in
+//! and out of `fastly` types.
+//!
+//! | Path               | Methods       | Answer                              |
+//! |--------------------|---------------|-------------------------------------|
+//! | `/`                | `GET`, `HEAD` | the demo page running every example |
+//! | `/examples`        | `GET`, `HEAD` | the bundled examples, as JSON       |
+//! | `/examples/<name>` | `GET`, `HEAD` | the output of one example           |
+//! | `/eval`            | `POST`        | the result of the submitted code    |
+//! | `/healthz`         | `GET`, `HEAD` | `ok`                                |
+//!
+//! Every route also answers `OPTIONS` with its methods, and other methods
+//! get `405`. Other paths go to the Scheme handler in `handler.scm` (see
+//! `lisp_compute::http`), which answers `404` by returning `#f`.
+//!
+//! The `/eval` body is either the program itself or a JSON object
+//! `{"code": "...", "limits": {...}}`. It is JSON when the content type is
+//! `application/json` or the body starts with `{`, which no program does.
+//! `limits` may lower any of `fuel`, `max_allocated_bytes`,
+//! `max_list_length`, `max_string_length`, `max_hash_table_size` and
+//! `max_depth` below the defaults in `default_limits`, but never raise them.
+//!
+//! The program runs in a fresh interpreter with the `Pure` and `Data`
+//! profiles. `display` and `newline` write to the `output` of the reply
+//! instead of returning their text alone. The reply is always JSON:
+//!
+//! ```text
+//! {"ok": true, "value": "55", "output": "...",
+//!  "usage": {"steps": 1234, "allocated_bytes": 0, "output_bytes": 3, "elapsed_ms": 0.4},
+//!  "limits": {"fuel": 1000000, ...}}
+//! ```
+//!
+//! A failed program has `"ok": false`, `"value": null` and an `error` with
+//! `kind`, `message`, and the `line` and `column` (counting from 1) of the
+//! form that failed or of the syntax error. Kinds are `syntax`, `error`,
+//! `raise`, `resource-limit`, `interrupted` and `request`.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+
+use crate::json::{self, Objects, WriteOptions};
+use crate::{reader, InterpreterLimits, Profile, SchemeError, SchemeInterpreter, SchemeValue};
+
+/// What a request path is for. Trailing slashes are ignored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Route<'a> {
+    Demo,
+    Examples,
+    /// `/examples/<name>`. Whether an example of that name exists is up to
+    /// the caller.
+    Example(&'a str),
+    Eval,
+    Health,
+    /// No built-in route; left to the Scheme handler.
+    NotFound,
+}
+
+impl<'a> Route<'a> {
+    pub fn for_path(path: &'a str) -> Route<'a> {
+        let trimmed = path.trim_end_matches('/');
+        match trimmed {
+            "" => Route::Demo,
+            "/examples" => Route::Examples,
+            "/eval" => Route::Eval,
+            "/healthz" => Route::Health,
+            _ => match trimmed.strip_prefix("/examples/") {
+                Some(name) if !name.contains('/') => Route::Example(name),
+                _ => Route::NotFound,
+            },
+        }
+    }
+
+    /// The methods this route answers, for the `Allow` header. Empty for
+    /// `NotFound`.
+    pub fn methods(&self) -> &'static [&'static str] {
+        match self {
+            Route::Eval => &["POST", "OPTIONS"],
+            Route::NotFound => &[],
+            _ => &["GET", "HEAD", "OPTIONS"],
+        }
+    }
+
+    pub fn allows(&self, method: &str) -> bool {
+        self.methods().contains(&method)
+    }
+}
+
+/// The largest body `/eval` accepts.
+pub const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Evaluation steps and procedure calls a program gets unless the request
+/// asks for fewer.
+pub const DEFAULT_FUEL: u64 = 1_000_000;
+
+/// Most bytes `display` and `newline` may write.
+pub const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
+/// The limits a program runs under unless the request lowers them.
+pub fn default_limits() -> InterpreterLimits {
+    InterpreterLimits {
+        max_allocated_bytes: Some(16 * 1024 * 1024),
+        max_list_length: Some(100_000),
+        max_string_length: Some(1024 * 1024),
+        max_hash_table_size: Some(100_000),
+        max_depth: Some(1_000),
+    }
+}
+
+/// A JSON reply: the status code and the body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reply {
+    pub status: u16,
+    pub body: String,
+}
+
+/// A parsed `/eval` request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EvalRequest {
+    pub code: String,
+    pub fuel: u64,
+    pub limits: InterpreterLimits,
+}
+
+impl EvalRequest {
+    /// Parses a body as described in the module documentation.
+    pub fn parse(body: &str, content_type: Option<&str>) -> Result<EvalRequest, String> {
+        let is_json = content_type.is_some_and(|t| t.trim().starts_with("application/json"))
+            || body.trim_start().starts_with('{');
+        let mut request = EvalRequest { code: body.to_string(), fuel: DEFAULT_FUEL, limits: default_limits() };
+        if !is_json {
+            return Ok(request);
+        }
+        let fields = match json::read(body, Objects::Alists).map_err(|e| e.to_string())? {
+            SchemeValue::List(fields) => fields,
+            SchemeValue::Nil => Vec::new(),
+            _ => return Err("expected a JSON object with a \"code\" field".to_string()),
+        };
+        let mut code = None;
+        for (key, value) in fields.iter().map(entry) {
+            match (key, value) {
+                ("code", SchemeValue::String(s)) => code = Some(s.clone()),
+                ("code", _) => return Err("\"code\" must be a string".to_string()),
+                ("limits", SchemeValue::List(limits)) => {
+                    for (name, value) in limits.iter().map(entry) {
+                        request.lower_limit(name, value)?;
+                    }
+                }
+                ("limits", SchemeValue::Nil) => (),
+                ("limits", _) => return Err("\"limits\" must be an object".to_string()),
+                (other, _) => return Err(format!("unknown field \"{}\"", other)),
+            }
+        }
+        request.code = code.ok_or("missing \"code\" field")?;
+        Ok(request)
+    }
+
+    fn lower_limit(&mut self, name: &str, value: &SchemeValue) -> Result<(), String> {
+        let n = match value {
+            SchemeValue::Number(n) if *n >= 0.0 && n.fract() == 0.0 => *n,
+            _ => return Err(format!("limit \"{}\" must be a non-negative integer", name)),
+        };
+        let lower = |limit: &mut Option<usize>| *limit = Some(limit.map_or(n as usize, |max| max.min(n as usize)));
+        match name {
+            "fuel" => self.fuel = self.fuel.min(n as u64),
+            "max_allocated_bytes" => lower(&mut self.limits.max_allocated_bytes),
+            "max_list_length" => lower(&mut self.limits.max_list_length),
+            "max_string_length" => lower(&mut self.limits.max_string_length),
+            "max_hash_table_size" => lower(&mut self.limits.max_hash_table_size),
+            "max_depth" => lower(&mut self.limits.max_depth),
+            other => return Err(format!("unknown limit \"{}\"", other)),
+        }
+        Ok(())
+    }
+}
+
+/// Answers `POST /eval` with `body`. Evaluation stops at `deadline`, in
+/// which case the reply is `503`.
+pub fn eval(body: &[u8], content_type: Option<&str>, deadline: Option<Instant>) -> Reply {
+    if body.len() > MAX_BODY_BYTES {
+        return request_error(413, format!("body is over the limit of {} bytes", MAX_BODY_BYTES));
+    }
+    let body = match std::str::from_utf8(body) {
+        Ok(body) => body,
+        Err(_) => return request_error(400, "body is not valid UTF-8".to_string()),
+    };
+    match EvalRequest::parse(body, content_type) {
+        Ok(request) => run(&request, deadline),
+        Err(message) => request_error(400, message),
+    }
+}
+
+/// Runs a parsed request in a fresh sandboxed interpreter.
+pub fn run(request: &EvalRequest, deadline: Option<Instant>) -> Reply {
+    let started = Instant::now();
+    let interpreter =
+        SchemeInterpreter::builder().with_profile(Profile::Data).with_limits(request.limits.clone()).build();
+    let output = capture_output(&interpreter);
+    interpreter.set_fuel(Some(request.fuel));
+    interpreter.set_deadline(deadline);
+
+    let result = match reader::read_forms(&request.code) {
+        Ok(forms) => evaluate(&interpreter, &forms),
+        Err(e) => Err(Failure { kind: "syntax", message: e.message, line: e.line, column: e.column }),
+    };
+
+    let output = output.borrow();
+    let usage = object(vec![
+        ("steps", number(interpreter.fuel_consumed() as f64)),
+        ("allocated_bytes", number(interpreter.allocated_bytes() as f64)),
+        ("output_bytes", number(output.len() as f64)),
+        ("elapsed_ms", number((started.elapsed().as_secs_f64() * 1e6).round() / 1e3)),
+    ]);
+    let limits = &request.limits;
+    let limit = |limit: Option<usize>| limit.map_or_else(null, |n| number(n as f64));
+    let limits = object(vec![
+        ("fuel", number(request.fuel as f64)),
+        ("max_allocated_bytes", limit(limits.max_allocated_bytes)),
+        ("max_list_length", limit(limits.max_list_length)),
+        ("max_string_length", limit(limits.max_string_length)),
+        ("max_hash_table_size", limit(limits.max_hash_table_size)),
+        ("max_depth", limit(limits.max_depth)),
+    ]);
+    let output = SchemeValue::String(output.clone());
+    match result {
+        Ok(value) => reply(
+            200,
+            vec![
+                ("ok", SchemeValue::Boolean(true)),
+                ("value", value.map_or_else(null, |v| SchemeValue::String(v.to_string()))),
+                ("output", output),
+                ("usage", usage),
+                ("limits", limits),
+            ],
+        ),
+        Err(failure) => reply(
+            if failure.kind == "interrupted" { 503 } else { 200 },
+            vec![
+                ("ok", SchemeValue::Boolean(false)),
+                ("value", null()),
+                ("output", output),
+                ("error", failure.to_json()),
+                ("usage", usage),
+                ("limits", limits),
+            ],
+        ),
+    }
+}
+
+/// Why a program stopped, with a position counted from 0.
+struct Failure {
+    kind: &'static str,
+    message: String,
+    line: usize,
+    column: usize,
+}
+
+impl Failure {
+    fn to_json(&self) -> SchemeValue {
+        object(vec![
+            ("kind", SchemeValue::String(self.kind.to_string())),
+            ("message", SchemeValue::String(self.message.clone())),
+            ("line", number((self.line + 1) as f64)),
+            ("column", number((self.column + 1) as f64)),
+        ])
+    }
+}
+
+/// Evaluates `forms` in order, returning the value of the last one, or
+/// `None` if there are none.
+fn evaluate(interpreter: &SchemeInterpreter, forms: &[reader::Form]) -> Result<Option<SchemeValue>, Failure> {
+    let failure = |e: SchemeError, line, column| Failure {
+        kind: match e {
+            SchemeError::Raise(SchemeValue::ErrorObject(_)) | SchemeError::Error(_) | SchemeError::Escape { .. } => {
+                "error"
+            }
+            SchemeError::Raise(_) => "raise",
+            SchemeError::ResourceLimit(_) | SchemeError::MemoryLimit(_) => "resource-limit",
+            SchemeError::Interrupted(_) => "interrupted",
+        },
+        message: e.to_string(),
+        line,
+        column,
+    };
+    interpreter.start_run().map_err(|e| failure(e, 0, 0))?;
+    let env = interpreter.toplevel.borrow().clone();
+    for form in forms {
+        interpreter.note_assignments(&form.datum);
+    }
+    let mut result = None;
+    for form in forms {
+        let value = interpreter.eval_toplevel(&form.datum, &env).map_err(|e| failure(e, form.line, form.column))?;
+        result = Some(value);
+    }
+    Ok(result)
+}
+
+/// Replaces `display` and `newline` with versions that also append to the
+/// returned buffer.
+fn capture_output(interpreter: &SchemeInterpreter) -> Rc<RefCell<String>> {
+    let output = Rc::new(RefCell::new(String::new()));
+    let write = |output: &RefCell<String>, text: String| {
+        let mut output = output.borrow_mut();
+        if output.len() + text.len() > MAX_OUTPUT_BYTES {
+            return Err(SchemeError::ResourceLimit(format!("more than {} bytes of output", MAX_OUTPUT_BYTES)));
+        }
+        output.push_str(&text);
+        Ok(SchemeValue::String(text))
+    };
+    let buffer = output.clone();
+    interpreter.define_native("display", 1, move |_ctx, args| write(&buffer, args[0].to_string()));
+    let buffer = output.clone();
+    interpreter.define_native("newline", 0, move |_ctx, _args| write(&buffer, "\n".to_string()));
+    output
+}
+
+fn request_error(status: u16, message: String) -> Reply {
+    let error = object(vec![
+        ("kind", SchemeValue::String("request".to_string())),
+        ("message", SchemeValue::String(message)),
+    ]);
+    reply(status, vec![("ok", SchemeValue::Boolean(false)), ("error", error)])
+}
+
+fn reply(status: u16, fields: Vec<(&str, SchemeValue)>) -> Reply {
+    let body = json::write(&object(fields), WriteOptions::default()).expect("replies are representable as JSON");
+    Reply { status, body: body + "\n" }
+}
+
+/// An alist that `json::write` writes as an object, in order.
+fn object(fields: Vec<(&str, SchemeValue)>) -> SchemeValue {
+    SchemeValue::List(
+        fields.into_iter().map(|(key, value)| SchemeValue::List(vec![SchemeValue::String(key.to_string()), value])).collect(),
+    )
+}
+
+/// The key and value of an entry read by `json::read` with `Objects::Alists`.
+fn entry(field: &SchemeValue) -> (&str, &SchemeValue) {
+    match field {
+        SchemeValue::List(pair) => match (&pair[0], &pair[1]) {
+            (SchemeValue::String(key), value) => (key, value),
+            _ => unreachable!("JSON object keys are strings"),
+        },
+        _ => unreachable!("JSON objects read as alists of pairs"),
+    }
+}
+
+fn number(n: f64) -> SchemeValue {
+    SchemeValue::Number(n)
+}
+
+fn null() -> SchemeValue {
+    SchemeValue::Symbol("null".to_string())
+}
@@ -0,0 +1,631 @@
+// Snapshots of an interpreter's state, so a request can start from an
+// interpreter that has already loaded its libraries.
+//
+// A snapshot holds the top-level frame and the prelude's definitions, with
+// every frame, procedure and object they reach. Builtins written in Rust
+// are recorded by name and looked up again on restore, which therefore
+// skips the prelude and fails unless the new interpreter has exactly the
+// same builtins. Procedures registered with `define_native` capture Rust
+// state and cannot be saved: a snapshot that reaches one fails, and the
+// host registers them again after restoring. Continuations are rejected
+// too, and a weak reference whose target nothing else reaches comes back
+// broken.
+//
+// Objects are written in an order where each comes after everything it is
+// built from, visiting bindings and hash table entries by name so the same
+// state always gives the same bytes. What can change after an object is built, such as the
+// bindings of a frame, follows in a second pass, so cycles through frames
+// and tables come out as they went in.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use crate::compile::Code;
+use crate::gc::Tracked;
+use crate::image::{identity, Decoder, Encoder};
+use crate::vm::{Closure, Frame};
+use crate::weak::{Ephemeron, WeakBox, WeakRef, WeakTable};
+use crate::{
+    Backend, Env, Environment, ErrorObject, Lambda, Macro, MacroKind, Profile, Promise, PromiseState, SchemeError,
+    SchemeInterpreter, SchemeValue,
+};
+
+const MAGIC: &[u8; 8] = b"LISPSNAP";
+const VERSION: u32 = 1;
+
+/// An object in the snapshot, in the order they are written.
+#[derive(Clone)]
+enum Entry {
+    /// The builtins frame, whose Rust builtins are not written.
+    Global,
+    Toplevel,
+    /// A builtin, by name.
+    Builtin(String),
+    Frame(Env),
+    VmFrame(Rc<Frame>),
+    Code(Rc<Code>),
+    Lambda(Rc<Lambda>),
+    Closure(Rc<Closure>),
+    Macro(Rc<Macro>),
+    Error(Rc<ErrorObject>),
+    Promise(Rc<Promise>),
+    WeakBox(Rc<WeakBox>),
+    WeakTable(Rc<WeakTable>),
+    Ephemeron(Rc<Ephemeron>),
+}
+
+fn address<T: ?Sized>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const () as usize
+}
+
+fn is_rust_procedure(value: &SchemeValue) -> bool {
+    matches!(value, SchemeValue::Function(_) | SchemeValue::Native(_) | SchemeValue::Primitive(_))
+}
+
+/// Identifies the set of builtins written in Rust, whatever order they are
+/// stored in. Natives the host added with `define_native` do not count.
+fn builtins_fingerprint(interpreter: &SchemeInterpreter) -> u64 {
+    let global = interpreter.global.borrow();
+    (global.vars.iter())
+        .filter(|(name, value)| is_rust_procedure(value) && interpreter.builtins.contains_key(*name))
+        .map(|(name, _)| {
+            // FNV-1a, which unlike the standard hasher is the same in every build.
+            name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3))
+        })
+        .fold(0, u64::wrapping_add)
+}
+
+fn sorted_vars(env: &Environment) -> Vec<(&String, &SchemeValue)> {
+    let mut vars: Vec<_> = env.vars.iter().collect();
+    vars.sort_by(|a, b| a.0.cmp(b.0));
+    vars
+}
+
+struct Writer<'a> {
+    interpreter: &'a SchemeInterpreter,
+    /// Rust builtins by identity, with the name to find them again.
+    builtins: HashMap<usize, String>,
+    ids: HashMap<usize, u32>,
+    entries: Vec<Entry>,
+}
+
+impl Writer<'_> {
+    fn add(&mut self, address: usize, entry: Entry) {
+        self.ids.insert(address, self.entries.len() as u32);
+        self.entries.push(entry);
+    }
+
+    fn value(&mut self, value: &SchemeValue) -> Result<(), SchemeError> {
+        match value {
+            SchemeValue::List(items) | SchemeValue::Vector(items) | SchemeValue::Values(items) => {
+                items.iter().try_for_each(|item| self.value(item))
+            }
+            SchemeValue::HashTable(table) => {
+                let mut keys: Vec<&String> = table.keys().collect();
+                keys.sort();
+                keys.into_iter().try_for_each(|key| self.value(&table[key]))
+            }
+            other => match identity(other) {
+                Some(address) if !self.ids.contains_key(&address) => self.object(address, other),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    fn object(&mut self, address: usize, value: &SchemeValue) -> Result<(), SchemeError> {
+        if let Some(name) = self.builtins.get(&address).cloned() {
+            self.add(address, Entry::Builtin(name));
+            return Ok(());
+        }
+        match value {
+            SchemeValue::Native(native) => {
+                return Err(format!("Cannot snapshot the native procedure {}", native.name).into());
+            }
+            SchemeValue::Function(_)
+            | SchemeValue::Continuation(_)
+            | SchemeValue::Request(_)
+            | SchemeValue::Response(_) => {
+                return Err(format!("Cannot snapshot {}", value).into());
+            }
+            SchemeValue::Environment(env) => self.frame(env),
+            SchemeValue::Lambda(lambda) => {
+                self.frame(&lambda.env);
+                lambda.body.iter().try_for_each(|form| self.value(form))?;
+                self.add(address, Entry::Lambda(lambda.clone()));
+            }
+            SchemeValue::Closure(closure) => {
+                self.code(&closure.code)?;
+                if let Some(frame) = &closure.frame {
+                    self.vm_frame(frame);
+                }
+                self.frame(&closure.globals);
+                self.add(address, Entry::Closure(closure.clone()));
+            }
+            SchemeValue::Macro(mac) => {
+                self.value(&mac.transformer)?;
+                self.add(address, Entry::Macro(mac.clone()));
+            }
+            SchemeValue::ErrorObject(error) => {
+                error.irritants.iter().try_for_each(|item| self.value(item))?;
+                self.add(address, Entry::Error(error.clone()));
+            }
+            SchemeValue::Promise(promise) => self.add(address, Entry::Promise(promise.clone())),
+            SchemeValue::WeakBox(weak_box) => {
+                if let Some(target) = weak_box.target.upgrade() {
+                    self.value(&target)?;
+                }
+                self.add(address, Entry::WeakBox(weak_box.clone()));
+            }
+            SchemeValue::WeakTable(table) => self.add(address, Entry::WeakTable(table.clone())),
+            SchemeValue::Ephemeron(ephemeron) => {
+                if let Some(key) = ephemeron.key.upgrade() {
+                    self.value(&key)?;
+                }
+                self.add(address, Entry::Ephemeron(ephemeron.clone()));
+            }
+            _ => unreachable!("only values with identity are objects"),
+        }
+        Ok(())
+    }
+
+    fn frame(&mut self, env: &Env) {
+        if !self.ids.contains_key(&address(env)) {
+            self.add(address(env), Entry::Frame(env.clone()));
+        }
+    }
+
+    fn vm_frame(&mut self, frame: &Rc<Frame>) {
+        if self.ids.contains_key(&address(frame)) {
+            return;
+        }
+        if let Some(parent) = &frame.parent {
+            self.vm_frame(parent);
+        }
+        self.add(address(frame), Entry::VmFrame(frame.clone()));
+    }
+
+    fn code(&mut self, code: &Rc<Code>) -> Result<(), SchemeError> {
+        if self.ids.contains_key(&address(code)) {
+            return Ok(());
+        }
+        fn constants(writer: &mut Writer, code: &Code) -> Result<(), SchemeError> {
+            code.constants.iter().try_for_each(|value| writer.value(value))?;
+            code.functions.iter().try_for_each(|function| constants(writer, function))
+        }
+        constants(self, code)?;
+        self.add(address(code), Entry::Code(code.clone()));
+        Ok(())
+    }
+
+    /// Visits what an entry holds besides what it was built from.
+    fn contents(&mut self, id: usize) -> Result<(), SchemeError> {
+        match self.entries[id].clone() {
+            Entry::Global => {
+                let global = self.interpreter.global.borrow();
+                (sorted_vars(&global).into_iter())
+                    .filter(|(_, value)| !is_rust_procedure(value))
+                    .try_for_each(|(_, value)| self.value(value))
+            }
+            Entry::Toplevel => self.frame_contents(&self.interpreter.toplevel.borrow().clone()),
+            Entry::Frame(env) => self.frame_contents(&env),
+            Entry::VmFrame(frame) => frame.slots.borrow().iter().flatten().try_for_each(|value| self.value(value)),
+            Entry::Promise(promise) => match promise.state.borrow().borrow().clone() {
+                PromiseState::Done(value) | PromiseState::Delay(value) | PromiseState::DelayForce(value) => {
+                    self.value(&value)
+                }
+            },
+            Entry::WeakTable(table) => {
+                let entries: Vec<_> = table.entries.borrow().values().cloned().collect();
+                for (key, value) in entries {
+                    if let Some(key) = key.upgrade() {
+                        self.value(&key)?;
+                        self.value(&value)?;
+                    }
+                }
+                Ok(())
+            }
+            Entry::Ephemeron(ephemeron) => match ephemeron.datum.borrow().clone() {
+                Some(datum) if ephemeron.key.is_alive() => self.value(&datum),
+                _ => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn frame_contents(&mut self, env: &Env) -> Result<(), SchemeError> {
+        let env = env.borrow();
+        if let Some(parent) = &env.parent {
+            self.frame(parent);
+        }
+        sorted_vars(&env).into_iter().try_for_each(|(_, value)| self.value(value))
+    }
+}
+
+impl SchemeInterpreter {
+    /// Saves the interpreter's definitions, and everything they reach, for
+    /// `restore`. Fails if they reach a procedure registered with
+    /// `define_native` or a continuation.
+    pub fn snapshot(&self) -> Result<Vec<u8>, SchemeError> {
+        let mut builtins = HashMap::new();
+        for (name, value) in self.global.borrow().vars.iter() {
+            if is_rust_procedure(value) && self.builtins.contains_key(name) {
+                if let Some(address) = identity(value) {
+                    // Aliases such as `call/cc` share one object; any name will do,
+                    // but pick the same one every time.
+                    let entry = builtins.entry(address).or_insert_with(|| name.clone());
+                    if name < entry {
+                        *entry = name.clone();
+                    }
+                }
+            }
+        }
+        let mut writer = Writer { interpreter: self, builtins, ids: HashMap::new(), entries: Vec::new() };
+        writer.add(address(&self.global), Entry::Global);
+        writer.add(address(&self.toplevel.borrow()), Entry::Toplevel);
+        // Visiting contents adds entries, which are visited in turn.
+        let mut next = 0;
+        while next < writer.entries.len() {
+            writer.contents(next)?;
+            next += 1;
+        }
+
+        let mut out = Encoder::default();
+        out.bytes.extend_from_slice(MAGIC);
+        out.u32(VERSION);
+        out.u8(match self.backend {
+            Backend::Bytecode => 0,
+            Backend::TreeWalker => 1,
+        });
+        out.u32(self.profiles.len() as u32);
+        for profile in &self.profiles {
+            out.str(profile.name());
+        }
+        out.u64(builtins_fingerprint(self));
+        out.u64(self.gensym_counter.get() as u64);
+
+        let Writer { entries, ids, .. } = writer;
+        out.objects = ids;
+        let id = |out: &Encoder, rc: usize| out.objects[&rc];
+        out.u32(entries.len() as u32);
+        for entry in &entries {
+            match entry {
+                Entry::Global => out.u8(0),
+                Entry::Toplevel => out.u8(1),
+                Entry::Builtin(name) => {
+                    out.u8(2);
+                    out.str(name);
+                }
+                Entry::Frame(_) => out.u8(3),
+                Entry::VmFrame(frame) => {
+                    out.u8(4);
+                    let parent = frame.parent.as_ref().map_or(u32::MAX, |parent| id(&out, address(parent)));
+                    out.u32(parent);
+                }
+                Entry::Code(code) => {
+                    out.u8(5);
+                    out.code(code)?;
+                }
+                Entry::Lambda(lambda) => {
+                    out.u8(6);
+                    out.u32(lambda.params.len() as u32);
+                    lambda.params.iter().for_each(|param| out.str(param));
+                    out.u8(lambda.rest.is_some() as u8);
+                    lambda.rest.iter().for_each(|rest| out.str(rest));
+                    out.values(&lambda.body)?;
+                    out.u32(id(&out, address(&lambda.env)));
+                }
+                Entry::Closure(closure) => {
+                    out.u8(7);
+                    out.u32(id(&out, address(&closure.code)));
+                    let frame = closure.frame.as_ref().map_or(u32::MAX, |frame| id(&out, address(frame)));
+                    out.u32(frame);
+                    out.u32(id(&out, address(&closure.globals)));
+                }
+                Entry::Macro(mac) => {
+                    out.u8(8);
+                    out.u8(match mac.kind {
+                        MacroKind::DefineMacro => 0,
+                        MacroKind::ExplicitRenaming => 1,
+                    });
+                    out.value(&mac.transformer)?;
+                }
+                Entry::Error(error) => {
+                    out.u8(9);
+                    out.str(&error.message);
+                    out.values(&error.irritants)?;
+                }
+                Entry::Promise(_) => out.u8(10),
+                Entry::WeakBox(weak_box) => {
+                    out.u8(11);
+                    weak_target(&mut out, &weak_box.target)?;
+                }
+                Entry::WeakTable(_) => out.u8(12),
+                Entry::Ephemeron(ephemeron) => {
+                    out.u8(13);
+                    weak_target(&mut out, &ephemeron.key)?;
+                }
+            }
+        }
+
+        for entry in &entries {
+            match entry {
+                Entry::Global => {
+                    let global = self.global.borrow();
+                    let vars: Vec<_> = sorted_vars(&global).into_iter().filter(|(_, value)| !is_rust_procedure(value)).collect();
+                    out.u32(vars.len() as u32);
+                    for (name, value) in vars {
+                        out.str(name);
+                        out.value(value)?;
+                    }
+                }
+                Entry::Toplevel | Entry::Frame(_) => {
+                    let env = match entry {
+                        Entry::Frame(env) => env.clone(),
+                        _ => self.toplevel.borrow().clone(),
+                    };
+                    let env = env.borrow();
+                    let parent = env.parent.as_ref().map_or(u32::MAX, |parent| id(&out, address(parent)));
+                    out.u32(parent);
+                    let vars = sorted_vars(&env);
+                    out.u32(vars.len() as u32);
+                    for (name, value) in vars {
+                        out.str(name);
+                        out.value(value)?;
+                    }
+                }
+                Entry::VmFrame(frame) => {
+                    let slots = frame.slots.borrow();
+                    out.u32(slots.len() as u32);
+                    for slot in slots.iter() {
+                        out.u8(slot.is_some() as u8);
+                        slot.iter().try_for_each(|value| out.value(value))?;
+                    }
+                }
+                Entry::Promise(promise) => {
+                    let state = promise.state.borrow().borrow().clone();
+                    let (tag, value) = match state {
+                        PromiseState::Done(value) => (0, value),
+                        PromiseState::Delay(value) => (1, value),
+                        PromiseState::DelayForce(value) => (2, value),
+                    };
+                    out.u8(tag);
+                    out.value(&value)?;
+                }
+                Entry::WeakTable(table) => {
+                    let mut live: Vec<(u32, SchemeValue, SchemeValue)> = (table.entries.borrow().values())
+                        .filter_map(|(key, value)| key.upgrade().map(|key| (id(&out, identity(&key).unwrap()), key, value.clone())))
+                        .collect();
+                    live.sort_by_key(|entry| entry.0);
+                    out.u32(live.len() as u32);
+                    for (_, key, value) in live {
+                        out.value(&key)?;
+                        out.value(&value)?;
+                    }
+                }
+                Entry::Ephemeron(ephemeron) => {
+                    let datum = ephemeron.datum.borrow().clone().filter(|_| ephemeron.key.is_alive());
+                    out.u8(datum.is_some() as u8);
+                    datum.iter().try_for_each(|value| out.value(value))?;
+                }
+                _ => {}
+            }
+        }
+        Ok(out.bytes)
+    }
+
+    /// Recreates an interpreter from `snapshot`, with the profiles and
+    /// backend it was taken with and default limits. To choose the limits,
+    /// use `SchemeInterpreterBuilder::restore`.
+    pub fn restore(snapshot: &[u8]) -> Result<Self, SchemeError> {
+        let mut input = Decoder::new(snapshot, "snapshot");
+        let (backend, profiles) = header(&mut input)?;
+        Self::builder().with_profiles(profiles).with_backend(backend).restore(snapshot)
+    }
+
+    /// Loads `snapshot` into an interpreter built without the prelude.
+    pub(crate) fn load_snapshot(&mut self, snapshot: &[u8]) -> Result<(), SchemeError> {
+        let mut input = Decoder::new(snapshot, "snapshot");
+        header(&mut input)?;
+        if input.u64()? != builtins_fingerprint(self) {
+            return Err("Cannot restore snapshot: it was taken with a different set of builtins".into());
+        }
+        let gensyms = input.u64()? as usize;
+        self.gensym_counter.set(self.gensym_counter.get().max(gensyms));
+
+        let count = input.index()?;
+        let mut kinds = Vec::new();
+        let mut codes: HashMap<usize, Rc<Code>> = HashMap::new();
+        let mut frames: HashMap<usize, Rc<Frame>> = HashMap::new();
+        for index in 0..count {
+            let kind = input.u8()?;
+            let object = match kind {
+                0 => SchemeValue::Environment(self.global.clone()),
+                1 => SchemeValue::Environment(self.toplevel.borrow().clone()),
+                2 => {
+                    let name = input.str()?;
+                    let value = self.global.borrow().vars.get(&name).cloned();
+                    value.ok_or_else(|| input.error(&format!("unknown builtin {}", name)))?
+                }
+                3 => SchemeValue::Environment(self.track_frame(Rc::new(RefCell::new(Environment { vars: HashMap::new(), parent: None })))),
+                4 => {
+                    let parent = optional(&mut input)?.map(|i| frames.get(&i).cloned().ok_or_else(|| input.error("bad frame"))).transpose()?;
+                    let frame = Rc::new(Frame { slots: RefCell::new(Vec::new()), parent });
+                    self.track(Tracked::VmFrame(Rc::downgrade(&frame)));
+                    frames.insert(index, frame);
+                    SchemeValue::Nil
+                }
+                5 => {
+                    codes.insert(index, Rc::new(input.code()?));
+                    SchemeValue::Nil
+                }
+                6 => {
+                    let params = (0..input.u32()?).map(|_| input.str()).collect::<Result<_, _>>()?;
+                    let rest = if input.u8()? != 0 { Some(input.str()?) } else { None };
+                    let body = input.values()?;
+                    let env = frame_ref(&mut input)?;
+                    let lambda = Rc::new(Lambda { params, rest, body, env });
+                    self.track(Tracked::Closure(Rc::downgrade(&lambda)));
+                    SchemeValue::Lambda(lambda)
+                }
+                7 => {
+                    let code = input.index()?;
+                    let code = codes.get(&code).cloned().ok_or_else(|| input.error("bad code"))?;
+                    let frame = optional(&mut input)?.map(|i| frames.get(&i).cloned().ok_or_else(|| input.error("bad frame"))).transpose()?;
+                    let globals = frame_ref(&mut input)?;
+                    let closure = Rc::new(Closure { code, frame, globals });
+                    self.track(Tracked::VmClosure(Rc::downgrade(&closure)));
+                    SchemeValue::Closure(closure)
+                }
+                8 => {
+                    let kind = if input.u8()? == 0 { MacroKind::DefineMacro } else { MacroKind::ExplicitRenaming };
+                    SchemeValue::Macro(Rc::new(Macro { kind, transformer: input.value()? }))
+                }
+                9 => {
+                    let message = input.str()?;
+                    SchemeValue::error_object(message, input.values()?)
+                }
+                10 => Promise::make(PromiseState::Done(SchemeValue::Nil)),
+                11 => {
+                    let target = weak_ref(&mut input)?;
+                    SchemeValue::WeakBox(Rc::new(WeakBox { target }))
+                }
+                12 => {
+                    let table = Rc::new(WeakTable::default());
+                    self.track(Tracked::Table(Rc::downgrade(&table)));
+                    SchemeValue::WeakTable(table)
+                }
+                13 => {
+                    let key = weak_ref(&mut input)?;
+                    let ephemeron = Rc::new(Ephemeron { key, datum: RefCell::new(None) });
+                    self.track(Tracked::Ephemeron(Rc::downgrade(&ephemeron)));
+                    SchemeValue::Ephemeron(ephemeron)
+                }
+                tag => return Err(input.error(&format!("unknown object tag {}", tag))),
+            };
+            kinds.push(kind);
+            input.objects.push(object);
+        }
+
+        for (index, &kind) in kinds.iter().enumerate() {
+            let object = input.objects[index].clone();
+            match (kind, object) {
+                (0, SchemeValue::Environment(env)) => {
+                    for _ in 0..input.u32()? {
+                        let name = input.str()?;
+                        let value = input.value()?;
+                        env.borrow_mut().define(&name, value);
+                    }
+                }
+                (1 | 3, SchemeValue::Environment(env)) => {
+                    let parent = optional(&mut input)?.map(|i| env_at(&input, i)).transpose()?;
+                    let mut vars = HashMap::new();
+                    for _ in 0..input.u32()? {
+                        let name = input.str()?;
+                        vars.insert(name, input.value()?);
+                    }
+                    *env.borrow_mut() = Environment { vars, parent };
+                }
+                (4, _) => {
+                    let slots = (0..input.u32()?)
+                        .map(|_| if input.u8()? != 0 { input.value().map(Some) } else { Ok(None) })
+                        .collect::<Result<_, SchemeError>>()?;
+                    *frames[&index].slots.borrow_mut() = slots;
+                }
+                (10, SchemeValue::Promise(promise)) => {
+                    let tag = input.u8()?;
+                    let value = input.value()?;
+                    *promise.state.borrow().borrow_mut() = match tag {
+                        0 => PromiseState::Done(value),
+                        1 => PromiseState::Delay(value),
+                        _ => PromiseState::DelayForce(value),
+                    };
+                }
+                (12, SchemeValue::WeakTable(table)) => {
+                    for _ in 0..input.u32()? {
+                        let key = input.value()?;
+                        let value = input.value()?;
+                        let key = WeakRef::new(&key).ok_or_else(|| input.error("weak table key without identity"))?;
+                        table.entries.borrow_mut().insert(key.address(), (key, value));
+                    }
+                }
+                (13, SchemeValue::Ephemeron(ephemeron)) => {
+                    let datum = if input.u8()? != 0 { Some(input.value()?) } else { None };
+                    *ephemeron.datum.borrow_mut() = datum;
+                }
+                _ => {}
+            }
+        }
+        if input.pos != snapshot.len() {
+            return Err(input.error("trailing bytes"));
+        }
+
+        self.builtins = (self.global.borrow().vars.keys())
+            .filter_map(|name| Some((name.clone(), crate::sandbox::builtin_profile(name)?)))
+            .collect();
+        // Procedures from the snapshot may hold folds of a builtin that
+        // their session redefined.
+        let toplevel = self.toplevel.borrow().clone();
+        for name in toplevel.borrow().vars.keys() {
+            self.note_rebound(name);
+        }
+        Ok(())
+    }
+}
+
+fn header(input: &mut Decoder) -> Result<(Backend, Vec<Profile>), SchemeError> {
+    if input.take(MAGIC.len())? != MAGIC {
+        return Err(input.error("not a snapshot"));
+    }
+    let version = input.u32()?;
+    if version != VERSION {
+        return Err(input.error(&format!("version {} is not supported (expected {})", version, VERSION)));
+    }
+    let backend = if input.u8()? == 0 { Backend::Bytecode } else { Backend::TreeWalker };
+    let mut profiles = Vec::new();
+    for _ in 0..input.u32()? {
+        let name = input.str()?;
+        profiles.push(Profile::from_name(&name).ok_or_else(|| input.error(&format!("unknown profile {}", name)))?);
+    }
+    Ok((backend, profiles))
+}
+
+fn weak_target(out: &mut Encoder, target: &WeakRef) -> Result<(), SchemeError> {
+    match target.upgrade() {
+        Some(value) => {
+            out.u8(1);
+            out.value(&value)
+        }
+        None => {
+            out.u8(0);
+            Ok(())
+        }
+    }
+}
+
+fn weak_ref(input: &mut Decoder) -> Result<WeakRef, SchemeError> {
+    if input.u8()? == 0 {
+        // Any dead reference will do.
+        return Ok(WeakRef::WeakBox(Weak::new()));
+    }
+    let value = input.value()?;
+    WeakRef::new(&value).ok_or_else(|| input.error("weak reference to a value without identity"))
+}
+
+/// An index, or `None` for `u32::MAX`.
+fn optional(input: &mut Decoder) -> Result<Option<usize>, SchemeError> {
+    let index = input.u32()?;
+    Ok(if index == u32::MAX { None } else { Some(index as usize) })
+}
+
+fn env_at(input: &Decoder, index: usize) -> Result<Env, SchemeError> {
+    match input.objects.get(index) {
+        Some(SchemeValue::Environment(env)) => Ok(env.clone()),
+        _ => Err(input.error("expected a frame")),
+    }
+}
+
+fn frame_ref(input: &mut Decoder) -> Result<Env, SchemeError> {
+    let index = input.index()?;
+    env_at(input, index)
+}
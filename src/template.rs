@@ -0,0 +1,280 @@
+//! String templates filled from Scheme bindings.
+//!
+//! ```text
+//! <h1>{{title}}</h1>
+//! {{#if items}}
+//! <ul>{{#each items}}<li>{{name}}: {{price}}</li>{{/each}}</ul>
+//! {{else}}
+//! <p>Nothing for {{user.name}}.</p>
+//! {{/if}}
+//! {{{footer}}}
+//! ```
+//!
+//! Bindings are an alist of `(key value)` entries, with string or symbol
+//! keys, or a hash table. `{{name}}` inserts a string, symbol or number,
+//! escaped for HTML; `{{{name}}}` inserts it as it is. `a.b` looks up `b`
+//! in the value of `a`. `{{#each list}}` repeats its body for each element
+//! of a list or vector: names are looked up in the element first, then in
+//! the enclosing bindings, and `{{.}}` is the element itself.
+//! `{{#if name}}` keeps its body unless the value is `#f`, `'()`, an empty
+//! vector or an empty string, and may have an `{{else}}`.
+//!
+//! A name with no binding is an error, as are a block that is not closed and
+//! blocks nested more than `MAX_NESTING` deep.
+//! Both report the line of the template they are on, so whoever edits the
+//! template can find them.
+//!
+//! Scheme code reaches this through `(render-template template bindings)`.
+
+use crate::html;
+use crate::{string_arg, SchemeError, SchemeInterpreter, SchemeValue};
+
+/// How deeply blocks may nest, so that parsing and rendering cannot
+/// exhaust the stack.
+const MAX_NESTING: usize = 512;
+
+/// A parsed template, which can be rendered any number of times.
+#[derive(Clone, Debug)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Text(String),
+    Value { name: String, raw: bool, line: usize },
+    Each { name: String, body: Vec<Node>, line: usize },
+    If { name: String, then: Vec<Node>, otherwise: Vec<Node>, line: usize },
+}
+
+/// A `{{...}}` tag or the text between tags. The tokenizer pairs each with
+/// the line it starts on.
+enum Token {
+    Text(String),
+    Value { name: String, raw: bool },
+    Open { block: &'static str, name: String },
+    Else,
+    Close(String),
+}
+
+impl Template {
+    pub fn parse(text: &str) -> Result<Template, SchemeError> {
+        let mut tokens = tokenize(text)?.into_iter();
+        let (nodes, end) = parse_nodes(&mut tokens, 0)?;
+        match end {
+            None => Ok(Template { nodes }),
+            Some((token, line)) => Err(unexpected(&token, line)),
+        }
+    }
+
+    /// Fills the template from `bindings`, an alist or hash table.
+    pub fn render(&self, bindings: &SchemeValue) -> Result<String, SchemeError> {
+        let mut out = String::new();
+        render_nodes(&self.nodes, &mut vec![bindings], &mut out, 0)?;
+        Ok(out)
+    }
+}
+
+/// Parses and renders `template` in one step.
+pub fn render(template: &str, bindings: &SchemeValue) -> Result<String, SchemeError> {
+    Template::parse(template)?.render(bindings)
+}
+
+impl SchemeInterpreter {
+    pub(crate) fn define_template_procedures(&self) {
+        self.define_native("render-template", 2, |_ctx, args| {
+            let template = string_arg("render-template", &args[0])?;
+            let html = render(template, &args[1]).map_err(|e| format!("render-template: {}", e))?;
+            Ok(SchemeValue::String(html))
+        });
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, SchemeError> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        if start > 0 {
+            tokens.push((Token::Text(rest[..start].to_string()), line));
+            line += rest[..start].matches('\n').count();
+        }
+        let raw = rest[start..].starts_with("{{{");
+        let (open, close) = if raw { (3, "}}}") } else { (2, "}}") };
+        let end = (rest[start + open..].find(close))
+            .ok_or_else(|| format!("{} is not closed on line {}", &rest[start..start + open], line))?;
+        let tag = &rest[start + open..start + open + end];
+        let token = if raw {
+            Token::Value { name: name(tag, line)?, raw: true }
+        } else {
+            let tag = tag.trim();
+            let block = |prefix: &str| tag.strip_prefix(prefix).filter(|rest| rest.starts_with(char::is_whitespace));
+            if let Some(each) = block("#each") {
+                Token::Open { block: "each", name: name(each, line)? }
+            } else if let Some(if_) = block("#if") {
+                Token::Open { block: "if", name: name(if_, line)? }
+            } else if let Some(block) = tag.strip_prefix('/') {
+                Token::Close(block.trim().to_string())
+            } else if tag == "else" {
+                Token::Else
+            } else {
+                Token::Value { name: name(tag, line)?, raw: false }
+            }
+        };
+        tokens.push((token, line));
+        line += tag.matches('\n').count();
+        rest = &rest[start + open + end + close.len()..];
+    }
+    if !rest.is_empty() {
+        tokens.push((Token::Text(rest.to_string()), line));
+    }
+    Ok(tokens)
+}
+
+/// Checks the name in a tag: `.` or dot-separated words.
+fn name(tag: &str, line: usize) -> Result<String, SchemeError> {
+    let name = tag.trim();
+    let word = |part: &str| !part.is_empty() && !part.contains(|c: char| c.is_whitespace() || "{}#/".contains(c));
+    if name == "." || name.split('.').all(word) {
+        Ok(name.to_string())
+    } else {
+        Err(format!("{{{{{}}}}} is not a valid tag on line {}", tag.trim(), line).into())
+    }
+}
+
+type Tokens = std::vec::IntoIter<(Token, usize)>;
+
+/// Parsed nodes and the token that ended them, if any.
+type Block = (Vec<Node>, Option<(Token, usize)>);
+
+/// Parses nodes up to the end or an `{{else}}` or closing tag, which is
+/// returned for the caller to check. `depth` is the number of enclosing
+/// blocks.
+fn parse_nodes(tokens: &mut Tokens, depth: usize) -> Result<Block, SchemeError> {
+    let mut nodes = Vec::new();
+    while let Some((token, line)) = tokens.next() {
+        match token {
+            Token::Text(text) => nodes.push(Node::Text(text)),
+            Token::Value { name, raw } => nodes.push(Node::Value { name, raw, line }),
+            Token::Open { block, name } => {
+                if depth == MAX_NESTING {
+                    return Err(too_deep(line));
+                }
+                let (body, mut end) = parse_nodes(tokens, depth + 1)?;
+                let mut otherwise = Vec::new();
+                if block == "if" && matches!(end, Some((Token::Else, _))) {
+                    let (nodes, after) = parse_nodes(tokens, depth + 1)?;
+                    otherwise = nodes;
+                    end = after;
+                }
+                match end {
+                    Some((Token::Close(closed), _)) if closed == block => (),
+                    Some((Token::Close(closed), close_line)) => {
+                        return Err(format!(
+                            "{{{{/{}}}}} on line {} does not close {{{{#{} {}}}}} from line {}",
+                            closed, close_line, block, name, line
+                        )
+                        .into());
+                    }
+                    Some((token, line)) => return Err(unexpected(&token, line)),
+                    None => return Err(format!("{{{{#{} {}}}}} on line {} is never closed", block, name, line).into()),
+                }
+                nodes.push(match block {
+                    "each" => Node::Each { name, body, line },
+                    _ => Node::If { name, then: body, otherwise, line },
+                });
+            }
+            token => return Ok((nodes, Some((token, line)))),
+        }
+    }
+    Ok((nodes, None))
+}
+
+fn too_deep(line: usize) -> SchemeError {
+    format!("blocks nested deeper than {} levels on line {}", MAX_NESTING, line).into()
+}
+
+fn unexpected(token: &Token, line: usize) -> SchemeError {
+    match token {
+        Token::Else => format!("{{{{else}}}} outside {{{{#if}}}} on line {}", line).into(),
+        Token::Close(block) => format!("{{{{/{}}}}} without an opening tag on line {}", block, line).into(),
+        _ => format!("unexpected tag on line {}", line).into(),
+    }
+}
+
+fn render_nodes<'a>(
+    nodes: &'a [Node],
+    scopes: &mut Vec<&'a SchemeValue>,
+    out: &mut String,
+    depth: usize,
+) -> Result<(), SchemeError> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Value { name, raw, line } => {
+                let text = match lookup(scopes, name, *line)? {
+                    SchemeValue::String(s) | SchemeValue::Symbol(s) => s.clone(),
+                    SchemeValue::Number(n) => n.to_string(),
+                    other => return Err(format!("cannot insert {} for {} on line {}", other, name, line).into()),
+                };
+                out.push_str(&if *raw { text } else { html::escape(&text) });
+            }
+            Node::Each { line, .. } | Node::If { line, .. } if depth == MAX_NESTING => return Err(too_deep(*line)),
+            Node::Each { name, body, line } => {
+                let items = match lookup(scopes, name, *line)? {
+                    SchemeValue::List(items) | SchemeValue::Vector(items) => items.as_slice(),
+                    SchemeValue::Nil => &[],
+                    other => return Err(format!("{{{{#each {}}}}} needs a list, got {} on line {}", name, other, line).into()),
+                };
+                for item in items {
+                    scopes.push(item);
+                    let result = render_nodes(body, scopes, out, depth + 1);
+                    scopes.pop();
+                    result?;
+                }
+            }
+            Node::If { name, then, otherwise, line } => {
+                let value = lookup(scopes, name, *line)?;
+                let truthy = match value {
+                    SchemeValue::Boolean(b) => *b,
+                    SchemeValue::Nil => false,
+                    SchemeValue::Vector(items) => !items.is_empty(),
+                    SchemeValue::String(s) => !s.is_empty(),
+                    _ => true,
+                };
+                render_nodes(if truthy { then } else { otherwise }, scopes, out, depth + 1)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Finds `name` in the innermost scope that has its first part.
+fn lookup<'a>(scopes: &[&'a SchemeValue], name: &str, line: usize) -> Result<&'a SchemeValue, SchemeError> {
+    let missing = || SchemeError::Error(format!("no value for {} on line {}", name, line));
+    if name == "." {
+        return scopes.last().copied().ok_or_else(missing);
+    }
+    let mut parts = name.split('.');
+    let first = parts.next().unwrap_or_default();
+    let mut value = scopes.iter().rev().find_map(|scope| field(scope, first)).ok_or_else(missing)?;
+    for part in parts {
+        value = field(value, part).ok_or_else(missing)?;
+    }
+    Ok(value)
+}
+
+/// The value for `key` in an alist or hash table.
+fn field<'a>(bindings: &'a SchemeValue, key: &str) -> Option<&'a SchemeValue> {
+    match bindings {
+        SchemeValue::HashTable(table) => table.get(key),
+        SchemeValue::List(entries) => entries.iter().find_map(|entry| match entry {
+            SchemeValue::List(pair) if pair.len() == 2 => match &pair[0] {
+                SchemeValue::String(k) | SchemeValue::Symbol(k) if k == key => Some(&pair[1]),
+                _ => None,
+            },
+            _ => None,
+        }),
+        _ => None,
+    }
+}
@@ -0,0 +1,127 @@
+//! Percent-encoding and `application/x-www-form-urlencoded` query strings.
+//!
+//! Decoding never fails, so handlers can parse whatever a client sends: a
+//! `%` not followed by two hex digits is kept as it is, and bytes that are
+//! not UTF-8 become U+FFFD. Encoding leaves only ASCII letters, digits and
+//! `-._~` unescaped.
+//!
+//! Query strings are read as lists of `(key value)` entries in their order,
+//! keeping repeated keys. `+` means a space in keys and values, a key
+//! without `=` has an empty value, and empty pieces between `&`s are
+//! skipped.
+//!
+//! Scheme code reaches this through `url-decode`, `url-encode`,
+//! `parse-query-string`, `parse-form-urlencoded` and `build-query-string`.
+
+use std::fmt::Write;
+
+use crate::{string_arg, SchemeError, SchemeInterpreter, SchemeValue};
+
+/// Decodes `%XX` escapes. `+` is left alone; see `decode_form` for that.
+pub fn decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let hex = |i: usize| bytes.get(i).and_then(|&b| (b as char).to_digit(16));
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], hex(i + 1), hex(i + 2)) {
+            (b'%', Some(high), Some(low)) => {
+                out.push((high * 16 + low) as u8);
+                i += 3;
+            }
+            (byte, _, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// `decode`, with `+` read as a space first.
+pub fn decode_form(text: &str) -> String {
+    decode(&text.replace('+', " "))
+}
+
+/// Escapes every byte except ASCII letters, digits and `-._~`.
+pub fn encode(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(byte as char),
+            _ => write!(out, "%{:02X}", byte).unwrap(),
+        }
+    }
+    out
+}
+
+/// Splits a query string or form body into its entries. A leading `?` is
+/// ignored.
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    let query = query.strip_prefix('?').unwrap_or(query);
+    (query.split('&'))
+        .filter(|piece| !piece.is_empty())
+        .map(|piece| {
+            let (key, value) = piece.split_once('=').unwrap_or((piece, ""));
+            (decode_form(key), decode_form(value))
+        })
+        .collect()
+}
+
+/// Joins entries into a query string, encoding keys and values.
+pub fn build_query<K: AsRef<str>, V: AsRef<str>>(entries: &[(K, V)]) -> String {
+    let pieces: Vec<_> =
+        entries.iter().map(|(key, value)| format!("{}={}", encode(key.as_ref()), encode(value.as_ref()))).collect();
+    pieces.join("&")
+}
+
+impl SchemeInterpreter {
+    pub(crate) fn define_url_procedures(&self) {
+        self.define_native("url-decode", 1, |_ctx, args| Ok(SchemeValue::String(decode(string_arg("url-decode", &args[0])?))));
+        self.define_native("url-encode", 1, |_ctx, args| Ok(SchemeValue::String(encode(string_arg("url-encode", &args[0])?))));
+        for name in ["parse-query-string", "parse-form-urlencoded"] {
+            self.define_native(name, 1, move |_ctx, args| {
+                let entries = parse_query(string_arg(name, &args[0])?);
+                Ok(alist(entries))
+            });
+        }
+        self.define_native("build-query-string", 1, |_ctx, args| {
+            let entries = match &args[0] {
+                SchemeValue::Nil => &[][..],
+                SchemeValue::List(entries) => entries.as_slice(),
+                other => return Err(format!("build-query-string: expected a list of (key value) entries, got {}", other).into()),
+            };
+            let entries = entries.iter().map(query_entry).collect::<Result<Vec<_>, SchemeError>>()?;
+            Ok(SchemeValue::String(build_query(&entries)))
+        });
+    }
+}
+
+fn alist(entries: Vec<(String, String)>) -> SchemeValue {
+    if entries.is_empty() {
+        return SchemeValue::Nil;
+    }
+    SchemeValue::List(
+        (entries.into_iter())
+            .map(|(key, value)| SchemeValue::List(vec![SchemeValue::String(key), SchemeValue::String(value)]))
+            .collect(),
+    )
+}
+
+/// A `(key value)` entry for `build-query-string`. Keys may be strings or
+/// symbols, and values strings, symbols or numbers.
+fn query_entry(entry: &SchemeValue) -> Result<(String, String), SchemeError> {
+    let text = |value: &SchemeValue, numbers: bool| match value {
+        SchemeValue::String(s) | SchemeValue::Symbol(s) => Some(s.clone()),
+        SchemeValue::Number(n) if numbers => Some(n.to_string()),
+        _ => None,
+    };
+    match entry {
+        SchemeValue::List(pair) if pair.len() == 2 => match (text(&pair[0], false), text(&pair[1], true)) {
+            (Some(key), Some(value)) => Ok((key, value)),
+            (None, _) => Err(format!("build-query-string: key must be a string or symbol, got {}", pair[0]).into()),
+            (_, None) => Err(format!("build-query-string: value must be a string, symbol or number, got {}", pair[1]).into()),
+        },
+        other => Err(format!("build-query-string: expected a (key value) entry, got {}", other).into()),
+    }
+}
@@ -0,0 +1,249 @@
+// Stack-based virtual machine for the bytecode from `compile.rs`.
+//
+// Calls between compiled procedures do not recurse on the Rust stack: each
+// one pushes an activation onto the VM's own call stack, and a tail call
+// replaces the current activation. Builtins, natives and primitives are
+// called through `SchemeInterpreter::apply`, so they behave exactly as with
+// the tree-walker, which stays available as `Backend::TreeWalker` and
+// serves as the reference for differential tests.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::compile::{self, Code, Op};
+use crate::gc::Tracked;
+use crate::{is_true, Env, SchemeError, SchemeInterpreter, SchemeValue};
+
+/// How an interpreter evaluates expanded code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Compile each top-level form to bytecode and run it on the VM.
+    #[default]
+    Bytecode,
+    /// Walk the expanded forms directly. Slower; kept as the reference
+    /// implementation.
+    TreeWalker,
+}
+
+/// A procedure compiled to bytecode, with the frame it closes over.
+pub struct Closure {
+    pub(crate) code: Rc<Code>,
+    pub(crate) frame: Option<Rc<Frame>>,
+    pub(crate) globals: Env,
+}
+
+// Frames hold closures that point back at them.
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("#<lambda>")
+    }
+}
+
+/// The heap frame of a compiled procedure that creates closures or has
+/// internal definitions. A slot is `None` until its definition runs.
+pub(crate) struct Frame {
+    pub(crate) slots: RefCell<Vec<Option<SchemeValue>>>,
+    pub(crate) parent: Option<Rc<Frame>>,
+}
+
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#<frame ({} slots)>", self.slots.borrow().len())
+    }
+}
+
+impl Frame {
+    /// The frame `depth` out. The compiler only addresses frames that exist,
+    /// but code loaded from an image or snapshot is checked here.
+    fn at(frame: &Option<Rc<Frame>>, depth: usize) -> Result<&Rc<Frame>, SchemeError> {
+        let mut frame = frame.as_ref().ok_or_else(missing_frame)?;
+        for _ in 0..depth {
+            frame = frame.parent.as_ref().ok_or_else(missing_frame)?;
+        }
+        Ok(frame)
+    }
+}
+
+fn missing_frame() -> SchemeError {
+    "compiled code addresses a missing frame".into()
+}
+
+fn missing_slot() -> SchemeError {
+    "compiled code addresses a missing frame slot".into()
+}
+
+/// A running call. Its arguments, if kept on the stack, start at `base`,
+/// just above the procedure being called.
+struct Activation {
+    code: Rc<Code>,
+    pc: usize,
+    base: usize,
+    frame: Option<Rc<Frame>>,
+    globals: Env,
+}
+
+fn unbound(name: &str) -> SchemeError {
+    format!("Unbound variable: {}", name).into()
+}
+
+impl SchemeInterpreter {
+    /// Compiles and runs an expanded top-level form.
+    pub(crate) fn run_compiled(&self, expr: &SchemeValue, env: &Env) -> Result<SchemeValue, SchemeError> {
+        self.run_code(Rc::new(compile::compile(expr)), env)
+    }
+
+    /// Runs a compiled top-level form.
+    pub(crate) fn run_code(&self, code: Rc<Code>, env: &Env) -> Result<SchemeValue, SchemeError> {
+        let activation = Activation { code, pc: 0, base: 1, frame: None, globals: env.clone() };
+        self.execute(activation, vec![SchemeValue::Nil])
+    }
+
+    pub(crate) fn apply_closure(&self, closure: &Rc<Closure>, args: Vec<SchemeValue>) -> Result<SchemeValue, SchemeError> {
+        let argc = args.len();
+        let mut stack = Vec::with_capacity(argc + 16);
+        stack.push(SchemeValue::Closure(closure.clone()));
+        stack.extend(args);
+        let activation = self.enter(closure, &mut stack, argc).map_err(|e| self.signal(e))?;
+        self.execute(activation, stack)
+    }
+
+    /// Runs the VM, counting it as one level of nesting like `eval_in`.
+    fn execute(&self, activation: Activation, stack: Vec<SchemeValue>) -> Result<SchemeValue, SchemeError> {
+        let depth = self.depth.get();
+        self.check_depth(depth)?;
+        self.depth.set(depth + 1);
+        let result = self.run(activation, stack).map_err(|e| self.signal(e));
+        self.depth.set(depth);
+        result
+    }
+
+    /// Binds the `argc` arguments on top of the stack and starts a call.
+    fn enter(&self, closure: &Closure, stack: &mut Vec<SchemeValue>, argc: usize) -> Result<Activation, SchemeError> {
+        let code = &closure.code;
+        let base = stack.len() - argc;
+        if code.rest {
+            if argc < code.params {
+                return Err(format!("Procedure expects at least {} arguments, got {}", code.params, argc).into());
+            }
+            let extra: Vec<SchemeValue> = stack.drain(base + code.params..).collect();
+            stack.push(if extra.is_empty() { SchemeValue::Nil } else { SchemeValue::List(extra) });
+        } else if argc != code.params {
+            return Err(format!("Procedure expects {} arguments, got {}", code.params, argc).into());
+        }
+        let frame = match code.frame_size {
+            None => closure.frame.clone(),
+            Some(size) => {
+                let mut slots: Vec<Option<SchemeValue>> = stack.drain(base..).map(Some).collect();
+                slots.resize(size, None);
+                let frame = Rc::new(Frame { slots: RefCell::new(slots), parent: closure.frame.clone() });
+                if code.captures {
+                    self.track(Tracked::VmFrame(Rc::downgrade(&frame)));
+                }
+                Some(frame)
+            }
+        };
+        Ok(Activation { code: code.clone(), pc: 0, base, frame, globals: closure.globals.clone() })
+    }
+
+    fn run(&self, mut act: Activation, mut stack: Vec<SchemeValue>) -> Result<SchemeValue, SchemeError> {
+        let mut calls: Vec<Activation> = Vec::new();
+        loop {
+            let op = act.code.ops[act.pc];
+            act.pc += 1;
+            match op {
+                Op::Const(i) => stack.push(act.code.constants[i].clone()),
+                Op::Arg(slot) => stack.push(stack[act.base + slot].clone()),
+                Op::SetArg(slot) => stack[act.base + slot] = stack[stack.len() - 1].clone(),
+                Op::Local { depth, slot, name } => {
+                    let value = Frame::at(&act.frame, depth)?.slots.borrow().get(slot).cloned().ok_or_else(missing_slot)?;
+                    stack.push(value.ok_or_else(|| unbound(&act.code.names[name]))?);
+                }
+                Op::SetLocal { depth, slot, name } => {
+                    let frame = Frame::at(&act.frame, depth)?;
+                    let mut slots = frame.slots.borrow_mut();
+                    match slots.get_mut(slot).ok_or_else(missing_slot)? {
+                        None => return Err(unbound(&act.code.names[name])),
+                        value => *value = Some(stack[stack.len() - 1].clone()),
+                    }
+                }
+                Op::DefineLocal { slot, name } => {
+                    let value = stack.pop();
+                    *Frame::at(&act.frame, 0)?.slots.borrow_mut().get_mut(slot).ok_or_else(missing_slot)? = value;
+                    stack.push(SchemeValue::Symbol(act.code.names[name].clone()));
+                }
+                Op::Global(i) => {
+                    let value = act.globals.borrow().get(&act.code.names[i]);
+                    stack.push(value.ok_or_else(|| unbound(&act.code.names[i]))?);
+                }
+                Op::SetGlobal(i) => {
+                    let value = stack[stack.len() - 1].clone();
+                    if !act.globals.borrow_mut().set(&act.code.names[i], value) {
+                        return Err(unbound(&act.code.names[i]));
+                    }
+                }
+                Op::DefineGlobal(i) => {
+                    let value = stack.pop().unwrap();
+                    let name = &act.code.names[i];
+                    act.globals.borrow_mut().define(name, value);
+                    stack.push(SchemeValue::Symbol(name.clone()));
+                }
+                Op::Closure(i) => {
+                    let closure = Rc::new(Closure {
+                        code: act.code.functions[i].clone(),
+                        frame: act.frame.clone(),
+                        globals: act.globals.clone(),
+                    });
+                    self.track(Tracked::VmClosure(Rc::downgrade(&closure)));
+                    stack.push(SchemeValue::Closure(closure));
+                }
+                Op::Jump(target) => act.pc = target,
+                Op::JumpIfFalse(target) => {
+                    if !is_true(&stack.pop().unwrap()) {
+                        act.pc = target;
+                    }
+                }
+                Op::Pop => {
+                    stack.pop();
+                }
+                Op::Call(argc) | Op::TailCall(argc) => {
+                    let callee = stack.len() - argc - 1;
+                    let closure = match &stack[callee] {
+                        SchemeValue::Closure(closure) => closure.clone(),
+                        _ => {
+                            // A tail call to anything else is followed by `Return`.
+                            let args = stack.split_off(callee + 1);
+                            let func = stack.pop().unwrap();
+                            stack.push(self.apply(&func, args)?);
+                            continue;
+                        }
+                    };
+                    self.tick()?;
+                    if let Op::TailCall(_) = op {
+                        stack.drain(act.base - 1..callee);
+                        act = self.enter(&closure, &mut stack, argc)?;
+                    } else {
+                        let depth = self.depth.get();
+                        self.check_depth(depth)?;
+                        let next = self.enter(&closure, &mut stack, argc)?;
+                        calls.push(std::mem::replace(&mut act, next));
+                        self.depth.set(depth + 1);
+                    }
+                }
+                Op::Return => {
+                    let value = stack.pop().unwrap();
+                    stack.truncate(act.base - 1);
+                    match calls.pop() {
+                        Some(caller) => {
+                            act = caller;
+                            self.depth.set(self.depth.get() - 1);
+                            stack.push(value);
+                        }
+                        None => return Ok(value),
+                    }
+                }
+                Op::Fail(i) => return Err(act.code.constants[i].to_string().into()),
+            }
+        }
+    }
+}
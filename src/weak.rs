@@ -0,0 +1,216 @@
+// Weak boxes, weak hash tables and ephemerons, for caches keyed by objects.
+//
+// Only objects with identity can be held weakly: procedures, environments,
+// promises, error objects and the weak containers themselves. Numbers,
+// strings and lists are copied by value, so a weak reference to one could
+// never be the last reference to it; the procedures reject them.
+//
+// Weak hash tables compare keys with `eq?` and hold each value only as long
+// as its key, like an ephemeron. The cycle collector knows about both, so
+// an entry whose value refers back to its key still goes away once nothing
+// else uses the key. Entries whose key was freed by reference counting are
+// dropped at the next lookup or collection.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use crate::convert::type_name;
+use crate::gc::Tracked;
+use crate::{Closure, Continuation, Environment, ErrorObject, Lambda, Macro, Native, Promise, SchemeError, SchemeInterpreter, SchemeValue};
+
+macro_rules! weak_refs {
+    ($($variant:ident: $t:ty),*) => {
+        /// A reference to an object with identity that does not keep it alive.
+        #[derive(Clone, Debug)]
+        pub(crate) enum WeakRef {
+            $($variant(Weak<$t>)),*
+        }
+
+        impl WeakRef {
+            pub(crate) fn new(value: &SchemeValue) -> Option<WeakRef> {
+                match value {
+                    $(SchemeValue::$variant(rc) => Some(WeakRef::$variant(Rc::downgrade(rc))),)*
+                    _ => None,
+                }
+            }
+
+            pub(crate) fn upgrade(&self) -> Option<SchemeValue> {
+                match self {
+                    $(WeakRef::$variant(w) => w.upgrade().map(SchemeValue::$variant),)*
+                }
+            }
+
+            pub(crate) fn is_alive(&self) -> bool {
+                match self {
+                    $(WeakRef::$variant(w) => w.strong_count() > 0,)*
+                }
+            }
+
+            /// The object's address, which its allocation keeps while any
+            /// weak reference to it exists.
+            pub(crate) fn address(&self) -> usize {
+                match self {
+                    $(WeakRef::$variant(w) => w.as_ptr() as *const () as usize,)*
+                }
+            }
+        }
+    };
+}
+
+weak_refs!(
+    Native: Native,
+    Lambda: Lambda,
+    Closure: Closure,
+    Macro: Macro,
+    Continuation: Continuation,
+    ErrorObject: ErrorObject,
+    Promise: Promise,
+    Environment: RefCell<Environment>,
+    WeakBox: WeakBox,
+    WeakTable: WeakTable,
+    Ephemeron: Ephemeron
+);
+
+/// From `make-weak-box`.
+#[derive(Debug)]
+pub struct WeakBox {
+    pub(crate) target: WeakRef,
+}
+
+/// From `make-weak-hash-table`. Entries are keyed by address.
+#[derive(Debug, Default)]
+pub struct WeakTable {
+    pub(crate) entries: RefCell<HashMap<usize, (WeakRef, SchemeValue)>>,
+}
+
+impl WeakTable {
+    fn get(&self, key: &WeakRef) -> Option<SchemeValue> {
+        let mut entries = self.entries.borrow_mut();
+        match entries.get(&key.address()) {
+            Some((k, value)) if k.is_alive() => Some(value.clone()),
+            Some(_) => {
+                entries.remove(&key.address());
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Drops entries whose key has been freed and returns how many are left.
+    fn prune(&self) -> usize {
+        let mut entries = self.entries.borrow_mut();
+        entries.retain(|_, (key, _)| key.is_alive());
+        entries.len()
+    }
+}
+
+/// From `make-ephemeron`: holds its datum only as long as its key is alive.
+#[derive(Debug)]
+pub struct Ephemeron {
+    pub(crate) key: WeakRef,
+    pub(crate) datum: RefCell<Option<SchemeValue>>,
+}
+
+impl Ephemeron {
+    fn is_broken(&self) -> bool {
+        !self.key.is_alive() || self.datum.borrow().is_none()
+    }
+}
+
+fn weak_ref(name: &str, value: &SchemeValue) -> Result<WeakRef, SchemeError> {
+    WeakRef::new(value).ok_or_else(|| format!("{}: {} cannot be held weakly", name, type_name(value)).into())
+}
+
+fn expected(name: &str, what: &str, value: &SchemeValue) -> SchemeError {
+    format!("{}: expected {}, got {}", name, what, type_name(value)).into()
+}
+
+fn table_arg<'a>(name: &str, value: &'a SchemeValue) -> Result<&'a Rc<WeakTable>, SchemeError> {
+    match value {
+        SchemeValue::WeakTable(table) => Ok(table),
+        other => Err(expected(name, "a weak hash table", other)),
+    }
+}
+
+fn ephemeron_arg<'a>(name: &str, value: &'a SchemeValue) -> Result<&'a Rc<Ephemeron>, SchemeError> {
+    match value {
+        SchemeValue::Ephemeron(ephemeron) => Ok(ephemeron),
+        other => Err(expected(name, "an ephemeron", other)),
+    }
+}
+
+impl SchemeInterpreter {
+    pub(crate) fn define_weak_procedures(&self) {
+        self.define_native("make-weak-box", 1, |_ctx, args| {
+            let target = weak_ref("make-weak-box", &args[0])?;
+            Ok(SchemeValue::WeakBox(Rc::new(WeakBox { target })))
+        });
+        self.define_native("weak-box?", 1, |_ctx, args| Ok(SchemeValue::Boolean(matches!(args[0], SchemeValue::WeakBox(_)))));
+        self.define_native("weak-box-value", 1..=2, |_ctx, args| match &args[0] {
+            SchemeValue::WeakBox(weak_box) => Ok(weak_box
+                .target
+                .upgrade()
+                .unwrap_or_else(|| args.get(1).cloned().unwrap_or(SchemeValue::Boolean(false)))),
+            other => Err(expected("weak-box-value", "a weak box", other)),
+        });
+
+        self.define_native("make-ephemeron", 2, |ctx, args| {
+            let key = weak_ref("make-ephemeron", &args[0])?;
+            let ephemeron = Rc::new(Ephemeron { key, datum: RefCell::new(Some(args[1].clone())) });
+            ctx.interpreter().track(Tracked::Ephemeron(Rc::downgrade(&ephemeron)));
+            Ok(SchemeValue::Ephemeron(ephemeron))
+        });
+        self.define_native("ephemeron?", 1, |_ctx, args| Ok(SchemeValue::Boolean(matches!(args[0], SchemeValue::Ephemeron(_)))));
+        self.define_native("ephemeron-key", 1, |_ctx, args| {
+            let ephemeron = ephemeron_arg("ephemeron-key", &args[0])?;
+            Ok(if ephemeron.is_broken() { None } else { ephemeron.key.upgrade() }.unwrap_or(SchemeValue::Boolean(false)))
+        });
+        self.define_native("ephemeron-datum", 1, |_ctx, args| {
+            let ephemeron = ephemeron_arg("ephemeron-datum", &args[0])?;
+            if ephemeron.is_broken() {
+                return Ok(SchemeValue::Boolean(false));
+            }
+            Ok(ephemeron.datum.borrow().clone().unwrap_or(SchemeValue::Boolean(false)))
+        });
+        self.define_native("ephemeron-broken?", 1, |_ctx, args| {
+            Ok(SchemeValue::Boolean(ephemeron_arg("ephemeron-broken?", &args[0])?.is_broken()))
+        });
+
+        self.define_native("make-weak-hash-table", 0, |ctx, _args| {
+            let table = Rc::new(WeakTable::default());
+            ctx.interpreter().track(Tracked::Table(Rc::downgrade(&table)));
+            Ok(SchemeValue::WeakTable(table))
+        });
+        self.define_native("weak-hash-table?", 1, |_ctx, args| {
+            Ok(SchemeValue::Boolean(matches!(args[0], SchemeValue::WeakTable(_))))
+        });
+        self.define_native("weak-hash-table-set!", 3, |ctx, args| {
+            let table = table_arg("weak-hash-table-set!", &args[0])?;
+            let key = weak_ref("weak-hash-table-set!", &args[1])?;
+            if !table.entries.borrow().contains_key(&key.address()) {
+                let len = table.prune() + 1;
+                ctx.interpreter().check_allocation(len, size_of::<(usize, (WeakRef, SchemeValue))>(), "hash table")?;
+            }
+            table.entries.borrow_mut().insert(key.address(), (key, args[2].clone()));
+            Ok(SchemeValue::Nil)
+        });
+        self.define_native("weak-hash-table-ref", 2..=3, |_ctx, args| {
+            let table = table_arg("weak-hash-table-ref", &args[0])?;
+            let found = WeakRef::new(&args[1]).and_then(|key| table.get(&key));
+            Ok(found.unwrap_or_else(|| args.get(2).cloned().unwrap_or(SchemeValue::Boolean(false))))
+        });
+        self.define_native("weak-hash-table-delete!", 2, |_ctx, args| {
+            let table = table_arg("weak-hash-table-delete!", &args[0])?;
+            if let Some(key) = WeakRef::new(&args[1]) {
+                let removed = table.entries.borrow_mut().remove(&key.address());
+                drop(removed);
+            }
+            Ok(SchemeValue::Nil)
+        });
+        self.define_native("weak-hash-table-count", 1, |_ctx, args| {
+            let table = table_arg("weak-hash-table-count", &args[0])?;
+            Ok(SchemeValue::Number(table.prune() as f64))
+        });
+    }
+}
@@ -1,6 +1,18 @@
 #[cfg(test)]
 mod tests {
-    use lisp_compute::{SchemeInterpreter, SchemeValue};
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    use lisp_compute::{
+        html, http, image, json, service, template, url, Backend, CancellationToken, FromScheme, IntoScheme, InterpreterLimits, Profile, SchemeError, SchemeInterpreter, SchemeValue,
+    };
+
+    /// Evaluates `code`, giving the value or the error as a string.
+    fn eval(interpreter: &SchemeInterpreter, code: &str) -> Result<String, String> {
+        interpreter.eval(code).map(|v| v.to_string()).map_err(|e| e.to_string())
+    }
 
     #[test]
     fn test_scheme_interpreter_basic() {
@@ -74,4 +86,1237 @@ mod tests {
             assert!(!output.is_empty(), "Example file {} produced no output", i);
         }
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_define_macro_and_macroexpand() {
+        let interpreter = SchemeInterpreter::new();
+        let program = r#"
+            (define (wrap-body body) `(begin ,@body))
+            (define-macro (my-unless test . body)
+              `(if ,test #f ,(wrap-body body)))
+            (my-unless (> 1 2) "ran")
+        "#;
+        assert!(matches!(interpreter.eval(program), Ok(SchemeValue::String(s)) if s == "ran"));
+
+        let expanded = interpreter
+            .eval("(define-macro (my-unless test . body) `(if ,test #f (begin ,@body))) (macroexpand-1 '(my-unless c 1 2))")
+            .unwrap();
+        assert_eq!(interpreter.display_value(&expanded), "[if, c, false, [begin, 1, 2]]");
+
+        let program = r#"
+            (define-macro (my-unless test . body) `(if ,test #f (begin ,@body)))
+            (define-macro (my-when test . body) `(my-unless (not ,test) ,@body))
+            (list (macroexpand-1 '(my-when c 1)) (macroexpand '(my-when c 1)))
+        "#;
+        let expanded = interpreter.eval(program).unwrap();
+        assert_eq!(
+            interpreter.display_value(&expanded),
+            "[[my-unless, [not, c], 1], [if, [not, c], false, [begin, 1]]]"
+        );
+    }
+
+    #[test]
+    fn test_er_macro_transformer_hygiene() {
+        let interpreter = SchemeInterpreter::new();
+        let program = r#"
+            (define-syntax my-or
+              (er-macro-transformer
+                (lambda (form rename compare)
+                  (if (null? (cdr form))
+                      #f
+                      `(,(rename 'let) ((,(rename 't) ,(cadr form)))
+                         (,(rename 'if) ,(rename 't) ,(rename 't) (my-or ,@(cddr form))))))))
+            (define t 5)
+            (let ((if list)) (my-or #f t))
+        "#;
+        assert!(matches!(interpreter.eval(program), Ok(SchemeValue::Number(n)) if n == 5.0));
+
+        let program = r#"
+            (define-syntax is-else?
+              (er-macro-transformer
+                (lambda (form rename compare)
+                  (compare (cadr form) (rename 'else)))))
+            (list (is-else? else) (is-else? other))
+        "#;
+        let result = interpreter.eval(program).unwrap();
+        assert_eq!(interpreter.display_value(&result), "[true, false]");
+    }
+
+    #[test]
+    fn test_call_cc_escapes_and_dynamic_wind() {
+        let interpreter = SchemeInterpreter::new();
+        let program = r#"
+            (define trace '())
+            (define (note x) (set! trace (cons x trace)))
+            (define (first-negative lst)
+              (call/cc
+                (lambda (return)
+                  (dynamic-wind
+                    (lambda () (note 'enter))
+                    (lambda ()
+                      (let loop ((l lst))
+                        (cond ((null? l) #f)
+                              ((< (car l) 0) (return (car l)))
+                              (else (loop (cdr l))))))
+                    (lambda () (note 'exit))))))
+            (list (first-negative (list 3 -4 5)) (first-negative (list 1 2)) trace)
+        "#;
+        let result = interpreter.eval(program).unwrap();
+        assert_eq!(interpreter.display_value(&result), "[-4, false, [exit, enter, exit, enter]]");
+
+        let result = interpreter.eval("(call-with-escape-continuation (lambda (k) (+ 1 (k 42))))");
+        assert!(matches!(result, Ok(SchemeValue::Number(n)) if n == 42.0));
+    }
+
+    #[test]
+    fn test_continuation_reentry_is_an_error() {
+        let interpreter = SchemeInterpreter::new();
+        let program = r#"
+            (define saved #f)
+            (+ 1 (call/cc (lambda (k) (set! saved k) 1)))
+            (saved 5)
+        "#;
+        let err = interpreter.eval(program).unwrap_err().to_string();
+        assert!(err.contains("re-entrant continuations are not supported"), "{}", err);
+    }
+
+    #[test]
+    fn test_guard_catches_raised_and_builtin_errors() {
+        let interpreter = SchemeInterpreter::new();
+        let program = r#"
+            (define (parse-age value)
+              (if (number? value) value (error "age is not a number:" value)))
+            (define (field-or-default parse value default)
+              (guard (e ((error-object? e) default))
+                (parse value)))
+            (list (field-or-default parse-age 42 0)
+                  (field-or-default parse-age "forty" 0)
+                  (guard (e ((error-object? e) (error-object-message e))) (/ 1 0))
+                  (guard (e ((error-object? e) (error-object-message e))) ((lambda (x) x)))
+                  (guard (e ((symbol? e) (list 'symbol e))) (raise 'boom))
+                  (guard (e ((string? e) 'string) ((symbol? e) => (lambda (yes) yes))) (raise 'sym)))
+        "#;
+        let result = interpreter.eval(program).unwrap();
+        assert_eq!(
+            interpreter.display_value(&result),
+            "[42, 0, Division by zero, Procedure expects 1 arguments, got 0, [symbol, boom], true]"
+        );
+
+        let program = r#"
+            (guard (e (#t (list (error-object-message e) (error-object-irritants e))))
+              (error "bad field:" 'age 42))
+        "#;
+        let result = interpreter.eval(program).unwrap();
+        assert_eq!(interpreter.display_value(&result), "[bad field:, [age, 42]]");
+
+        let err = interpreter.eval("(guard (e ((string? e) 'no)) (raise 'unhandled))").unwrap_err().to_string();
+        assert_eq!(err, "Uncaught exception: unhandled");
+        let err = interpreter.eval("(error \"Something failed:\" 1 2)").unwrap_err().to_string();
+        assert_eq!(err, "Something failed: 1 2");
+    }
+
+    #[test]
+    fn test_with_exception_handler() {
+        let interpreter = SchemeInterpreter::new();
+        let result = interpreter.eval("(with-exception-handler (lambda (c) 10) (lambda () (+ 1 (raise-continuable 'oops))))");
+        assert!(matches!(result, Ok(SchemeValue::Number(n)) if n == 11.0));
+
+        let program = r#"
+            (call/cc
+              (lambda (k)
+                (with-exception-handler
+                  (lambda (c) (k (list 'handled (error-object-message c))))
+                  (lambda () (+ 1 (car 5))))))
+        "#;
+        let result = interpreter.eval(program).unwrap();
+        assert_eq!(interpreter.display_value(&result), "[handled, car requires a list argument]");
+
+        let err = interpreter
+            .eval("(with-exception-handler (lambda (c) 10) (lambda () (+ 1 (raise 'oops))))")
+            .unwrap_err().to_string();
+        assert!(err.starts_with("exception handler returned from non-continuable raise"), "{}", err);
+
+        // A handler's own error goes to the handler outside it, once
+        let program = r#"
+            (define calls 0)
+            (define (count-calls thunk)
+              (set! calls 0)
+              (guard (e (#t (list (error-object-message e) calls)))
+                (with-exception-handler
+                  (lambda (c) (set! calls (+ calls 1)) (car '()))
+                  thunk)))
+            (list (count-calls (lambda () (+ 1 (raise 'oops))))
+                  (count-calls (lambda () (+ 1 (vector-ref (vector) 0)))))
+        "#;
+        let result = interpreter.eval(program).unwrap();
+        assert_eq!(interpreter.display_value(&result), "[[car requires a list argument, 1], [car requires a list argument, 1]]");
+    }
+
+    #[test]
+    fn test_multiple_values() {
+        let interpreter = SchemeInterpreter::new();
+        assert!(matches!(
+            interpreter.eval("(call-with-values (lambda () (values 1 2)) +)"),
+            Ok(SchemeValue::Number(n)) if n == 3.0
+        ));
+        assert!(matches!(interpreter.eval("(values 42)"), Ok(SchemeValue::Number(n)) if n == 42.0));
+
+        let program = r#"
+            (define (split-first lst) (values (car lst) (cdr lst)))
+            (define-values (head tail) (split-first (list 1 2 3)))
+            (list head
+                  tail
+                  (receive (a . rest) (values 1 2 3) (list a rest))
+                  (let ((a 'outer))
+                    (let-values (((a b) (values 1 2)) ((c) (values a)))
+                      (list a b c)))
+                  (let*-values (((a b) (values 1 2)) ((c) (values (+ a b))))
+                    c))
+        "#;
+        let result = interpreter.eval(program).unwrap();
+        assert_eq!(interpreter.display_value(&result), "[1, [2, 3], [1, [2, 3]], [1, 2, outer], 3]");
+    }
+
+    #[test]
+    fn test_promises_are_memoized_and_iterative() {
+        let interpreter = SchemeInterpreter::new();
+        let program = r#"
+            (define count 0)
+            (define p (delay (begin (set! count (+ count 1)) count)))
+            (list (force p) (force p) (promise? p) (force (make-promise 5)) (force 7))
+        "#;
+        let result = interpreter.eval(program).unwrap();
+        assert_eq!(interpreter.display_value(&result), "[1, 1, true, 5, 7]");
+
+        // A long delay-force chain must not grow the Rust stack.
+        let program = r#"
+            (define (countdown n) (delay-force (if (= n 0) (delay 'done) (countdown (- n 1)))))
+            (force (countdown 10000))
+        "#;
+        assert!(matches!(interpreter.eval(program), Ok(SchemeValue::Symbol(s)) if s == "done"));
+    }
+
+    #[test]
+    fn test_streams() {
+        let interpreter = SchemeInterpreter::new();
+        let program = r#"
+            (define (integers-from n) (stream-cons n (integers-from (+ n 1))))
+            (list (stream->list (stream-take 5 (stream-map (lambda (x) (* x x)) (integers-from 1))))
+                  (stream-car (stream-filter (lambda (x) (> x 10000)) (integers-from 1)))
+                  (stream->list (list->stream (list 1 2 3)))
+                  (stream-null? stream-null))
+        "#;
+        let result = interpreter.eval(program).unwrap();
+        assert_eq!(interpreter.display_value(&result), "[[1, 4, 9, 16, 25], 10001, [1, 2, 3], true]");
+
+        let program = r#"
+            (define forced '())
+            (define s (stream-map (lambda (x) (set! forced (cons x forced)) x) (list->stream (list 1 2 3))))
+            (stream-car s)
+            forced
+        "#;
+        let result = interpreter.eval(program).unwrap();
+        assert_eq!(interpreter.display_value(&result), "[1]");
+    }
+
+    #[test]
+    fn test_define_native() {
+        let interpreter = SchemeInterpreter::new();
+        let hits = Rc::new(Cell::new(0));
+        let counter = hits.clone();
+        interpreter.define_native("hit!", 0, move |_ctx, _args| {
+            counter.set(counter.get() + 1);
+            Ok(SchemeValue::Number(counter.get() as f64))
+        });
+        interpreter.define_native("apply-twice", 2, |ctx, args| {
+            let once = ctx.apply(&args[0], vec![args[1].clone()])?;
+            ctx.apply(&args[0], vec![once])
+        });
+        interpreter.define_native("checked-sqrt", 1, |_ctx, args| match args[0] {
+            SchemeValue::Number(n) if n >= 0.0 => Ok(SchemeValue::Number(n.sqrt())),
+            _ => Err(SchemeError::from("checked-sqrt: expected a non-negative number")),
+        });
+
+        let result = interpreter.eval("(hit!) (hit!) (apply-twice (lambda (x) (* x 3)) 2)").unwrap();
+        assert!(matches!(result, SchemeValue::Number(n) if n == 18.0));
+        assert_eq!(hits.get(), 2);
+        assert!(matches!(interpreter.eval("(procedure? hit!)"), Ok(SchemeValue::Boolean(true))));
+
+        // Arity is checked before the closure runs.
+        let err = interpreter.eval("(apply-twice car)").unwrap_err().to_string();
+        assert_eq!(err, "apply-twice expects 2 arguments, got 1");
+        assert_eq!(hits.get(), 2);
+
+        // Native errors and escapes behave like those of any other procedure.
+        let result = interpreter
+            .eval(r#"(guard (e ((error-object? e) (error-object-message e))) (checked-sqrt -1))"#)
+            .unwrap();
+        assert_eq!(interpreter.display_value(&result), "checked-sqrt: expected a non-negative number");
+        let result = interpreter.eval("(call/cc (lambda (k) (apply-twice (lambda (x) (k 'escaped)) 1)))").unwrap();
+        assert!(matches!(result, SchemeValue::Symbol(s) if s == "escaped"));
+    }
+
+    #[test]
+    fn test_register_fn_with_typed_arguments() {
+        let interpreter = SchemeInterpreter::new();
+        interpreter.register_fn("clamp", |x: i64, lo: i64, hi: i64| x.clamp(lo, hi));
+        interpreter.register_fn("greet", |name: Option<String>| format!("hello, {}", name.as_deref().unwrap_or("stranger")));
+        interpreter.register_fn("sum", |xs: Vec<f64>| xs.iter().sum::<f64>());
+        interpreter.register_fn("min-max", |xs: Vec<i64>| (xs.iter().min().copied(), xs.iter().max().copied()));
+        interpreter.register_fn("lookup", |table: HashMap<String, String>, key: String| table.get(&key).cloned());
+        interpreter.register_fn("parse", |s: String| s.parse::<f64>().map_err(|e| format!("parse: {}", e)));
+
+        let result = interpreter
+            .eval(r#"(list (clamp 15 0 10) (greet "ada") (greet #f) (sum (list 1 2 3.5)) (min-max (list 3 1 2)) (min-max '()))"#)
+            .unwrap();
+        assert_eq!(interpreter.display_value(&result), "[10, hello, ada, hello, stranger, 6.5, [1, 3], [false, false]]");
+
+        let err = interpreter.eval("(clamp 1 2)").unwrap_err().to_string();
+        assert_eq!(err, "clamp expects 3 arguments, got 2");
+        let err = interpreter.eval(r#"(clamp 1.5 0 10)"#).unwrap_err().to_string();
+        assert_eq!(err, "clamp: argument 1: expected an integer, got a number");
+        let err = interpreter.eval(r#"(clamp 1 "0" 10)"#).unwrap_err().to_string();
+        assert_eq!(err, "clamp: argument 2: expected an integer, got a string");
+        let err = interpreter.eval("(clamp (expt 2 63) 0 10)").unwrap_err().to_string();
+        assert_eq!(err, "clamp: argument 1: 9223372036854776000 is out of range for i64");
+        assert_eq!(interpreter.eval("(clamp (- (expt 2 63)) 0 10)").unwrap().to_string(), "0");
+        assert_eq!(i64::from_scheme(&SchemeValue::Number(9007199254740992.0)).unwrap(), 1 << 53);
+        assert!(u64::from_scheme(&SchemeValue::Number(2f64.powi(64))).is_err());
+        assert_eq!(u8::from_scheme(&SchemeValue::Number(255.0)).unwrap(), 255);
+        assert!(u8::from_scheme(&SchemeValue::Number(256.0)).is_err());
+        let err = interpreter.eval(r#"(parse "twelve")"#).unwrap_err().to_string();
+        assert_eq!(err, "parse: invalid float literal");
+
+        // Conversion errors can be caught like any other builtin error.
+        let result = interpreter.eval(r#"(guard (e (#t (error-object-message e))) (sum (list 1 "2")))"#).unwrap();
+        assert_eq!(interpreter.display_value(&result), "sum: argument 1: expected a number, got a string");
+
+        let mut table = HashMap::new();
+        table.insert("lang".to_string(), "scheme".to_string());
+        let table = table.into_scheme().unwrap();
+        interpreter.define_native("config", 0, move |_ctx, _args| Ok(table.clone()));
+        let result = interpreter.eval(r#"(list (lookup (config) "lang") (lookup (config) "editor"))"#).unwrap();
+        assert_eq!(<(String, bool)>::from_scheme(&result).unwrap(), ("scheme".to_string(), false));
+    }
+
+    #[test]
+    fn test_persistent_session() {
+        let interpreter = SchemeInterpreter::new();
+        interpreter.register_fn("double", |x: f64| x * 2.0);
+        interpreter.eval("(define greeting \"hello\") (define (handle path) (list greeting path))").unwrap();
+        interpreter.eval("(define-macro (twice e) `(begin ,e ,e))").unwrap();
+
+        // Definitions from one call are visible in the next.
+        let result = interpreter.eval("(handle \"/index\")").unwrap();
+        assert_eq!(interpreter.display_value(&result), "[hello, /index]");
+        assert!(matches!(interpreter.eval("(define n 0) (twice (set! n (+ n 1))) n"), Ok(SchemeValue::Number(n)) if n == 2.0));
+
+        // The host can read, replace and call top-level bindings.
+        assert!(matches!(interpreter.get_global("n"), Some(SchemeValue::Number(n)) if n == 2.0));
+        assert!(interpreter.get_global("car").is_some());
+        interpreter.set_global("greeting", SchemeValue::String("hi".to_string()));
+        let result = interpreter.call("handle", vec![SchemeValue::String("/about".to_string())]).unwrap();
+        assert_eq!(interpreter.display_value(&result), "[hi, /about]");
+        assert_eq!(interpreter.call("missing", vec![]).unwrap_err().to_string(), "Unbound variable: missing");
+        assert_eq!(interpreter.call("handle", vec![]).unwrap_err().to_string(), "Procedure expects 1 arguments, got 0");
+
+        // reset forgets script definitions but keeps builtins and natives.
+        interpreter.reset();
+        assert!(interpreter.get_global("greeting").is_none());
+        assert_eq!(interpreter.eval("(handle \"/\")").unwrap_err().to_string(), "Unbound variable: handle");
+        assert!(matches!(interpreter.eval("(double (car (list 4)))"), Ok(SchemeValue::Number(n)) if n == 8.0));
+    }
+
+    #[test]
+    fn test_fuel_limits() {
+        let interpreter = SchemeInterpreter::new();
+        interpreter.eval("(define (count-to n) (let loop ((i 0)) (if (< i n) (loop (+ i 1)) i)))").unwrap();
+
+        // Fuel use is deterministic.
+        interpreter.set_fuel(None);
+        interpreter.eval("(count-to 100)").unwrap();
+        let used = interpreter.fuel_consumed();
+        assert!(used > 100);
+        interpreter.set_fuel(None);
+        interpreter.eval("(count-to 100)").unwrap();
+        assert_eq!(interpreter.fuel_consumed(), used);
+
+        interpreter.set_fuel(Some(used));
+        assert!(matches!(interpreter.eval("(count-to 100)"), Ok(SchemeValue::Number(n)) if n == 100.0));
+        assert_eq!(interpreter.fuel_remaining(), Some(0));
+
+        // A runaway loop stops, and scripts cannot catch the limit.
+        interpreter.set_fuel(Some(10_000));
+        let err = interpreter.eval("(guard (e (#t 'caught)) (let forever () (forever)))").unwrap_err();
+        assert!(matches!(err, SchemeError::ResourceLimit(_)), "{}", err);
+        assert_eq!(interpreter.fuel_consumed(), 10_000);
+        interpreter.set_fuel(Some(10_000));
+        let err = interpreter
+            .eval("(with-exception-handler (lambda (e) 0) (lambda () (count-to 1000000)))")
+            .unwrap_err();
+        assert!(matches!(err, SchemeError::ResourceLimit(_)), "{}", err);
+
+        // The same holds while a macro transformer runs.
+        interpreter.set_fuel(None);
+        interpreter.eval("(define-macro (spin) (let loop () (loop)))").unwrap();
+        interpreter.set_fuel(Some(10_000));
+        let err = interpreter.eval("(spin)").unwrap_err();
+        assert!(matches!(err, SchemeError::ResourceLimit(_)), "{:?}", err);
+        interpreter.set_fuel(Some(10_000));
+        let err = interpreter.eval("(guard (e (#t 'caught)) (eval '(spin) (interaction-environment)))").unwrap_err();
+        assert!(matches!(err, SchemeError::ResourceLimit(_)), "{:?}", err);
+
+        interpreter.set_fuel(None);
+        assert!(matches!(interpreter.eval("(count-to 1000)"), Ok(SchemeValue::Number(n)) if n == 1000.0));
+    }
+
+    #[test]
+    fn test_memory_limits() {
+        let interpreter = SchemeInterpreter::with_limits(InterpreterLimits {
+            max_allocated_bytes: Some(100_000),
+            max_list_length: Some(1_000),
+            max_string_length: Some(100),
+            max_hash_table_size: Some(10),
+            max_depth: Some(50),
+        });
+        let over_limit = |program: &str| match interpreter.eval(program) {
+            Err(SchemeError::MemoryLimit(message)) => message,
+            other => panic!("expected a memory limit for {}, got {:?}", program, other),
+        };
+
+        assert!(matches!(interpreter.eval("(vector-length (make-vector 1000 'x))"), Ok(SchemeValue::Number(n)) if n == 1000.0));
+        assert!(interpreter.allocated_bytes() > 0);
+        assert_eq!(over_limit("(make-vector 1e12)"), "vector of length 1000000000000 is over the limit of 1000");
+        assert_eq!(over_limit("(make-string 101 \"x\")"), "string of length 101 is over the limit of 100");
+        assert!(matches!(interpreter.eval("(make-string 3 \"z\")"), Ok(SchemeValue::String(s)) if s == "zzz"));
+
+        // The byte budget covers everything built during one eval.
+        interpreter.eval("(define (build n acc) (if (= n 0) acc (build (- n 1) (cons n acc))))").unwrap();
+        assert!(interpreter.eval("(length (build 50 '()))").is_ok());
+        assert_eq!(over_limit("(length (build 900 '()))"), "more than 100000 bytes allocated");
+
+        // Deep recursion fails cleanly.
+        interpreter.eval("(define (depth n) (if (= n 0) 0 (+ 1 (depth (- n 1)))))").unwrap();
+        assert!(matches!(interpreter.eval("(depth 40)"), Ok(SchemeValue::Number(n)) if n == 40.0));
+        assert_eq!(over_limit("(depth 100)"), "recursion deeper than 50 levels");
+
+        // Scripts can catch going over a limit.
+        assert_eq!(eval(&interpreter, "(guard (e (#t (error-object-message e))) (depth 100))"), Ok("recursion deeper than 50 levels".to_string()));
+        assert_eq!(eval(&interpreter, "(guard (e ((error-object? e) 'caught)) (make-vector 1e12))"), Ok("caught".to_string()));
+        assert_eq!(
+            eval(&interpreter, "(call/cc (lambda (k) (with-exception-handler (lambda (e) (k (error-object-message e))) (lambda () (make-string 101)))))"),
+            Ok("string of length 101 is over the limit of 100".to_string())
+        );
+
+        // Without limits, an allocation the host cannot make is caught the same way.
+        let unlimited = SchemeInterpreter::new();
+        assert_eq!(
+            unlimited.eval("(guard (e (#t (error-object-message e))) (make-vector 100000000000 0))").unwrap().to_string(),
+            "not enough memory for a vector of length 100000000000"
+        );
+        assert!(matches!(unlimited.eval("(make-string 100000000000)"), Err(SchemeError::MemoryLimit(_))));
+
+        // So is a form that expands deeper than the limit, when `eval` expands it.
+        assert_eq!(over_limit("(and 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1 1)"), "expansion nested deeper than 50 levels");
+        let deep_and = "(define (ones n) (if (= n 0) '() (cons 1 (ones (- n 1)))))
+                        (guard (e (#t (error-object-message e))) (eval (cons 'and (ones 40)) (interaction-environment)))";
+        assert_eq!(eval(&interpreter, deep_and), Ok("expansion nested deeper than 50 levels".to_string()));
+        assert_eq!(eval(&unlimited, &deep_and.replace("40", "600")), Ok("expansion nested deeper than 512 levels".to_string()));
+
+        // Tail calls do not count towards the depth.
+        assert!(matches!(interpreter.eval("(let loop ((i 0)) (if (< i 10000) (loop (+ i 1)) i))"), Ok(SchemeValue::Number(n)) if n == 10000.0));
+
+        let mut big = HashMap::new();
+        for i in 0..11 {
+            big.insert(i.to_string(), i);
+        }
+        let big = big.into_scheme().unwrap();
+        interpreter.define_native("big-table", 0, move |_ctx, _args| Ok(big.clone()));
+        assert_eq!(over_limit("(big-table)"), "hash table of length 11 is over the limit of 10");
+    }
+
+    #[test]
+    fn test_sandbox_profiles() {
+        let pure = SchemeInterpreter::builder().with_profile(Profile::Pure).build();
+        assert!(pure.allows(Profile::Pure));
+        assert!(!pure.allows(Profile::Fetch));
+        assert!(matches!(pure.eval("(stream-car (stream-cons (* 6 7) '()))"), Ok(SchemeValue::Number(n)) if n == 42.0));
+        for name in ["string-append", "display", "current-second", "random", "log", "gc", "gc-stats", "request-path"] {
+            assert!(pure.get_global(name).is_none(), "{} should not exist", name);
+            assert_eq!(pure.eval(name).unwrap_err().to_string(), format!("Unbound variable: {}", name));
+        }
+
+        let data = SchemeInterpreter::builder().with_profile(Profile::Data).with_profile(Profile::Time).build();
+        let result = data.eval(r#"(string-append (string-upcase "ab") (number->string (string-length "cde")))"#).unwrap();
+        assert_eq!(data.display_value(&result), "AB3");
+        assert!(matches!(data.eval("(> (current-second) 0)"), Ok(SchemeValue::Boolean(true))));
+        assert!(data.get_global("random").is_none());
+
+        // Building fails on a builtin that no profile lists.
+        for profile in Profile::ALL {
+            SchemeInterpreter::builder().with_profile(profile).build();
+        }
+        let logging = SchemeInterpreter::builder().with_profile(Profile::Logging).build();
+        assert!(matches!(logging.eval("(>= (gc) 0)"), Ok(SchemeValue::Boolean(true))));
+
+        let full = SchemeInterpreter::new();
+        assert!(Profile::ALL.iter().all(|&p| full.allows(p)));
+        assert!(matches!(full.eval("(let ((r (random 10))) (and (>= r 0) (< r 10)))"), Ok(SchemeValue::Boolean(true))));
+    }
+
+    #[test]
+    fn test_eval_with_restricted_environments() {
+        let interpreter = SchemeInterpreter::builder().with_profile(Profile::Data).build();
+        interpreter.eval("(define secret 42)").unwrap();
+
+        assert_eq!(eval(&interpreter, "(eval '(* 6 7) (environment 'pure))"), Ok("42".to_string()));
+        assert_eq!(eval(&interpreter, "(eval 'secret (interaction-environment))"), Ok("42".to_string()));
+        assert_eq!(eval(&interpreter, r#"(eval '(string-append "a" "b") (environment 'data))"#), Ok("ab".to_string()));
+
+        // A restricted environment sees only its profiles and no top-level definitions.
+        assert_eq!(eval(&interpreter, "(eval 'secret (environment 'pure))"), Err("Unbound variable: secret".to_string()));
+        assert_eq!(eval(&interpreter, r#"(eval '(string-append "a") (environment 'pure))"#), Err("Unbound variable: string-append".to_string()));
+        assert_eq!(eval(&interpreter, "(environment 'time)"), Err("environment: profile time is not available".to_string()));
+        assert_eq!(eval(&interpreter, "(environment 'network)"), Err("environment: unknown profile network".to_string()));
+
+        // Code inside cannot widen its own environment.
+        assert_eq!(eval(&interpreter, "(eval '(interaction-environment) (environment 'pure))"), Err("Unbound variable: interaction-environment".to_string()));
+        assert_eq!(eval(&interpreter, "(eval '(environment 'data) (environment 'pure))"), Err("Unbound variable: environment".to_string()));
+        assert_eq!(eval(&interpreter, "(eval '(+ 1 2))"), Err("eval requires an expression and an environment".to_string()));
+    }
+
+    #[test]
+    fn test_deadlines_and_cancellation() {
+        let interpreter = SchemeInterpreter::new();
+        let forever = "(guard (e (#t 'caught)) (let loop () (loop)))";
+
+        interpreter.set_deadline(Some(Instant::now() + Duration::from_millis(20)));
+        let started = Instant::now();
+        let err = interpreter.eval(forever).unwrap_err();
+        assert!(matches!(err, SchemeError::Interrupted(_)), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // Once the deadline has passed nothing more runs.
+        assert!(matches!(interpreter.eval("1"), Err(SchemeError::Interrupted(_))));
+        interpreter.set_deadline(None);
+        assert!(matches!(interpreter.eval("1"), Ok(SchemeValue::Number(_))));
+
+        let token = CancellationToken::new();
+        interpreter.set_cancellation_token(Some(token.clone()));
+        let canceller = token.clone();
+        interpreter.define_native("cancel!", 0, move |_ctx, _args| {
+            canceller.cancel();
+            Ok(SchemeValue::Nil)
+        });
+        let err = interpreter
+            .eval("(with-exception-handler (lambda (e) 0) (lambda () (cancel!) (let loop () (loop))))")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Interrupted: cancelled by the host");
+        assert!(token.is_cancelled());
+        interpreter.set_cancellation_token(None);
+        assert!(matches!(interpreter.eval("(+ 1 2)"), Ok(SchemeValue::Number(n)) if n == 3.0));
+    }
+
+    #[test]
+    fn test_garbage_collection() {
+        let interpreter = SchemeInterpreter::new();
+        interpreter
+            .eval("(define (count-down n) (let loop ((i n)) (if (= i 0) 'done (loop (- i 1)))))")
+            .unwrap();
+        let start = interpreter.gc_stats();
+
+        // Each named let leaves a closure and its frame pointing at each other.
+        interpreter.eval("(count-down 3) (count-down 3) (count-down 3)").unwrap();
+        assert!(interpreter.collect_garbage() >= 6);
+        let stats = interpreter.gc_stats();
+        assert_eq!(stats.collections, start.collections + 1);
+        assert_eq!(stats.live_objects, start.live_objects);
+
+        // Collections run on their own, so a long session stays bounded.
+        for _ in 0..20 {
+            interpreter.eval("(let loop ((i 0)) (if (< i 500) (begin (count-down 2) (loop (+ i 1)))))").unwrap();
+        }
+        let stats = interpreter.gc_stats();
+        assert!(stats.collections > start.collections + 1);
+        assert!(stats.live_objects < 10_000, "{:?}", stats);
+
+        // Closures still in use survive.
+        interpreter.eval("(define keep (let () (define (g n) (if (= n 0) 'ok (g (- n 1)))) g))").unwrap();
+        interpreter.collect_garbage();
+        assert_eq!(interpreter.eval("(keep 5)").unwrap().to_string(), "ok");
+
+        assert_eq!(interpreter.eval("(number? (gc))").unwrap().to_string(), "true");
+        assert_eq!(interpreter.eval("(car (car (gc-stats)))").unwrap().to_string(), "collections");
+        assert_eq!(interpreter.eval("(length (gc-stats))").unwrap().to_string(), "4");
+    }
+
+    #[test]
+    fn test_weak_references() {
+        let interpreter = SchemeInterpreter::new();
+
+        eval(&interpreter, "(define key (lambda (x) x))").unwrap();
+        eval(&interpreter, "(define box (make-weak-box key))").unwrap();
+        eval(&interpreter, "(define eph (make-ephemeron key 'datum))").unwrap();
+        eval(&interpreter, "(define cache (make-weak-hash-table))").unwrap();
+        // The cached value refers back to its key.
+        eval(&interpreter, "(weak-hash-table-set! cache key (lambda () (key 1)))").unwrap();
+        eval(&interpreter, "(weak-hash-table-set! cache (lambda () 'temporary) 1)").unwrap();
+
+        assert_eq!(eval(&interpreter, "(eq? (weak-box-value box) key)"), Ok("true".to_string()));
+        assert_eq!(eval(&interpreter, "((weak-hash-table-ref cache key))"), Ok("1".to_string()));
+        assert_eq!(eval(&interpreter, "(weak-hash-table-count cache)"), Ok("1".to_string()));
+        assert_eq!(eval(&interpreter, "(weak-hash-table-ref cache car 'missing)"), Ok("missing".to_string()));
+        assert_eq!(eval(&interpreter, "(ephemeron-datum eph)"), Ok("datum".to_string()));
+
+        eval(&interpreter, "(set! key #f)").unwrap();
+        interpreter.collect_garbage();
+        assert_eq!(eval(&interpreter, "(weak-box-value box 'gone)"), Ok("gone".to_string()));
+        assert_eq!(eval(&interpreter, "(list (ephemeron-broken? eph) (ephemeron-datum eph))"), Ok("[true, false]".to_string()));
+        assert_eq!(eval(&interpreter, "(weak-hash-table-count cache)"), Ok("0".to_string()));
+
+        assert_eq!(eval(&interpreter, "(make-weak-box \"text\")"), Err("make-weak-box: a string cannot be held weakly".to_string()));
+        assert_eq!(eval(&interpreter, "(weak-box-value 1)"), Err("weak-box-value: expected a weak box, got a number".to_string()));
+    }
+
+    #[test]
+    fn test_bytecode_matches_tree_walker() {
+        let backends = [Backend::TreeWalker, Backend::Bytecode];
+        let interpreters: Vec<SchemeInterpreter> =
+            backends.iter().map(|&backend| SchemeInterpreter::builder().with_backend(backend).build()).collect();
+        assert_eq!(SchemeInterpreter::new().backend(), Backend::Bytecode);
+
+        let examples = [
+            include_str!("../examples/fibonacci.scm"),
+            include_str!("../examples/advanced.scm"),
+            include_str!("../examples/list-processing.scm"),
+            include_str!("../examples/turing-complete.scm"),
+            include_str!("../examples/computational-patterns.scm"),
+        ];
+        for example in examples {
+            let outputs: Vec<_> = interpreters.iter().map(|i| i.run_program(example).map_err(|e| e.to_string())).collect();
+            assert_eq!(outputs[0], outputs[1]);
+        }
+
+        let programs = [
+            "(define (f x . rest) (list x rest)) (f 1 2 3)",
+            "(define (counter) (define n 0) (lambda () (set! n (+ n 1)) n)) (define c (counter)) (c) (c)",
+            "(define (even? n) (if (= n 0) #t (odd? (- n 1)))) (define (odd? n) (if (= n 0) #f (even? (- n 1)))) (even? 10001)",
+            "(let loop ((i 0) (acc '())) (if (= i 5) acc (loop (+ i 1) (cons i acc))))",
+            "(define x 1) (define (g) (set! x (+ x 1)) x) (g) (g)",
+            "(define (h) (define a 1) (define (k) (+ a b)) (define b 2) (k)) (h)",
+            "(define (early) (set! later 1)) (early)",
+            "(define (use-before) (define a b) (define b 1) a) (use-before)",
+            "(call/cc (lambda (k) (+ 1 (k 42))))",
+            "(guard (e (#t (list 'caught (error-object-message e)))) (car '()))",
+            "(with-exception-handler (lambda (e) 0) (lambda () (+ 1 (raise-continuable 'oops))))",
+            "(define n 0) (guard (e (#t n)) (with-exception-handler (lambda (c) (set! n (+ n 1)) (car '())) (lambda () (car 5))))",
+            "(call-with-values (lambda () (values 1 2)) (lambda (a b) (+ a b)))",
+            "(force (delay (+ 1 2)))",
+            "((lambda (x) x))",
+            "((lambda (x) x) 1 2)",
+            "(undefined-variable)",
+            "(if)",
+            "(lambda (1) x)",
+            "(define (deep n) (if (= n 0) 0 (+ 1 (deep (- n 1))))) (deep 100)",
+            "(define (spin n) (if (= n 0) 'done (spin (- n 1)))) (spin 100000)",
+        ];
+        for program in programs {
+            let results: Vec<_> =
+                interpreters.iter().map(|i| eval(i, program)).collect();
+            assert_eq!(results[0], results[1], "{}", program);
+        }
+    }
+
+    const EXAMPLES: [(&str, &str); 5] = [
+        ("fibonacci.scm", include_str!("../examples/fibonacci.scm")),
+        ("advanced.scm", include_str!("../examples/advanced.scm")),
+        ("list-processing.scm", include_str!("../examples/list-processing.scm")),
+        ("turing-complete.scm", include_str!("../examples/turing-complete.scm")),
+        ("computational-patterns.scm", include_str!("../examples/computational-patterns.scm")),
+    ];
+
+    #[test]
+    fn test_program_images() {
+        // The Compute binary's image also holds the demo page.
+        let programs: Vec<_> = EXAMPLES.iter().copied().chain([("demo.scm", include_str!("../demo.scm"))]).collect();
+        let bytes = image::compile(&programs).unwrap();
+        if std::env::var_os("UPDATE_IMAGES").is_some() {
+            std::fs::write("examples/examples.img", &bytes).unwrap();
+        }
+        assert!(
+            bytes == include_bytes!("../examples/examples.img"),
+            "examples/examples.img is out of date; rerun the tests with UPDATE_IMAGES=1"
+        );
+
+        let interpreter = SchemeInterpreter::from_image(&bytes).unwrap();
+        let reference = SchemeInterpreter::new();
+        for (name, source) in EXAMPLES {
+            assert_eq!(interpreter.run_image_program(name).unwrap(), reference.run_program(source).unwrap());
+        }
+        interpreter.run_image_program("demo.scm").unwrap();
+        let page = interpreter.call("demo-page", vec![SchemeValue::String("output".to_string())]).unwrap();
+        assert!(html::to_string(&page).unwrap().contains("output"));
+        assert!(interpreter.run_image_program("missing.scm").is_err());
+
+        // Macros defined in an image still work for code evaluated later.
+        let bytes = image::compile(&[(
+            "macros",
+            "(define-macro (twice x) `(begin ,x ,x)) (define n 0) (twice (set! n (+ n 1))) (string-append \"n=\" (number->string n))",
+        )])
+        .unwrap();
+        let interpreter = SchemeInterpreter::from_image(&bytes).unwrap();
+        assert!(interpreter.run_image_program("macros").unwrap().ends_with("n=2\n"));
+        assert_eq!(interpreter.eval("(twice (set! n (* n 10))) n").unwrap().to_string(), "200");
+
+        let error = |bytes: &[u8]| SchemeInterpreter::from_image(bytes).err().map(|e| e.to_string());
+        assert_eq!(error(b"(display 1)"), Some("Invalid image: not a program image".to_string()));
+        assert_eq!(error(&bytes[..bytes.len() - 1]), Some("Invalid image: truncated".to_string()));
+        let mut newer = bytes.clone();
+        newer[8] += 1;
+        assert!(error(&newer).unwrap().contains("is not supported"));
+        assert!(image::compile(&[("bad", "(define x (car '()))")]).unwrap_err().to_string().contains("bad line 0"));
+
+        // Crafted bytecode fails to load or fails when it runs, but never panics.
+        let prelude = SchemeInterpreter::new().snapshot().unwrap();
+        let crafted = |ops: &[(u8, &[u32])]| {
+            let mut bytes = b"LISPIMG\0".to_vec();
+            let u32 = |bytes: &mut Vec<u8>, n: u32| bytes.extend_from_slice(&n.to_le_bytes());
+            u32(&mut bytes, 2);
+            bytes.extend_from_slice(&0u64.to_le_bytes());
+            u32(&mut bytes, prelude.len() as u32);
+            bytes.extend_from_slice(&prelude);
+            for n in [1, 1] {
+                u32(&mut bytes, n);
+            }
+            bytes.push(b'p');
+            for n in [1, 0, 0] {
+                u32(&mut bytes, n);
+            }
+            bytes.push(0);
+            u32(&mut bytes, ops.len() as u32);
+            for (tag, operands) in ops {
+                bytes.push(*tag);
+                operands.iter().for_each(|&n| u32(&mut bytes, n));
+            }
+            u32(&mut bytes, 1);
+            bytes.push(3);
+            bytes.extend_from_slice(&1f64.to_bits().to_le_bytes());
+            for n in [1, 1] {
+                u32(&mut bytes, n);
+            }
+            bytes.push(b'x');
+            for n in [0, 0] {
+                u32(&mut bytes, n);
+            }
+            bytes.push(0);
+            u32(&mut bytes, u32::MAX);
+            bytes.push(0);
+            SchemeInterpreter::from_image(&bytes).and_then(|i| i.run_image_program("p")).map_err(|e| e.to_string())
+        };
+        let (constant, local, jump_if_false, pop, call, ret) = (0, 3, 11, 12, 13, 15);
+        assert!(crafted(&[(constant, &[0]), (ret, &[])]).unwrap().ends_with("1\n"));
+        let malformed = Err("Invalid image: malformed bytecode".to_string());
+        assert_eq!(crafted(&[(pop, &[]), (ret, &[])]), malformed);
+        assert_eq!(crafted(&[(constant, &[0]), (call, &[1]), (ret, &[])]), malformed);
+        assert_eq!(crafted(&[(constant, &[0]), (jump_if_false, &[3]), (constant, &[0]), (ret, &[])]), malformed);
+        let missing_frame = crafted(&[(local, &[2, 0, 0]), (ret, &[])]).unwrap_err();
+        assert!(missing_frame.ends_with("compiled code addresses a missing frame"), "{}", missing_frame);
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        for backend in [Backend::Bytecode, Backend::TreeWalker] {
+            let interpreter = SchemeInterpreter::builder().with_profiles(Profile::ALL).with_backend(backend).build();
+            interpreter
+                .eval(
+                    r#"
+                    (define counter (let ((n 0)) (lambda () (set! n (+ n 1)) n)))
+                    (counter)
+                    (define-macro (twice x) `(begin ,x ,x))
+                    (define (make-loop) (define (self) self) self)
+                    (define loop (make-loop))
+                    (define squares (stream-map (lambda (x) (* x x)) (list->stream '(1 2 3))))
+                    (define cache (make-weak-hash-table))
+                    (weak-hash-table-set! cache counter 'cached)
+                    (define config (list "edge" (vector 1 2) car))
+                    "#,
+                )
+                .unwrap();
+            let snapshot = interpreter.snapshot().unwrap();
+
+            let restored = SchemeInterpreter::restore(&snapshot).unwrap();
+            assert_eq!(restored.backend(), backend);
+            assert_eq!(eval(&restored, "(twice (counter))"), Ok("3".to_string()));
+            assert_eq!(eval(&restored, "(eq? (loop) loop)"), Ok("true".to_string()));
+            assert_eq!(eval(&restored, "(stream->list squares)"), Ok("[1, 4, 9]".to_string()));
+            assert_eq!(eval(&restored, "(weak-hash-table-ref cache counter)"), Ok("cached".to_string()));
+            assert_eq!(eval(&restored, "((caddr config) (cadr config))"), Err("car requires a list argument".to_string()));
+            assert_eq!(eval(&restored, "(car config)"), Ok("edge".to_string()));
+            // The original is unaffected.
+            assert_eq!(interpreter.eval("(counter)").unwrap().to_string(), "2");
+        }
+
+        let interpreter = SchemeInterpreter::new();
+        interpreter.define_native("host-time", 0, |_ctx, _args| Ok(SchemeValue::Number(0.0)));
+        interpreter.eval("(define now host-time)").unwrap();
+        assert_eq!(interpreter.snapshot().unwrap_err().to_string(), "Cannot snapshot the native procedure host-time");
+        interpreter.reset();
+        interpreter.eval("(define k (call/cc (lambda (k) k)))").unwrap();
+        assert!(interpreter.snapshot().is_err());
+        interpreter.reset();
+
+        let snapshot = interpreter.snapshot().unwrap();
+        let restricted = SchemeInterpreter::builder().restore(&snapshot).err().map(|e| e.to_string());
+        assert_eq!(restricted, Some("Cannot restore snapshot: it was taken with a different set of builtins".to_string()));
+        let limits = InterpreterLimits { max_depth: Some(50), ..InterpreterLimits::default() };
+        let restored = SchemeInterpreter::builder().with_profiles(Profile::ALL).with_limits(limits).restore(&snapshot).unwrap();
+        assert_eq!(restored.limits().max_depth, Some(50));
+        assert!(SchemeInterpreter::restore(&snapshot[..snapshot.len() - 1]).is_err());
+        assert_eq!(
+            SchemeInterpreter::restore(b"not a snapshot").err().map(|e| e.to_string()),
+            Some("Invalid snapshot: not a snapshot".to_string())
+        );
+    }
+
+    #[test]
+    fn test_optimizer() {
+        let interpreter = SchemeInterpreter::new();
+        let dump = |form: &str| interpreter.eval(&format!("(expand/optimize '{})", form)).unwrap().to_string();
+        assert_eq!(dump("(+ 1 (* 2 3))"), "7");
+        assert_eq!(dump("(let ((square (lambda (x) (* x x)))) (square 5))"), "25");
+        assert_eq!(dump("(let ((x 1) (y (f))) (if (< x 0) 'negative y))"), "[[lambda, [y], y], [f]]");
+        assert_eq!(dump("(if #f (car '()) (cadr '(a b)))"), "[quote, b]");
+        assert_eq!(dump("(car '())"), "[car, [quote, ()]]");
+        assert_eq!(dump("(let ((x 1) (f (lambda () x))) (f))"), "[[lambda, [f], [f]], [lambda, (), x]]");
+        assert_eq!(
+            dump("(define (area r) (* (* 2 2) r))"),
+            "[define, area, [lambda, [r], [*, [if, [#<function builtins-unchanged?>], 4, [*, 2, 2]], r]]]"
+        );
+        assert_eq!(dump("(begin (set! + -) (+ 1 2))"), "[begin, [set!, +, -], [+, 1, 2]]");
+
+        // A procedure folded before `*` is rebound still sees the new binding.
+        interpreter.eval("(define (area r) (* (* 2 2) r))").unwrap();
+        assert_eq!(interpreter.eval("(area 3)").unwrap().to_string(), "12");
+        interpreter.eval("(set! * +)").unwrap();
+        assert_eq!(interpreter.eval("(area 3)").unwrap().to_string(), "7");
+        assert_eq!(dump("(* 2 3)"), "[*, 2, 3]");
+        let session = SchemeInterpreter::new();
+        let program = "(define (three) (+ 1 2)) (define (+ a b) (- a b)) (three)";
+        assert_eq!(session.eval(program).unwrap().to_string(), "-1");
+
+        let plain = SchemeInterpreter::builder().with_optimizer(false).build();
+        assert_eq!(plain.eval("(expand/optimize '(+ 1 2))").unwrap().to_string(), "[+, 1, 2]");
+        let optimized = SchemeInterpreter::new();
+        for (name, source) in EXAMPLES {
+            let plain = SchemeInterpreter::builder().with_profiles(Profile::ALL).with_optimizer(false).build();
+            assert_eq!(optimized.run_program(source).ok(), plain.run_program(source).ok(), "{}", name);
+        }
+        for program in ["(car '())", "(let ((f (lambda (x) x))) (f))", "(let ((x 1)) (set! x 2) (+ x 1))"] {
+            let results: Vec<_> = [&optimized, &plain].iter().map(|i| eval(i, program)).collect();
+            assert_eq!(results[0], results[1]);
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        let interpreter = SchemeInterpreter::new();
+        let text = r#"{"name": "edge", "tags": ["a", "b"], "port": 8080, "ratio": 0.25, "tls": true, "debug": false, "proxy": null}"#;
+        interpreter.set_global("text", SchemeValue::String(text.to_string()));
+        interpreter.eval("(define doc (string->json text))").unwrap();
+        assert_eq!(
+            eval(&interpreter, r#"(list (hash-ref doc "name") (hash-ref doc "tags") (hash-ref doc "port") (hash-ref doc "ratio"))"#),
+            Ok("[edge, #(a b), 8080, 0.25]".to_string())
+        );
+        assert_eq!(
+            eval(&interpreter, r#"(list (hash-ref doc "tls") (hash-ref doc "debug") (eq? (hash-ref doc "proxy") 'null) (hash-ref doc "missing" 'none))"#),
+            Ok("[true, false, true, none]".to_string())
+        );
+        assert_eq!(
+            eval(&interpreter, "(json->string doc)"),
+            Ok(r#"{"debug":false,"name":"edge","port":8080,"proxy":null,"ratio":0.25,"tags":["a","b"],"tls":true}"#.to_string())
+        );
+        assert_eq!(
+            eval(&interpreter, r#"(json-write (list (list "b" (vector 1 (list "c" "x\n" 'null))) (list 'a (vector)) (list "d" '())) 'pretty)"#),
+            Ok("{\n  \"b\": [\n    1,\n    [\n      \"c\",\n      \"x\\n\",\n      null\n    ]\n  ],\n  \"a\": [],\n  \"d\": []\n}".to_string())
+        );
+        let alist = r#"(json-read "{\"b\": {\"c\": [1, null]}, \"a\": 2}" 'alist)"#;
+        assert_eq!(eval(&interpreter, alist), Ok("[[b, [[c, #(1 null)]]], [a, 2]]".to_string()));
+        assert_eq!(eval(&interpreter, &format!("(json->string {})", alist)), Ok(r#"{"b":{"c":[1,null]},"a":2}"#.to_string()));
+        assert_eq!(eval(&interpreter, &format!("(json->string {} 'sort-keys)", alist)), Ok(r#"{"a":2,"b":{"c":[1,null]}}"#.to_string()));
+        assert_eq!(eval(&interpreter, r#"(string->json "\"\\u00e9\\ud83d\\ude00\"")"#), Ok("é😀".to_string()));
+
+        let errors = [
+            (r#"{"a": 1,\n  "b" 2}"#, "expected ':', found '2' at line 2, column 7"),
+            ("[1, 2", "expected ',' or ']', found end of input at line 1, column 6"),
+            ("[01]", "expected ',' or ']', found '1' at line 1, column 3"),
+            ("tru", "unexpected end of input at line 1, column 4"),
+            ("[1.]", "expected a digit, found ']' at line 1, column 4"),
+            (r#""\ud83d""#, "unpaired surrogate in \\u escape at line 1, column 4"),
+            ("1e999", "number out of range at line 1, column 1"),
+            ("{} x", "unexpected character 'x' at line 1, column 4"),
+        ];
+        for (text, message) in errors {
+            interpreter.set_global("text", SchemeValue::String(text.replace("\\n", "\n")));
+            assert_eq!(eval(&interpreter, "(string->json text)"), Err(format!("string->json: {}", message)), "{}", text);
+        }
+        assert_eq!(eval(&interpreter, "(json->string (lambda (x) x))"), Err("json->string: cannot represent #<lambda> as JSON".to_string()));
+        assert_eq!(eval(&interpreter, "(json->string (expt 10 400))"), Err("json->string: cannot represent inf as JSON".to_string()));
+
+        let value = json::read("[1, {\"k\": \"v\"}]", json::Objects::Alists).unwrap();
+        assert_eq!(json::write(&value, json::WriteOptions::default()).unwrap(), r#"[1,{"k":"v"}]"#);
+        assert_eq!(json::read("[", json::Objects::HashTables).unwrap_err().to_string(), "Invalid JSON: unexpected end of input at line 1, column 2");
+        let nested = "[".repeat(1000);
+        assert!(json::read(&nested, json::Objects::HashTables).unwrap_err().to_string().contains("nesting deeper than 512 levels"));
+        assert!(SchemeInterpreter::builder().build().eval("(string->json \"1\")").is_err());
+    }
+
+    #[test]
+    fn test_eval_service() {
+        let eval = |body: &[u8], content_type: Option<&str>, deadline: Option<Instant>| {
+            let reply = service::eval(body, content_type, deadline);
+            match json::read(&reply.body, json::Objects::HashTables).unwrap() {
+                SchemeValue::HashTable(fields) => (reply.status, SchemeValue::HashTable(fields)),
+                other => panic!("reply is not an object: {}", other),
+            }
+        };
+        let field = |reply: &SchemeValue, path: &str| {
+            path.split('.').fold(reply.clone(), |value, key| match value {
+                SchemeValue::HashTable(fields) => fields.get(key).cloned().unwrap_or_else(|| panic!("no {} in reply", path)),
+                other => panic!("{} is not an object: {}", key, other),
+            })
+            .to_string()
+        };
+        let run = |code: &str| eval(code.as_bytes(), None, None);
+
+        let (status, reply) = run("(define (fib n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))\n(display \"fib \")\n(display (fib 10))\n(newline)\n(fib 10)");
+        assert_eq!(status, 200);
+        assert_eq!(field(&reply, "ok"), "true");
+        assert_eq!(field(&reply, "value"), "55");
+        assert_eq!(field(&reply, "output"), "fib 55\n");
+        assert_eq!(field(&reply, "usage.output_bytes"), "7");
+        assert!(field(&reply, "usage.steps").parse::<u64>().unwrap() > 100);
+        assert_eq!(field(&reply, "limits.fuel"), service::DEFAULT_FUEL.to_string());
+        assert_eq!(field(&run("").1, "value"), "null");
+
+        // Failures report the form or syntax error with positions counted from 1
+        let (status, reply) = run("(display \"before\")\n  (car '())");
+        assert_eq!((status, field(&reply, "ok"), field(&reply, "value")), (200, "false".to_string(), "null".to_string()));
+        assert_eq!(field(&reply, "output"), "before");
+        assert_eq!(field(&reply, "error.kind"), "error");
+        assert_eq!(field(&reply, "error.message"), "car requires a list argument");
+        assert_eq!((field(&reply, "error.line"), field(&reply, "error.column")), ("2".to_string(), "3".to_string()));
+        let reply = run("(+ 1\n  (* 2 3)").1;
+        assert_eq!(field(&reply, "error.kind"), "syntax");
+        assert_eq!((field(&reply, "error.line"), field(&reply, "error.column")), ("2".to_string(), "10".to_string()));
+        let reply = run(&"(".repeat(20_000)).1;
+        assert_eq!(field(&reply, "error.kind"), "syntax");
+        assert_eq!(field(&reply, "error.message"), "Nesting deeper than 512 levels on line 0");
+        assert_eq!(field(&run(&format!("{}1{}", "(+ 1 ".repeat(100), ")".repeat(100))).1, "value"), "101");
+        // Flat forms that expand into deep ones stop at the same depth.
+        for code in [format!("(and {}1)", "1 ".repeat(6000)), format!("(let* ({}) x)", "(x 1) ".repeat(3000))] {
+            let reply = run(&code).1;
+            assert_eq!(field(&reply, "error.kind"), "resource-limit");
+            assert_eq!(field(&reply, "error.message"), "Resource limit exceeded: expansion nested deeper than 512 levels");
+        }
+        assert_eq!(field(&run(&format!("(and {}2)", "1 ".repeat(100))).1, "value"), "2");
+        assert_eq!(field(&run("(raise 'oops)").1, "error.kind"), "raise");
+        assert_eq!(field(&run("(log \"x\")").1, "error.message"), "Unbound variable: log");
+        let reply = run("(define (spam) (display \"spam\") (spam)) (spam)").1;
+        assert_eq!(field(&reply, "error.kind"), "resource-limit");
+        assert_eq!(field(&reply, "usage.output_bytes"), service::MAX_OUTPUT_BYTES.to_string());
+        let reply = run("(define-macro (spin) (let loop () (loop))) (spin)").1;
+        assert_eq!(field(&reply, "error.kind"), "resource-limit");
+
+        // JSON bodies may lower the limits but not raise them
+        let body = r#"{"code": "(define (loop) (loop)) (loop)", "limits": {"fuel": 1000, "max_depth": 1000000}}"#;
+        let reply = eval(body.as_bytes(), None, None).1;
+        assert_eq!(field(&reply, "error.message"), "Resource limit exceeded: out of fuel after 1000 steps");
+        assert_eq!((field(&reply, "error.line"), field(&reply, "error.column")), ("1".to_string(), "24".to_string()));
+        assert_eq!((field(&reply, "usage.steps"), field(&reply, "limits.max_depth")), ("1000".to_string(), "1000".to_string()));
+        let body = r#"{"code": "(make-vector 100 0)", "limits": {"max_list_length": 10}}"#;
+        assert_eq!(field(&eval(body.as_bytes(), Some("application/json"), None).1, "error.kind"), "resource-limit");
+        assert_eq!(field(&eval(b"{\"code\": \"(* 6 7)\"}", Some("application/json"), None).1, "value"), "42");
+
+        let past = Some(Instant::now() - Duration::from_millis(1));
+        let (status, reply) = eval(b"(+ 1 2)", None, past);
+        assert_eq!((status, field(&reply, "error.kind")), (503, "interrupted".to_string()));
+
+        let bad_requests: [(&[u8], u16, &str); 6] = [
+            (br#"{"code": 5}"#, 400, "\"code\" must be a string"),
+            (br#"{"limits": {}}"#, 400, "missing \"code\" field"),
+            (br#"{"code": "1", "limits": {"fuel": -1}}"#, 400, "limit \"fuel\" must be a non-negative integer"),
+            (br#"{"code": "1", "limits": {"gas": 1}}"#, 400, "unknown limit \"gas\""),
+            (b"(display \"\xff\")", 400, "body is not valid UTF-8"),
+            (&[b' '; service::MAX_BODY_BYTES + 1], 413, "body is over the limit of 65536 bytes"),
+        ];
+        for (body, status, message) in bad_requests {
+            let reply = eval(body, None, None);
+            assert_eq!((reply.0, field(&reply.1, "error.kind"), field(&reply.1, "error.message")), (status, "request".to_string(), message.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_routes() {
+        use service::Route;
+        let routes = [
+            ("/", Route::Demo),
+            ("/examples", Route::Examples),
+            ("/examples/", Route::Examples),
+            ("/examples/fibonacci", Route::Example("fibonacci")),
+            ("/examples/fibonacci/", Route::Example("fibonacci")),
+            ("/examples/a/b", Route::NotFound),
+            ("/eval", Route::Eval),
+            ("/healthz", Route::Health),
+            ("/evaluate", Route::NotFound),
+            ("/favicon.ico", Route::NotFound),
+        ];
+        for (path, route) in routes {
+            assert_eq!(Route::for_path(path), route, "{}", path);
+        }
+        assert_eq!(Route::Demo.methods(), ["GET", "HEAD", "OPTIONS"]);
+        assert!(Route::Example("x").allows("HEAD"));
+        assert!(!Route::Health.allows("POST"));
+        assert_eq!(Route::Eval.methods(), ["POST", "OPTIONS"]);
+        assert!(!Route::Eval.allows("GET"));
+        assert!(!Route::NotFound.allows("GET"));
+    }
+
+    #[test]
+    fn test_http_handlers() {
+        let request = |method: &str, path: &str, headers: &[(&str, &str)], body: &str| http::Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: "name=edge&x=1".to_string(),
+            headers: headers.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect(),
+            body: body.to_string(),
+        };
+        let handler = SchemeInterpreter::builder().with_profiles(http::HANDLER_PROFILES).build();
+        handler.eval(include_str!("../handler.scm")).unwrap();
+        let snapshot = handler.snapshot().unwrap();
+        if std::env::var_os("UPDATE_IMAGES").is_some() {
+            std::fs::write("handler.snapshot", &snapshot).unwrap();
+        }
+        assert!(
+            snapshot == include_bytes!("../handler.snapshot"),
+            "handler.snapshot is out of date; rerun the tests with UPDATE_IMAGES=1"
+        );
+        let interpreter = (SchemeInterpreter::builder().with_profiles(http::HANDLER_PROFILES))
+            .with_limits(service::default_limits())
+            .restore(&snapshot)
+            .unwrap();
+        assert!(interpreter.eval("(current-second)").is_err());
+        let hello = http::handle(&interpreter, request("GET", "/hello", &[], "")).unwrap().unwrap();
+        assert_eq!(hello.status, 200);
+        assert_eq!(hello.body, "Hello from Scheme! This was a GET request.\n");
+        let echo = http::handle(&interpreter, request("POST", "/echo", &[("Content-Type", "text/csv")], "a,b")).unwrap().unwrap();
+        assert_eq!((echo.headers, echo.body), (vec![("content-type".to_string(), "text/csv".to_string())], "a,b".to_string()));
+        assert_eq!(http::handle(&interpreter, request("GET", "/missing", &[], "")).unwrap(), None);
+
+        interpreter.eval(r#"
+            (define (handle request)
+              (if (equal? (request-query request) "")
+                  (string-append (request-method request) " " (request-path request))
+                  (make-response 201
+                                 (list (list 'X-Seen (or (request-header request "x-token") "none"))
+                                       (list "Content-Type" "application/json"))
+                                 (request-body request))))"#).unwrap();
+        let created = http::handle(&interpreter, request("PUT", "/items", &[("X-Token", "t1"), ("x-token", "t2")], "{}")).unwrap().unwrap();
+        assert_eq!(created.status, 201);
+        assert_eq!(
+            created.headers,
+            vec![("x-seen".to_string(), "t1".to_string()), ("content-type".to_string(), "application/json".to_string())]
+        );
+        let plain = http::handle(&interpreter, http::Request { method: "GET".to_string(), path: "/".to_string(), ..Default::default() });
+        assert_eq!(
+            plain.unwrap().unwrap(),
+            http::Response { status: 200, headers: vec![("content-type".to_string(), http::DEFAULT_CONTENT_TYPE.to_string())], body: "GET /".to_string() }
+        );
+
+        assert_eq!(eval(&interpreter, "(request-path \"/\")"), Err("request-path: expected a request, got /".to_string()));
+        interpreter.eval(r#"
+            (define (handle request)
+              (define fake (make-hash-table))
+              (hash-set! fake "method" "GET")
+              (hash-set! fake "path" "/admin")
+              (hash-set! fake "headers" '())
+              (list (guard (e (#t (error-object-message e))) (request-path fake))
+                    (guard (e (#t (error-object-message e))) (hash-set! request "path" "/admin") (request-path request))
+                    (make-response 200 '() "ok")))"#).unwrap();
+        let spoofed = interpreter.call("handle", vec![request("GET", "/", &[], "").into_scheme().unwrap()]).unwrap();
+        assert_eq!(spoofed.to_string(), "[request-path: expected a request, got #<hash-table>, hash-set! requires a hash table, string key, and value, #<response 200>]");
+        interpreter.eval(r#"
+            (define (handle request)
+              (define forged (make-hash-table))
+              (hash-set! forged "status" 200)
+              (hash-set! forged "headers" '())
+              (hash-set! forged "body" "forged")
+              forged)"#).unwrap();
+        assert_eq!(
+            http::handle(&interpreter, request("GET", "/", &[], "")).unwrap_err().to_string(),
+            "handle: expected a response from make-response or a string, got #<hash-table>"
+        );
+        assert_eq!(eval(&interpreter, "(make-response 600 '() \"\")"), Err("make-response: status 600 is not between 100 and 599".to_string()));
+        assert_eq!(eval(&interpreter, "(make-response 200 '((\"a b\" \"1\")) \"\")"), Err("make-response: \"a b\" is not a valid header name".to_string()));
+        assert_eq!(eval(&interpreter, "(make-response 200 '((\"a\" 1)) \"\")"), Err("make-response: header a must have a string value, got 1".to_string()));
+        interpreter.eval("(define (handle request) 42)").unwrap();
+        assert_eq!(
+            http::handle(&interpreter, request("GET", "/", &[], "")).unwrap_err().to_string(),
+            "handle: expected a response from make-response or a string, got 42"
+        );
+
+        let sandboxed = SchemeInterpreter::builder().with_profile(Profile::Data).build();
+        assert!(sandboxed.eval("(make-response 200 '() \"\")").is_err());
+        assert!(SchemeInterpreter::builder().with_profile(Profile::Request).build().eval("(procedure? request-header)").is_ok());
+    }
+
+    #[test]
+    fn test_url_procedures() {
+        let interpreter = SchemeInterpreter::new();
+        assert_eq!(
+            eval(&interpreter, r#"(parse-query-string "?a=1&b=x+y%20z&a=2&&flag&c=%E2%9C%93&bad=%zz%4")"#),
+            Ok("[[a, 1], [b, x y z], [a, 2], [flag, ], [c, ✓], [bad, %zz%4]]".to_string())
+        );
+        assert_eq!(
+            eval(&interpreter, r#"(parse-form-urlencoded "name=J%C3%B6rg&msg=hi%21+there&empty=")"#),
+            Ok("[[name, Jörg], [msg, hi! there], [empty, ]]".to_string())
+        );
+        assert_eq!(eval(&interpreter, r#"(parse-query-string "")"#), Ok("()".to_string()));
+        assert_eq!(eval(&interpreter, r#"(url-encode "a b/c?d=é&~")"#), Ok("a%20b%2Fc%3Fd%3D%C3%A9%26~".to_string()));
+        assert_eq!(eval(&interpreter, r#"(url-decode "a%20b+c%2")"#), Ok("a b+c%2".to_string()));
+        assert_eq!(eval(&interpreter, r#"(url-decode "%FF")"#), Ok("\u{FFFD}".to_string()));
+        assert_eq!(
+            eval(&interpreter, r#"(build-query-string (list (list 'q "lisp & scheme") (list "page" 2) '("x" "")))"#),
+            Ok("q=lisp%20%26%20scheme&page=2&x=".to_string())
+        );
+        assert_eq!(eval(&interpreter, "(build-query-string '())"), Ok("".to_string()));
+        assert_eq!(
+            eval(&interpreter, r#"(parse-query-string (build-query-string '(("k y" "a+b=c") ("k y" "é"))))"#),
+            Ok("[[k y, a+b=c], [k y, é]]".to_string())
+        );
+        assert_eq!(
+            eval(&interpreter, "(build-query-string '((1 \"a\")))"),
+            Err("build-query-string: key must be a string or symbol, got 1".to_string())
+        );
+        assert_eq!(
+            eval(&interpreter, "(build-query-string '((\"a\")))"),
+            Err("build-query-string: expected a (key value) entry, got [a]".to_string())
+        );
+        assert_eq!(eval(&interpreter, "(url-encode 5)"), Err("url-encode requires string arguments".to_string()));
+
+        assert_eq!(url::parse_query("x=1&x=2"), vec![("x".to_string(), "1".to_string()), ("x".to_string(), "2".to_string())]);
+        assert_eq!(url::build_query(&[("a b", "c&d")]), "a%20b=c%26d");
+        assert_eq!(url::decode_form("a+b%2B"), "a b+");
+        assert!(SchemeInterpreter::builder().build().eval("(url-encode \"x\")").is_err());
+    }
+
+    #[test]
+    fn test_sxml_html() {
+        let interpreter = SchemeInterpreter::new();
+        assert_eq!(
+            eval(&interpreter, r#"(sxml->html '(*TOP* (*DECL* DOCTYPE html)
+                                         (html (head (title "x & y") (meta (@ (charset "utf-8"))))
+                                               (body (p (@ (class "a\"<b>") (hidden) (draggable #f) (tabindex 3))
+                                                        "text <i>" (br) (*RAW* "<b>raw</b>") 42)))))"#),
+            Ok(concat!(
+                "<!DOCTYPE html><html><head><title>x &amp; y</title><meta charset=\"utf-8\"></head>",
+                "<body><p class=\"a&quot;&lt;b&gt;\" hidden tabindex=\"3\">text &lt;i&gt;<br><b>raw</b>42</p></body></html>"
+            )
+            .to_string())
+        );
+        assert_eq!(
+            eval(&interpreter, r#"(let ((items '("a" "b"))) (sxml->html `(ul ,(list `(li ,(car items)) `(li ,(cadr items))) #f ())))"#),
+            Ok("<ul><li>a</li><li>b</li></ul>".to_string())
+        );
+        assert_eq!(eval(&interpreter, r#"(sxml->html '(style "a > b { color: red }"))"#), Ok("<style>a > b { color: red }</style>".to_string()));
+        assert_eq!(eval(&interpreter, "(sxml->html (list))"), Ok("".to_string()));
+        assert_eq!(eval(&interpreter, "(sxml->html (list 'p (list) \"x\"))"), Ok("<p>x</p>".to_string()));
+
+        let errors = [
+            (r#"'(style "</STYLE><script>")"#, "style text cannot contain </style"),
+            (r#"'(br "x")"#, "br is a void element and cannot have content"),
+            (r#"'(p (@ (onclick= "x")) "y")"#, "onclick= is not a valid attribute name"),
+            (r#"'(p (@ (href ,x)))"#, "attribute href cannot have the value [unquote, x]"),
+            ("'(p #t)", "cannot render true as HTML"),
+            ("(list (string->symbol \"a><script\"))", "\"a><script\" is not a valid tag name"),
+        ];
+        for (node, message) in errors {
+            assert_eq!(eval(&interpreter, &format!("(sxml->html {})", node)), Err(format!("sxml->html: {}", message)), "{}", node);
+        }
+
+        // With a procedure, the output is passed on in chunks as it is rendered
+        interpreter.eval("(define chunks '())").unwrap();
+        interpreter.eval("(sxml->html (list 'div (list 'p (make-string 5000 \"x\")) '(p \"y\")) (lambda (c) (set! chunks (cons c chunks))))").unwrap();
+        assert_eq!(eval(&interpreter, "(list (string-length (car chunks)) (string-length (cadr chunks)))"), Ok("[18, 5008]".to_string()));
+        let node = interpreter.eval("'(p (@ (id \"n\")) \"1 < 2\")").unwrap();
+        let mut bytes = Vec::new();
+        html::write(&node, &mut bytes).unwrap();
+        assert_eq!(String::from_utf8(bytes).unwrap(), "<p id=\"n\">1 &lt; 2</p>");
+
+        // The demo page served at /
+        interpreter.eval(include_str!("../demo.scm")).unwrap();
+        let page = interpreter.call("demo-page", vec![SchemeValue::String("(< 1 2) => true\n".to_string())]).unwrap();
+        let page = html::to_string(&page).unwrap();
+        assert!(page.starts_with("<!DOCTYPE html><html lang=\"en\"><head><meta charset=\"UTF-8\">"));
+        assert!(page.contains("<div class=\"scheme-output\">(&lt; 1 2) =&gt; true\n</div>"));
+        assert!(page.contains("font-family: 'Courier New', monospace;"));
+    }
+
+    #[test]
+    fn test_templates() {
+        let interpreter = SchemeInterpreter::new();
+        interpreter.set_global(
+            "page",
+            SchemeValue::String(
+                "<h1>{{title}}</h1>\n{{#if items}}<ul>{{#each items}}<li>{{name}}: {{price}} {{currency}}</li>{{/each}}</ul>\
+                 {{else}}<p>Nothing for {{ user.name }}.</p>{{/if}}\n{{{footer}}}"
+                    .to_string(),
+            ),
+        );
+        assert_eq!(
+            eval(&interpreter, r#"(render-template page (list (list 'title "Tom & Jerry's") (list 'currency "EUR") (list 'footer "<hr>")
+                                                (list 'items (list '((name "<b>") (price 3)) '(("name" "x") ("price" 4.5))))))"#),
+            Ok("<h1>Tom &amp; Jerry&#39;s</h1>\n<ul><li>&lt;b&gt;: 3 EUR</li><li>x: 4.5 EUR</li></ul>\n<hr>".to_string())
+        );
+        assert_eq!(
+            eval(&interpreter, r#"(render-template page '((title "t") (items ()) (footer "") (user ((name "Ann")))))"#),
+            Ok("<h1>t</h1>\n<p>Nothing for Ann.</p>\n".to_string())
+        );
+        assert_eq!(
+            eval(&interpreter, r#"(render-template "{{#each tags}}[{{.}}]{{/each}}" (list (list "tags" (vector "a" 'b 3))))"#),
+            Ok("[a][b][3]".to_string())
+        );
+        assert_eq!(eval(&interpreter, r#"(render-template "{{#if s}}yes{{else}}no{{/if}}" '((s "")))"#), Ok("no".to_string()));
+        assert_eq!(
+            eval(&interpreter, r#"(render-template "{{greeting}}, {{name}}" (string->json "{\"greeting\": \"Hi\", \"name\": \"<Ann>\"}"))"#),
+            Ok("Hi, &lt;Ann&gt;".to_string())
+        );
+
+        let errors = [
+            (r#""a\nb {{missing}}" '()"#, "no value for missing on line 2"),
+            (r#""line1\nline2\n{{#each xs}}\n  {{name}}\n{{/each}}" '((xs (((nome "x")))))"#, "no value for name on line 4"),
+            (r#""a\n{{#each xs}}\nb\n" '((xs ()))"#, "{{#each xs}} on line 2 is never closed"),
+            (r#""{{#if a}}\nx{{/each}}" '((a #t))"#, "{{/each}} on line 2 does not close {{#if a}} from line 1"),
+            (r#""x {{else}}" '()"#, "{{else}} outside {{#if}} on line 1"),
+            (r#""{{/if}}" '()"#, "{{/if}} without an opening tag on line 1"),
+            (r#""\n{{ a b }}" '()"#, "{{a b}} is not a valid tag on line 2"),
+            (r#""{{a" '()"#, "{{ is not closed on line 1"),
+            (r#""{{a}}" '((a (1 2)))"#, "cannot insert [1, 2] for a on line 1"),
+            (r#""{{#each a}}{{/each}}" '((a 5))"#, "{{#each a}} needs a list, got 5 on line 1"),
+        ];
+        for (args, message) in errors {
+            assert_eq!(eval(&interpreter, &format!("(render-template {})", args)), Err(format!("render-template: {}", message)), "{}", args);
+        }
+
+        let card = template::Template::parse("<p>{{who}}</p>").unwrap();
+        let bindings = interpreter.eval("'((who \"a & b\"))").unwrap();
+        assert_eq!(card.render(&bindings).unwrap(), "<p>a &amp; b</p>");
+        assert_eq!(card.render(&SchemeValue::Nil).unwrap_err().to_string(), "no value for who on line 1");
+
+        let a = interpreter.eval("'((a #t))").unwrap();
+        let nested = |n: usize| format!("{}x{}", "{{#if a}}\n".repeat(n), "{{/if}}".repeat(n));
+        assert_eq!(template::render(&nested(512), &a).unwrap(), "\n".repeat(512) + "x");
+        assert_eq!(
+            template::render(&nested(6000), &a).unwrap_err().to_string(),
+            "blocks nested deeper than 512 levels on line 513"
+        );
+    }
+}